@@ -0,0 +1,382 @@
+// Assembler for the Cowgod-style mnemonics the disassembler prints, producing a
+// binary that can be loaded like any other rom.
+//
+//   ; comments run to the end of the line
+//   SPEED equ 3              ; constants, defined before they are used
+//   start:                   ; labels, on their own or in front of an instruction
+//       LD V0, SPEED
+//       LD I, ship
+//       DRW V0, V1, 3
+//       JP start
+//   ship:
+//       sprite ..#.....      ; one sprite row per line ('#'/'1' set, '.'/'0' clear),
+//       sprite .###....      ; 16 columns wide rows take two bytes
+//       sprite #####...
+//       db 0x01, 2, %101     ; raw bytes
+//       dw 0x1234, start     ; raw big-endian words
+//
+// Numbers are decimal, hex (0x12, $12) or binary (0b101, %101), and operands can be
+// sums and differences of numbers, labels and constants (`ship + 2`).
+// Encoding goes through `Instruction::encode`, so anything assembled decodes back
+// to the same instruction in the cpu and the disassembler.
+
+use crate::instruction::Instruction;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AsmError {
+    pub line: usize, // 1 based
+    pub msg: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "line {}: {}", self.line, self.msg) }
+}
+
+impl Error for AsmError {}
+
+// what a line turns into, with expressions still unevaluated until all labels are known
+enum Statement<'a> {
+    Instr(&'a str, Vec<&'a str>), // mnemonic, operands
+    Bytes(Vec<&'a str>),
+    Words(Vec<&'a str>),
+    Sprite(Vec<u8>),
+}
+
+impl<'a> Statement<'a> {
+    fn size(&self) -> usize {
+        match self {
+            // only LD I, LONG NNNN is 4 bytes
+            Statement::Instr(mnemonic, operands) => {
+                let long = operands.get(1).is_some_and(|op| starts_with_word(op, "long"));
+                if mnemonic.eq_ignore_ascii_case("ld") && long { 4 } else { 2 }
+            }
+            Statement::Bytes(values) => values.len(),
+            Statement::Words(values) => values.len() * 2,
+            Statement::Sprite(row) => row.len(),
+        }
+    }
+}
+
+fn starts_with_word(text: &str, word: &str) -> bool {
+    let mut parts = text.split_whitespace();
+    parts.next().is_some_and(|first| first.eq_ignore_ascii_case(word)) && parts.next().is_some()
+}
+
+fn is_symbol(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let lower = text.to_ascii_lowercase();
+    if let Some(hex) = lower.strip_prefix("0x").or_else(|| lower.strip_prefix('$')) {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b").or_else(|| lower.strip_prefix('%')) {
+        i64::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn parse_sprite_row(row: &str) -> Result<Vec<u8>, String> {
+    if row.is_empty() || row.len() > 16 {
+        return Err(format!("sprite rows are 1 to 16 pixels wide: {}", row));
+    }
+    let mut bits: u16 = 0;
+    for (col, c) in row.chars().enumerate() {
+        match c {
+            '#' | '1' => bits |= 0x8000 >> col,
+            '.' | '0' => {}
+            _ => return Err(format!("bad sprite pixel '{}', use '#' or '.'", c)),
+        }
+    }
+    let bytes = bits.to_be_bytes();
+    Ok(if row.len() > 8 { bytes.to_vec() } else { vec![bytes[0]] })
+}
+
+struct Assembler {
+    symbols: HashMap<String, i64>, // labels and constants, by lowercase name
+}
+
+impl Assembler {
+    fn define(&mut self, name: &str, value: i64) -> Result<(), String> {
+        if !is_symbol(name) {
+            return Err(format!("bad name: {}", name));
+        }
+        if self.symbols.insert(name.to_ascii_lowercase(), value).is_some() {
+            return Err(format!("{} is defined twice", name));
+        }
+        Ok(())
+    }
+
+    // sums and differences of numbers and symbols
+    fn eval(&self, expr: &str) -> Result<i64, String> {
+        let mut total = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+        // the extra '+' ends the last term
+        for c in expr.chars().chain(std::iter::once('+')) {
+            match c {
+                '+' | '-' if term.trim().is_empty() => {
+                    // unary sign
+                    if c == '-' {
+                        sign = -sign;
+                    }
+                }
+                '+' | '-' => {
+                    let name = term.trim();
+                    let value = parse_number(name)
+                        .or_else(|| self.symbols.get(&name.to_ascii_lowercase()).copied())
+                        .ok_or(format!("unknown value: {}", name))?;
+                    total += sign * value;
+                    sign = if c == '-' { -1 } else { 1 };
+                    term.clear();
+                }
+                _ => term.push(c),
+            }
+        }
+        if expr.trim().is_empty() || expr.trim_end().ends_with(['+', '-']) {
+            return Err(format!("missing value: {}", expr.trim()));
+        }
+        Ok(total)
+    }
+
+    fn eval_range(&self, expr: &str, min: i64, max: i64) -> Result<i64, String> {
+        let value = self.eval(expr)?;
+        if value < min || value > max {
+            let sign = if min < 0 { "-" } else { "" };
+            return Err(format!("{} is out of range ({}{:#X} to {:#X})", expr.trim(), sign, min.abs(), max));
+        }
+        Ok(value)
+    }
+
+    fn addr(&self, expr: &str) -> Result<u16, String> { Ok(self.eval_range(expr, 0, 0xFFF)? as u16) }
+    // negative bytes are allowed and stored as two's complement, `ADD V0, -1`
+    fn byte(&self, expr: &str) -> Result<u8, String> { Ok(self.eval_range(expr, -0x80, 0xFF)? as u8) }
+    fn nibble(&self, expr: &str) -> Result<u8, String> { Ok(self.eval_range(expr, 0, 0xF)? as u8) }
+
+    fn instruction(&self, mnemonic: &str, operands: &[&str]) -> Result<Instruction, String> {
+        use Instruction::*;
+        let ops: Vec<Operand> = operands.iter().map(|op| Operand::parse(op)).collect();
+        let mnemonic = mnemonic.to_ascii_uppercase();
+        // the value in an operand that isn't a register or keyword
+        let val = |idx: usize| operands[idx];
+        Ok(match (mnemonic.as_str(), ops.as_slice()) {
+            ("CLS", []) => ClearScreen,
+            ("RET", []) => Return,
+            ("SCR", []) => ScrollRight,
+            ("SCL", []) => ScrollLeft,
+            ("EXIT", []) => Exit,
+            ("LOW", []) => Lores,
+            ("HIGH", []) => Hires,
+            ("AUDIO", []) => LoadAudio,
+            ("SYS", [Operand::Value]) => {
+                // 00E0, 00EE, 00CN, ... would come out as some other instruction
+                let addr = self.addr(val(0))?;
+                if !matches!(Instruction::decode(addr, 0), Some(Sys(_))) {
+                    return Err(format!("SYS {:#05X} is the opcode of another instruction", addr));
+                }
+                Sys(addr)
+            }
+            ("SCD", [Operand::Value]) => ScrollDown(self.nibble(val(0))?),
+            ("SCU", [Operand::Value]) => ScrollUp(self.nibble(val(0))?),
+            ("PLANE", [Operand::Value]) => SelectPlanes(self.nibble(val(0))?),
+            ("JP", [Operand::Value]) => Jump(self.addr(val(0))?),
+            ("JP", [Operand::V(0), Operand::Value]) => JumpOffset(self.addr(val(1))?),
+            ("CALL", [Operand::Value]) => Call(self.addr(val(0))?),
+            ("SE", [Operand::V(x), Operand::Value]) => SkipEqImm(*x, self.byte(val(1))?),
+            ("SE", [Operand::V(x), Operand::V(y)]) => SkipEq(*x, *y),
+            ("SNE", [Operand::V(x), Operand::Value]) => SkipNeImm(*x, self.byte(val(1))?),
+            ("SNE", [Operand::V(x), Operand::V(y)]) => SkipNe(*x, *y),
+            ("LD", [Operand::V(x), Operand::Value]) => SetImm(*x, self.byte(val(1))?),
+            ("LD", [Operand::V(x), Operand::V(y)]) => Set(*x, *y),
+            ("LD", [Operand::I, Operand::Value]) => SetI(self.addr(val(1))?),
+            ("LD", [Operand::I, Operand::Long]) => {
+                let expr = operands[1].trim()[4..].trim();
+                SetILong(self.eval_range(expr, 0, 0xFFFF)? as u16)
+            }
+            ("LD", [Operand::V(x), Operand::Dt]) => GetDelay(*x),
+            ("LD", [Operand::V(x), Operand::K]) => WaitKey(*x),
+            ("LD", [Operand::Dt, Operand::V(x)]) => SetDelay(*x),
+            ("LD", [Operand::St, Operand::V(x)]) => SetSound(*x),
+            ("LD", [Operand::F, Operand::V(x)]) => Font(*x),
+            ("LD", [Operand::Hf, Operand::V(x)]) => BigFont(*x),
+            ("LD", [Operand::B, Operand::V(x)]) => Bcd(*x),
+            ("LD", [Operand::IndirectI, Operand::V(x)]) => Store(*x),
+            ("LD", [Operand::V(x), Operand::IndirectI]) => Load(*x),
+            ("LD", [Operand::R, Operand::V(x)]) => SaveFlags(*x),
+            ("LD", [Operand::V(x), Operand::R]) => LoadFlags(*x),
+            ("ADD", [Operand::V(x), Operand::Value]) => AddImm(*x, self.byte(val(1))?),
+            ("ADD", [Operand::V(x), Operand::V(y)]) => Add(*x, *y),
+            ("ADD", [Operand::I, Operand::V(x)]) => AddI(*x),
+            ("OR", [Operand::V(x), Operand::V(y)]) => Or(*x, *y),
+            ("AND", [Operand::V(x), Operand::V(y)]) => And(*x, *y),
+            ("XOR", [Operand::V(x), Operand::V(y)]) => Xor(*x, *y),
+            ("SUB", [Operand::V(x), Operand::V(y)]) => Sub(*x, *y),
+            ("SUBN", [Operand::V(x), Operand::V(y)]) => SubReverse(*x, *y),
+            // the single register form shifts Vx in place, whatever the shift quirk
+            ("SHR", [Operand::V(x)]) => ShiftRight(*x, *x),
+            ("SHR", [Operand::V(x), Operand::V(y)]) => ShiftRight(*x, *y),
+            ("SHL", [Operand::V(x)]) => ShiftLeft(*x, *x),
+            ("SHL", [Operand::V(x), Operand::V(y)]) => ShiftLeft(*x, *y),
+            ("RND", [Operand::V(x), Operand::Value]) => Random(*x, self.byte(val(1))?),
+            ("DRW", [Operand::V(x), Operand::V(y), Operand::Value]) => Draw(*x, *y, self.nibble(val(2))?),
+            ("SKP", [Operand::V(x)]) => SkipKey(*x),
+            ("SKNP", [Operand::V(x)]) => SkipNotKey(*x),
+            ("PITCH", [Operand::V(x)]) => SetPitch(*x),
+            ("SAVE", [Operand::Range(x, y)]) => SaveRange(*x, *y),
+            ("LOAD", [Operand::Range(x, y)]) => LoadRange(*x, *y),
+            _ => return Err(format!("bad instruction: {} {}", mnemonic, operands.join(", ")).trim().to_string()),
+        })
+    }
+
+    fn emit(&self, statement: &Statement, out: &mut Vec<u8>) -> Result<(), String> {
+        match statement {
+            Statement::Instr(mnemonic, operands) => out.extend(self.instruction(mnemonic, operands)?.encode()),
+            Statement::Bytes(values) => {
+                for value in values {
+                    out.push(self.byte(value)?);
+                }
+            }
+            Statement::Words(values) => {
+                for value in values {
+                    out.extend_from_slice(&(self.eval_range(value, -0x8000, 0xFFFF)? as u16).to_be_bytes());
+                }
+            }
+            Statement::Sprite(row) => out.extend_from_slice(row),
+        }
+        Ok(())
+    }
+}
+
+// operand kinds, values are evaluated separately once the kind has picked the instruction
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    V(u8),
+    Range(u8, u8), // Vx - Vy
+    I,
+    IndirectI, // [I]
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long, // LONG NNNN
+    Value,
+}
+
+impl Operand {
+    fn parse(text: &str) -> Operand {
+        let reg = |text: &str| {
+            let text = text.trim();
+            let digit = text.strip_prefix('V').or_else(|| text.strip_prefix('v'))?;
+            if digit.len() == 1 { u8::from_str_radix(digit, 16).ok() } else { None }
+        };
+        if let Some(x) = reg(text) {
+            return Operand::V(x);
+        }
+        if let Some((x, y)) = text.split_once('-').and_then(|(x, y)| Some((reg(x)?, reg(y)?))) {
+            return Operand::Range(x, y);
+        }
+        if starts_with_word(text, "long") {
+            return Operand::Long;
+        }
+        match text.trim().to_ascii_uppercase().as_str() {
+            "I" => Operand::I,
+            "[I]" => Operand::IndirectI,
+            "DT" => Operand::Dt,
+            "ST" => Operand::St,
+            "K" => Operand::K,
+            "F" => Operand::F,
+            "HF" => Operand::Hf,
+            "B" => Operand::B,
+            "R" => Operand::R,
+            _ => Operand::Value,
+        }
+    }
+}
+
+// origin is the address the binary will be loaded at, normally 0x200
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, AsmError> {
+    let mut asm = Assembler { symbols: HashMap::new() };
+    let mut statements = vec![];
+
+    // first pass: sizes, so every label has an address
+    let mut addr = origin as i64;
+    for (line_num, line) in source.lines().enumerate() {
+        let at_line = |msg: String| AsmError { line: line_num + 1, msg };
+        let mut line = line.split(';').next().unwrap().trim();
+        if let Some((label, rest)) = line.split_once(':') {
+            asm.define(label.trim(), addr).map_err(at_line)?;
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (word, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let rest = rest.trim();
+        let list = || rest.split(',').map(|s| s.trim()).collect::<Vec<_>>();
+        let statement = match word.to_ascii_lowercase().as_str() {
+            "db" => Statement::Bytes(list()),
+            "dw" => Statement::Words(list()),
+            "sprite" => Statement::Sprite(parse_sprite_row(rest).map_err(at_line)?),
+            _ if rest.split_whitespace().next().is_some_and(|w| w.eq_ignore_ascii_case("equ")) => {
+                let value = asm.eval(rest.split_whitespace().skip(1).collect::<Vec<_>>().join(" ").as_str());
+                asm.define(word, value.map_err(at_line)?).map_err(at_line)?;
+                continue;
+            }
+            _ if rest.is_empty() => Statement::Instr(word, vec![]),
+            _ => Statement::Instr(word, list()),
+        };
+        addr += statement.size() as i64;
+        if addr > 0x10000 {
+            return Err(at_line("program doesn't fit in memory".to_string()));
+        }
+        statements.push((line_num, statement));
+    }
+
+    // second pass: everything is known, encode
+    let mut out = vec![];
+    for (line_num, statement) in &statements {
+        asm.emit(statement, &mut out).map_err(|msg| AsmError { line: line_num + 1, msg })?;
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::{self, Syntax};
+
+    // every opcode that decodes: encode, disassemble, assemble and get the same bytes
+    #[test]
+    fn round_trips_every_instruction() {
+        for opcode in 0..=0xFFFF {
+            let instr = match Instruction::decode(opcode, 0x1234) {
+                Some(instr) => instr,
+                None => continue,
+            };
+            let bytes = instr.encode();
+            let word = |at: usize| bytes.get(at..at + 2).map_or(0, |w| u16::from_be_bytes([w[0], w[1]]));
+            assert_eq!(Instruction::decode(word(0), word(2)), Some(instr), "{:04X}", opcode);
+            let text = disasm::format(instr, Syntax::Cowgod);
+            assert_eq!(assemble(&text, 0x200).as_ref(), Ok(&bytes), "{:04X}: {}", opcode, text);
+        }
+    }
+
+    #[test]
+    fn sys_refuses_other_instructions() {
+        for addr in ["0x0E0", "0x0EE", "0x0C3", "0x0D1", "0x0FB", "0x0FF"] {
+            let err = assemble(&format!("SYS {}", addr), 0x200).unwrap_err();
+            assert!(err.msg.contains("another instruction"), "{}", err);
+        }
+        assert_eq!(assemble("SYS 0x123", 0x200), Ok(vec![0x01, 0x23]));
+    }
+}
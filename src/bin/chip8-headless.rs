@@ -0,0 +1,316 @@
+// Runs a rom without a window for a fixed number of cycles or frames, then dumps the
+// screen, registers and memory. Meant for CI. It drives the same Emulator as the
+// window, through frontends that take their keys from a script and run in emulated
+// time; build with --no-default-features to leave out SDL2 as well.
+//
+// exit codes:
+//   0 - the rom halted by itself (00FD)
+//   1 - the cpu reported an error
+//   2 - the cycle/frame limit was reached
+//  64 - bad arguments or files
+
+use chip8::cpu;
+use chip8::emulator::{Emulator, ErrorPolicy, Stop, DEFAULT_IPF};
+use chip8::frontend::{Audio, Clock, Display, Input, InputEvent};
+use chip8::movie::Movie;
+use chip8::quirks::Quirks;
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::time::Duration;
+use std::{env, fs, io, process};
+
+const EXIT_HALTED: i32 = 0;
+const EXIT_ERROR: i32 = 1;
+const EXIT_LIMIT: i32 = 2;
+const EXIT_USAGE: i32 = 64;
+
+// colors of the png dump, indexed by the bitmask of lit planes
+const PNG_PALETTE: [[u8; 3]; 1 << cpu::NUM_PLANES] =
+    [[0, 0, 0], [255, 255, 255], [128, 128, 128], [192, 192, 192]];
+const ASCII_PALETTE: [char; 1 << cpu::NUM_PLANES] = ['.', '#', '+', '@'];
+
+const USAGE: &str = "Usage: chip8-headless [options] path/to/rom|game.8o|-
+
+  --quirks vip|schip|xochip  quirk profile (default vip)
+  --cycles N                 stop after N instructions
+  --frames N                 stop after N frames (60 Hz timer ticks)
+  --ipf N                    instructions per frame (default 8)
+  --vip-timing               run instructions for roughly as long as they took on the
+                             COSMAC VIP instead of a fixed number per frame
+  --keys FILE                key script, one \"<frame> <hex key> down|up\" per line. Like
+                             in the window, keys change one per frame, so a second change
+                             on the same frame happens on the next one
+  --replay FILE              play back a movie recorded with `chip8 --record`, using its
+                             quirks, load address, seed and speed (runs to its end without
+                             a limit)
+  --seed N                   rng seed (default 0)
+  --load-address ADDR        where the rom is loaded and starts (default 200, 600 for ETI-660)
+  --screen FILE|-            framebuffer dump, png if FILE ends in .png (default -)
+  --regs FILE|-              register dump (default -)
+  --mem FILE|-               hex dump of memory (default none)
+  --skip-errors              treat faulting instructions as NOPs instead of stopping";
+
+struct Options {
+    quirks: Quirks,
+    max_cycles: Option<u64>,
+    max_frames: Option<u64>,
+    ipf: u64,
+    vip_timing: bool,
+    keys: BTreeMap<u64, Vec<(usize, bool)>>, // frame -> (key, pressed)
+    movie: Option<Movie>,
+    seed: u64,
+    load_address: usize,
+    screen: Option<String>,
+    regs: Option<String>,
+    mem: Option<String>,
+    skip_errors: bool,
+    rom_file: String,
+}
+
+fn parse_num(arg: &str, val: Option<&String>) -> Result<u64, String> {
+    let val = val.ok_or(format!("{} needs a value", arg))?;
+    val.parse().map_err(|_| format!("{}: not a number: {}", arg, val))
+}
+
+fn parse_key_script(path: &str) -> Result<BTreeMap<u64, Vec<(usize, bool)>>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let mut keys: BTreeMap<u64, Vec<(usize, bool)>> = BTreeMap::new();
+    for (line_num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let bad_line = || format!("{}:{}: expected \"<frame> <hex key> down|up\"", path, line_num + 1);
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 3 {
+            return Err(bad_line());
+        }
+        let frame: u64 = fields[0].parse().map_err(|_| bad_line())?;
+        let key = usize::from_str_radix(fields[1].trim_start_matches("0x"), 16)
+            .ok()
+            .filter(|&key| key < 16)
+            .ok_or_else(bad_line)?;
+        let pressed = match fields[2] {
+            "down" => true,
+            "up" => false,
+            _ => return Err(bad_line()),
+        };
+        keys.entry(frame).or_default().push((key, pressed));
+    }
+    Ok(keys)
+}
+
+fn read_movie(path: &str) -> Result<Movie, String> {
+    let data = fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+    Movie::from_bytes(&data).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        quirks: Quirks::default(),
+        max_cycles: None,
+        max_frames: None,
+        ipf: DEFAULT_IPF,
+        vip_timing: false,
+        keys: BTreeMap::new(),
+        movie: None,
+        seed: 0,
+        load_address: cpu::ROM_START,
+        screen: Some("-".to_string()),
+        regs: Some("-".to_string()),
+        mem: None,
+        skip_errors: false,
+        rom_file: String::new(),
+    };
+    let mut rom_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = args.next().ok_or("--quirks needs a value")?;
+                options.quirks =
+                    Quirks::from_name(name).ok_or(format!("Unknown quirk profile: {}", name))?;
+            }
+            "--cycles" => options.max_cycles = Some(parse_num(arg, args.next())?),
+            "--frames" => options.max_frames = Some(parse_num(arg, args.next())?),
+            "--ipf" => options.ipf = parse_num(arg, args.next())?.max(1),
+            "--vip-timing" => options.vip_timing = true,
+            "--keys" => options.keys = parse_key_script(args.next().ok_or("--keys needs a file")?)?,
+            "--replay" => options.movie = Some(read_movie(args.next().ok_or("--replay needs a file")?)?),
+            "--seed" => options.seed = parse_num(arg, args.next())?,
+            "--load-address" => {
+                options.load_address = chip8::debugger::parse_addr(args.next().map(|s| s.as_str()))? as usize
+            }
+            "--screen" => options.screen = Some(args.next().ok_or("--screen needs a file")?.clone()),
+            "--regs" => options.regs = Some(args.next().ok_or("--regs needs a file")?.clone()),
+            "--mem" => options.mem = Some(args.next().ok_or("--mem needs a file")?.clone()),
+            "--skip-errors" => options.skip_errors = true,
+            _ if rom_file.is_none() && !arg.starts_with("--") => rom_file = Some(arg.to_string()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    if let Some(movie) = &options.movie {
+        if !options.keys.is_empty() {
+            return Err("--replay and --keys can't be combined".to_string());
+        }
+        options.quirks = movie.quirks;
+        options.load_address = movie.load_address as usize;
+        options.seed = movie.seed;
+        options.vip_timing = movie.ipf == 0;
+        options.ipf = movie.ipf.max(1);
+        if options.max_cycles.is_none() && options.max_frames.is_none() {
+            options.max_frames = Some(movie.frames.len() as u64);
+        }
+    }
+    if options.max_cycles.is_none() && options.max_frames.is_none() {
+        return Err("Need a limit, --cycles or --frames".to_string());
+    }
+    options.rom_file = rom_file.ok_or("No rom given")?;
+    Ok(options)
+}
+
+// nothing to show, the screen is dumped from the cpu at the end
+struct NullDisplay;
+
+impl Display for NullDisplay {
+    fn draw(&mut self, _gfx: &[u8], _cols: usize, _rows: usize) -> Result<(), String> { Ok(()) }
+}
+
+struct NullAudio;
+
+impl Audio for NullAudio {
+    fn set_playing(&mut self, _playing: bool) {}
+    fn set_pattern(&mut self, _pattern: [u8; cpu::AUDIO_PATTERN_SIZE], _pitch: u8) {}
+}
+
+// the key script, the emulator polls once at the start of every frame
+struct ScriptInput {
+    keys: BTreeMap<u64, Vec<(usize, bool)>>,
+    frame: u64,
+}
+
+impl Input for ScriptInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let keys = self.keys.remove(&self.frame).unwrap_or_default();
+        self.frame += 1;
+        let event = |(key, pressed)| if pressed { InputEvent::KeyDown(key) } else { InputEvent::KeyUp(key) };
+        keys.into_iter().map(event).collect()
+    }
+}
+
+// emulated time, sleeping only moves it on so frames run as fast as they can
+#[derive(Default)]
+struct VirtualClock {
+    now: Duration,
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Duration { self.now }
+    fn sleep(&mut self, duration: Duration) { self.now += duration; }
+}
+
+type HeadlessEmulator = Emulator<NullDisplay, NullAudio, ScriptInput, VirtualClock>;
+
+// runs until the rom halts, errors or a limit is hit, returning the exit code
+fn run(emu: &mut HeadlessEmulator) -> i32 {
+    match emu.run() {
+        Stop::Exited => EXIT_HALTED,
+        Stop::Error(err) => {
+            eprintln!("halted: {}", err);
+            EXIT_ERROR
+        }
+        Stop::Display(_) => EXIT_ERROR,
+        Stop::Limit | Stop::Quit => EXIT_LIMIT,
+    }
+}
+
+fn open_output(path: &str) -> io::Result<Box<dyn Write>> {
+    if path == "-" {
+        Ok(Box::new(io::stdout()))
+    } else {
+        Ok(Box::new(fs::File::create(path)?))
+    }
+}
+
+fn dump_screen(cpu: &cpu::CPU, path: &str) -> io::Result<()> {
+    let (cols, rows) = cpu.get_gfx_size();
+    let gfx = cpu.get_gfx();
+    if path.ends_with(".png") {
+        let file = io::BufWriter::new(fs::File::create(path)?);
+        let mut encoder = png::Encoder::new(file, cols as u32, rows as u32);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let data: Vec<u8> = gfx.iter().flat_map(|&px| PNG_PALETTE[px as usize]).collect();
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&data)?;
+        return Ok(());
+    }
+    let mut out = open_output(path)?;
+    for row in gfx.chunks(cols) {
+        let line: String = row.iter().map(|&px| ASCII_PALETTE[px as usize]).collect();
+        writeln!(out, "{}", line)?;
+    }
+    Ok(())
+}
+
+fn dump_mem(cpu: &cpu::CPU, path: &str) -> io::Result<()> {
+    let mem = cpu.get_mem();
+    open_output(path)?.write_all(chip8::debugger::hex_dump(mem, 0, mem.len()).as_bytes())
+}
+
+fn dump_all(cpu: &cpu::CPU, options: &Options) -> Result<(), String> {
+    let with_path = |path: &str, e: io::Error| format!("{}: {}", path, e);
+    if let Some(path) = &options.screen {
+        dump_screen(cpu, path).map_err(|e| with_path(path, e))?;
+    }
+    if let Some(path) = &options.regs {
+        open_output(path)
+            .and_then(|mut out| out.write_all(cpu.dump_registers().as_bytes()))
+            .map_err(|e| with_path(path, e))?;
+    }
+    if let Some(path) = &options.mem {
+        dump_mem(cpu, path).map_err(|e| with_path(path, e))?;
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("{}\n\n{}", msg, USAGE);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let rom = match chip8::rom::load_program(&options.rom_file, options.load_address) {
+        Ok(program) => program.rom,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let mut cpu = cpu::CPU::new(options.quirks);
+    cpu.load_rom_at(&rom, options.load_address);
+    cpu.seed_rng(options.seed);
+    let on_error = if options.skip_errors { ErrorPolicy::Skip } else { ErrorPolicy::Halt };
+    let input = ScriptInput { keys: options.keys.clone(), frame: 0 };
+    let mut emu = Emulator::new(cpu, NullDisplay, NullAudio, input, VirtualClock::default(), on_error);
+    emu.set_ipf(options.ipf);
+    emu.set_vip_timing(options.vip_timing);
+    emu.set_limits(options.max_frames, options.max_cycles);
+    if let Some(movie) = options.movie.clone() {
+        if let Err(e) = emu.play_movie(movie) {
+            eprintln!("{}", e);
+            process::exit(EXIT_USAGE);
+        }
+    }
+    let code = run(&mut emu);
+
+    if let Err(e) = dump_all(emu.cpu(), &options) {
+        eprintln!("{}", e);
+        process::exit(EXIT_USAGE);
+    }
+    process::exit(code);
+}
@@ -0,0 +1,214 @@
+// User settings, read from a TOML file (see default_path), e.g.
+//
+//     palette = "amber" # used at startup unless --palette says otherwise
+//     axis_threshold = 0.5 # how far a controller's stick has to be pushed to press a key
+//     database = "/path/to/chip-8-database/database/programs.json" # instead of the bundled one
+//
+//     [palettes]
+//     mine = ["#000000", "#FFFFFF"]                     # background, foreground
+//     xo = ["#000000", "#FF0000", "#00FF00", "#FFFF00"] # background, plane 1, plane 2, both
+//
+//     [keys] # hex keys not listed here keep their default, see keymap.rs
+//     5 = ["W", "Up"]
+//     8 = ["scancode:S", "Down"]
+//
+//     [roms."pong.ch8".keys] # by file name or SHA-1, on top of [keys]
+//     1 = ["scancode:W", "button:dpup"]
+//
+// Palettes defined here come after the built-in ones, or replace one of the same name.
+
+use crate::keymap::{self, Binding, KeyOverrides, Keymap};
+use crate::palette::{self, Palette};
+use crate::state::RomHash;
+
+use std::path::{Path, PathBuf};
+
+// settings for a single rom
+#[derive(Default)]
+pub struct RomConfig {
+    pub keys: KeyOverrides,
+}
+
+pub struct Config {
+    pub palettes: Vec<Palette>,
+    pub palette: Option<String>, // name of the one to start with
+    pub keys: KeyOverrides,
+    pub axis_threshold: f32,
+    pub roms: Vec<(String, RomConfig)>, // file name or hex SHA-1 of the rom
+    pub database: Option<PathBuf>,      // rom database, see romdb.rs
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            palettes: palette::builtin(),
+            palette: None,
+            keys: vec![],
+            axis_threshold: keymap::DEFAULT_AXIS_THRESHOLD,
+            roms: vec![],
+            database: None,
+        }
+    }
+}
+
+// $XDG_CONFIG_HOME/chip8/config.toml, or ~/.config/chip8/config.toml
+pub fn default_path() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(dir.join("chip8").join("config.toml"))
+}
+
+pub fn load(path: &Path) -> Result<Config, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+    parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+pub fn parse(text: &str) -> Result<Config, String> {
+    let table: toml::Table = text.parse().map_err(|e: toml::de::Error| match e.span() {
+        Some(span) => format!("line {}: {}", text[..span.start].matches('\n').count() + 1, e.message()),
+        None => e.message().to_string(),
+    })?;
+    let mut config = Config::default();
+    for (key, value) in &table {
+        match (key.as_str(), value) {
+            ("palette", toml::Value::String(name)) => config.palette = Some(name.clone()),
+            ("palettes", toml::Value::Table(palettes)) => {
+                for (name, colors) in palettes {
+                    config.add_palette(parse_palette(name, colors)?);
+                }
+            }
+            ("keys", toml::Value::Table(keys)) => config.keys = parse_keys("keys", keys)?,
+            ("axis_threshold", toml::Value::Float(threshold)) if (0.0..=1.0).contains(threshold) => {
+                config.axis_threshold = *threshold as f32
+            }
+            ("axis_threshold", _) => return Err("axis_threshold should be between 0.0 and 1.0".to_string()),
+            ("roms", toml::Value::Table(roms)) => {
+                for (rom, settings) in roms {
+                    config.roms.push((rom.clone(), parse_rom(rom, settings)?));
+                }
+            }
+            ("database", toml::Value::String(path)) => config.database = Some(path.into()),
+            ("palette", _) => return Err("palette should be the name of one".to_string()),
+            ("database", _) => return Err("database should be the path of a programs.json".to_string()),
+            ("palettes" | "keys" | "roms", _) => return Err(format!("{} should be a table", key)),
+            _ => return Err(format!("unknown setting: {}", key)),
+        }
+    }
+    Ok(config)
+}
+
+fn parse_palette(name: &str, colors: &toml::Value) -> Result<Palette, String> {
+    let bad = || format!("palettes.{}: expected a list of 2 or 4 \"#RRGGBB\" colors", name);
+    let colors = colors.as_array().ok_or_else(bad)?;
+    let colors: Vec<palette::Rgb> = colors
+        .iter()
+        .map(|color| color.as_str().and_then(palette::parse_color))
+        .collect::<Option<_>>()
+        .ok_or_else(bad)?;
+    palette::from_colors(name, &colors).ok_or_else(bad)
+}
+
+// hex key = key name or list of them
+fn parse_keys(section: &str, keys: &toml::Table) -> Result<KeyOverrides, String> {
+    let mut overrides = vec![];
+    for (hex_key, names) in keys {
+        let key = keymap::parse_hex_key(hex_key)
+            .ok_or_else(|| format!("{}: not a hex key: {}", section, hex_key))?;
+        let bad = || format!("{}.{}: expected a key name or a list of them", section, hex_key);
+        let names: Vec<&str> = match names {
+            toml::Value::String(name) => vec![name],
+            toml::Value::Array(names) => {
+                names.iter().map(|name| name.as_str()).collect::<Option<_>>().ok_or_else(bad)?
+            }
+            _ => return Err(bad()),
+        };
+        overrides.push((key, names.into_iter().map(Binding::parse).collect()));
+    }
+    Ok(overrides)
+}
+
+fn parse_rom(rom: &str, settings: &toml::Value) -> Result<RomConfig, String> {
+    let settings = settings.as_table().ok_or_else(|| format!("roms.{} should be a table", rom))?;
+    let mut config = RomConfig::default();
+    for (key, value) in settings {
+        match (key.as_str(), value) {
+            ("keys", toml::Value::Table(keys)) => {
+                config.keys = parse_keys(&format!("roms.{}.keys", rom), keys)?
+            }
+            _ => return Err(format!("unknown setting: roms.{}.{}", rom, key)),
+        }
+    }
+    Ok(config)
+}
+
+impl Config {
+    // keymap (the default layout, or the rom database's) with [keys] and then the rom's
+    // own keys applied
+    pub fn keymap(&self, mut keymap: Keymap, rom_path: &str, rom_hash: &RomHash) -> Keymap {
+        let file_name = Path::new(rom_path).file_name().map(|name| name.to_string_lossy());
+        let hash: String = rom_hash.iter().map(|byte| format!("{:02x}", byte)).collect();
+        keymap.axis_threshold = self.axis_threshold;
+        keymap.apply(&self.keys);
+        for (rom, settings) in &self.roms {
+            if file_name.as_deref() == Some(rom.as_str()) || rom.eq_ignore_ascii_case(&hash) {
+                keymap.apply(&settings.keys);
+            }
+        }
+        keymap
+    }
+
+    fn add_palette(&mut self, palette: Palette) {
+        match self.palettes.iter_mut().find(|known| known.name == palette.name) {
+            Some(known) => *known = palette,
+            None => self.palettes.push(palette),
+        }
+    }
+
+    pub fn find_palette(&self, name: &str) -> Option<usize> {
+        self.palettes.iter().position(|palette| palette.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn palette_names(&self) -> Vec<&str> {
+        self.palettes.iter().map(|palette| palette.name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_controller_bindings() {
+        let config = parse(
+            r#"
+            axis_threshold = 0.3
+
+            [keys]
+            5 = ["Up", "button:dpup", "axis:lefty-"]
+            6 = "axis:triggerright"
+
+            [roms."pong.ch8".keys]
+            1 = ["scancode:W", "button:x"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.axis_threshold, 0.3);
+        let keymap = config.keymap(Keymap::default(), "roms/pong.ch8", &[0; 20]);
+        assert_eq!(keymap.axis_threshold, 0.3);
+        assert_eq!(
+            keymap.keys[0x5],
+            [Binding::parse("Up"), Binding::Button("dpup".to_string()), Binding::Axis("lefty".to_string(), false)]
+        );
+        assert_eq!(keymap.keys[0x6], [Binding::Axis("triggerright".to_string(), true)]);
+        assert_eq!(keymap.keys[0x1], [Binding::Scancode("W".to_string()), Binding::Button("x".to_string())]);
+    }
+
+    #[test]
+    fn axis_threshold_outside_a_table() {
+        assert!(parse("axis_threshold = 1.5").is_err());
+        // after a table header it belongs to the table
+        assert!(parse("[roms.\"pong.ch8\".keys]\naxis_threshold = 0.3").is_err());
+    }
+}
@@ -1,46 +1,161 @@
-use rand::Rng;
+use crate::instruction::Instruction;
+use crate::quirks::Quirks;
+use crate::state::{self, RomHash, StateError};
+use crate::timing;
 
-const MEM_SIZE: usize = 0x1000;
-const ROM_START: usize = 0x200;
+use std::fmt;
+use std::ops::Range;
+
+pub const MEM_SIZE: usize = 0x10000; // XO-CHIP, everything else only uses the first 4 KiB
+pub const ROM_START: usize = 0x200;
 pub const ROM_SIZE: usize = MEM_SIZE - ROM_START;
 pub const GFX_COLS: usize = 64;
 pub const GFX_ROWS: usize = 32;
+pub const HIRES_GFX_COLS: usize = 128;
+pub const HIRES_GFX_ROWS: usize = 64;
 const FONT_LOC: usize = 0x50;
 const FONT_NUM_ROWS: usize = 5;
+const BIG_FONT_LOC: usize = FONT_LOC + FONT_NUM_ROWS * 16;
+const BIG_FONT_NUM_ROWS: usize = 10;
+const NUM_RPL_FLAGS: usize = 16; // SCHIP only has 8, XO-CHIP has 16
+pub const NUM_PLANES: usize = 2;
+const PLANE_MASK: u8 = (1 << NUM_PLANES) - 1;
+pub const AUDIO_PATTERN_SIZE: usize = 16; // bytes, played back as 128 1-bit samples
+const DEFAULT_PITCH: u8 = 64; // 4000 Hz playback rate
+// square wave for ROMs that never load a pattern (500 Hz at the default pitch)
+const DEFAULT_AUDIO_PATTERN: [u8; AUDIO_PATTERN_SIZE] = [0xF0; AUDIO_PATTERN_SIZE];
 
-pub fn coords_to_index(x: u8, y: u8) -> usize {
-    (y as usize * GFX_COLS) + x as usize
-}
-pub fn index_to_coords(i: u16) -> (usize, usize) {
+pub fn coords_to_index(x: usize, y: usize, cols: usize) -> usize { (y * cols) + x }
+pub fn index_to_coords(i: usize, cols: usize) -> (usize, usize) {
     (
-        i as usize % GFX_COLS as usize,   //x, 0-indexed
-        (i as usize / GFX_COLS as usize), //y, 0-indexed
+        i % cols, //x, 0-indexed
+        i / cols, //y, 0-indexed
     )
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CpuErrorKind {
+    UnknownOpcode,
+    StackOverflow,
+    StackUnderflow,
+    MemoryOutOfBounds(usize), // first address that could not be accessed
+}
+
+// an instruction that could not be executed, pc is the address of that instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CpuError {
+    pub pc: u16,
+    pub opcode: u16,
+    pub kind: CpuErrorKind,
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.kind {
+            CpuErrorKind::UnknownOpcode => write!(f, "unknown opcode")?,
+            CpuErrorKind::StackOverflow => write!(f, "stack overflow")?,
+            CpuErrorKind::StackUnderflow => write!(f, "stack underflow")?,
+            CpuErrorKind::MemoryOutOfBounds(addr) => {
+                write!(f, "memory access out of bounds at {:#06X}", addr)?
+            }
+        }
+        write!(f, " (opcode {:04X} at {:#06X})", self.opcode, self.pc)
+    }
+}
+
+impl std::error::Error for CpuError {}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemAccess {
+    Read,
+    Write,
+}
+
+// range of memory (inclusive) whose data accesses get reported, see CPU::take_watch_hit
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub start: u16,
+    pub end: u16,
+    pub read: bool,
+    pub write: bool,
+}
+
+impl Watchpoint {
+    fn matches(&self, addr: usize, access: MemAccess) -> bool {
+        let wanted = match access {
+            MemAccess::Read => self.read,
+            MemAccess::Write => self.write,
+        };
+        wanted && (self.start as usize..=self.end as usize).contains(&addr)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub pc: u16, // address of the instruction that made the access
+    pub addr: u16,
+    pub access: MemAccess,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StepOutcome {
+    Executed,
+    Drew,   // gfx changed and should be presented
+    Exited, // 00FD, nothing left to run
+}
+
+// FX0A's progress. Like on the VIP it completes when a key is released, and only keys
+// that go down while it waits count.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum KeyWait {
+    Idle,
+    Waiting,
+    Pressed(u8),
+    Released(u8), // FX0A stores it the next time it runs
+}
+
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     opcode: u16, // big-endian
     mem: [u8; MEM_SIZE],
     regs: [u8; 16],                   // named V0..VF
     keys: [bool; 16],                 // true iff key is pressed, from key 0 to key F
-    gfx: [bool; GFX_ROWS * GFX_COLS], // pixels, true or false (easier than trying some bit array)
+    gfx: Vec<u8>,                     // pixels, one bit per plane that is lit
+    hires: bool,                      // 128x64 SCHIP mode instead of 64x32
+    planes: u8,                       // XO-CHIP bitmask of planes drawn to by DXYN, 00E0 and scrolls
     stack: [u16; 16],                 // stores pc on each jump
     sp: u8,
     i: u16,
     pc: u16,
     delay_timer: u8,
     sound_timer: u8,
-    pub ignore_keypress: bool, //hacky workaround
+    key_wait: KeyWait,
+    quirks: Quirks,
+    vblank: bool, // set on every timer tick, consumed by DXYN when quirks.display_wait
+    rpl: [u8; NUM_RPL_FLAGS], // SCHIP "RPL user flags", persisted by FX75/FX85
+    redraw: bool,             // gfx changed since perform_cycle last returned Drew
+    exited: bool,             // 00FD was executed
+    audio_pattern: [u8; AUDIO_PATTERN_SIZE], // XO-CHIP sound buffer, loaded by F002
+    pitch: u8,                               // XO-CHIP pitch register, set by FX3A
+    op_pc: u16,                   // address of the instruction being executed
+    watchpoints: Vec<Watchpoint>, // checked on every data access by load/store
+    watch_hit: Option<WatchHit>,  // first watchpoint hit since the last take_watch_hit
+    cycles: u32,                  // VIP machine cycles the last instruction took
+    rom_hash: RomHash,            // SHA-1 of the loaded rom, ties save states to it
+    load_address: u16,            // where the rom was loaded and started
+    rng: u64,                     // xorshift64* state for CXNN, never 0
 
     // memory layout
     // 0x000-0x1FF - Chip 8 interpreter (contains font set in emu)
-    // 0x050-0x0A0 - Used for the built in 4x5 pixel font set (0-F)
+    // 0x050-0x09F - Used for the built in 4x5 pixel font set (0-F)
+    // 0x0A0-0x0FF - Used for the built in 8x10 SCHIP font set (0-F)
     // 0x200-0xFFF - most chip8 programs (eti660 chip8 roms start at 0x600)
+    // 0x1000-0xFFFF - rest of the XO-CHIP address space
     // 
     // TODO: Can (maybe?) use virtual addresses,
     //  reducing memory space needed by eliminating 0x0..0x50 and 0xA1..0x1FF
 
-    // gfx layout
+    // gfx layout (hires is 128x64, with the corners at (127,00) and (127,63))
     // +--------------------+
     // |(00,00)      (63,00)|
     // |                    |
@@ -50,26 +165,46 @@ pub struct CPU {
     // sprites
     //
     // up to 15 bytes, each byte being a row of pixels
+    // or 32 bytes for a 16x16 SCHIP sprite (DXY0), two bytes per row
     // sprites are XORed with gfx to turn on/off pixels
+    // with more than one XO-CHIP plane selected, the sprite data for each
+    // plane follows the previous one in memory (plane 1 first)
     // font sprites for hex digits 0-F are located in the first section of mem
 }
 
 impl CPU {
-    pub fn new() -> CPU {
+    pub fn new(quirks: Quirks) -> CPU {
         let mut cpu = CPU {
             opcode: 0,
             mem: [0u8; MEM_SIZE],
             regs: [0; 16],
             keys: [false; 16],
-            gfx: [false; GFX_COLS * GFX_ROWS],
+            gfx: vec![0; GFX_COLS * GFX_ROWS],
+            hires: false,
+            planes: 0x1,
             stack: [0; 16],
             sp: 0,
             i: 0,
             pc: ROM_START as u16,
             delay_timer: 0,
             sound_timer: 0,
-            ignore_keypress: false,
+            key_wait: KeyWait::Idle,
+            quirks,
+            vblank: false,
+            rpl: [0; NUM_RPL_FLAGS],
+            redraw: false,
+            exited: false,
+            audio_pattern: DEFAULT_AUDIO_PATTERN,
+            pitch: DEFAULT_PITCH,
+            op_pc: ROM_START as u16,
+            watchpoints: vec![],
+            watch_hit: None,
+            cycles: 0,
+            rom_hash: state::rom_hash(&[]),
+            load_address: ROM_START as u16,
+            rng: 1,
         };
+        cpu.seed_rng(rand::random());
         cpu.load_font();
         cpu
     }
@@ -95,305 +230,1001 @@ impl CPU {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // f
         ];
         self.mem[FONT_LOC..FONT_LOC + (FONT_NUM_ROWS * 16)].copy_from_slice(&CHARS);
+
+        // SCHIP only defines 0-9, A-F are the ones Octo uses
+        const BIG_CHARS: [u8; BIG_FONT_NUM_ROWS * 16] = [
+            0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+            0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+            0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+            0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+            0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+            0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+            0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+            0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // a
+            0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // b
+            0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // c
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // d
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // e
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // f
+        ];
+        self.mem[BIG_FONT_LOC..BIG_FONT_LOC + (BIG_FONT_NUM_ROWS * 16)].copy_from_slice(&BIG_CHARS);
+    }
+
+    fn fetch(&mut self) -> Result<(), CpuErrorKind> {
+        self.opcode = self.read_word(self.pc as usize)?;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    // all memory accesses made by instructions go through these, so a bad I
+    // (or pc) is reported instead of panicking on the array bounds
+    fn mem_range(&self, start: usize, len: usize) -> Result<Range<usize>, CpuErrorKind> {
+        if start + len > MEM_SIZE {
+            return Err(CpuErrorKind::MemoryOutOfBounds(start.max(MEM_SIZE)));
+        }
+        Ok(start..start + len)
+    }
+    fn read_word(&self, addr: usize) -> Result<u16, CpuErrorKind> {
+        let range = self.mem_range(addr, 2)?;
+        Ok((self.mem[range.start] as u16) << 8 | self.mem[range.start + 1] as u16)
     }
 
-    fn fetch(&mut self) {
-        self.opcode =
-            (self.mem[self.pc as usize] as u16) << 8 | (self.mem[(self.pc + 1) as usize] as u16);
-        self.pc += 2;
+    // data accesses made by instructions (as opposed to fetching the instructions
+    // themselves) go through load/store so watchpoints see them. Callers check the
+    // whole range they are going to touch with mem_range first.
+    fn load(&mut self, addr: usize) -> u8 {
+        self.watch(addr, MemAccess::Read);
+        self.mem[addr]
+    }
+    fn store(&mut self, addr: usize, val: u8) {
+        self.watch(addr, MemAccess::Write);
+        self.mem[addr] = val;
+    }
+    fn watch(&mut self, addr: usize, access: MemAccess) {
+        if self.watch_hit.is_none() && self.watchpoints.iter().any(|w| w.matches(addr, access)) {
+            self.watch_hit = Some(WatchHit {
+                pc: self.op_pc,
+                addr: addr as u16,
+                access,
+            });
+        }
     }
 
     // helper functions that should help with readability
     // could have been macros, but this will type check
 
-    fn nibble2_usize(&self) -> usize { ((self.opcode & 0xF00) >> 8) as usize }
-    fn nibble3_usize(&self) -> usize { ((self.opcode & 0xF0) >> 4) as usize }
-    fn nibble2_reg(&mut self) -> &mut u8 { &mut self.regs[self.nibble2_usize()] as &mut u8 }
-    fn nibble3_reg(&mut self) -> &mut u8 { &mut self.regs[self.nibble3_usize()] as &mut u8 }
-    fn lower_4_val(&self) -> u8 { (self.opcode & 0xF) as u8 }
-    fn lower_8_val(&self) -> u8 { (self.opcode & 0xFF) as u8 }
-    fn lower_12_val(&self) -> u16 { (self.opcode & 0xFFF) as u16 }
-    fn fetch_sprite_row(&self, i: usize) -> [bool; 8] {
-        // returns a byte at self.mem[i] as an array of bools
-        [
-            (self.mem[i] & 0b10000000) == 0b10000000,
-            (self.mem[i] & 0b01000000) == 0b01000000,
-            (self.mem[i] & 0b00100000) == 0b00100000,
-            (self.mem[i] & 0b00010000) == 0b00010000,
-            (self.mem[i] & 0b00001000) == 0b00001000,
-            (self.mem[i] & 0b00000100) == 0b00000100,
-            (self.mem[i] & 0b00000010) == 0b00000010,
-            (self.mem[i] & 0b00000001) == 0b00000001,
-        ]
+    fn reg(&mut self, x: u8) -> &mut u8 { &mut self.regs[x as usize] }
+    fn shift_source(&self, x: u8, y: u8) -> u8 {
+        self.regs[if self.quirks.shift_uses_vy { y } else { x } as usize]
     }
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.regs[15] = 0;
+        }
+    }
+    fn fetch_sprite_row(&mut self, i: usize, width: usize) -> u16 {
+        // returns the row of a sprite starting at self.mem[i], left-aligned pixels as bits
+        if width == 16 {
+            (self.load(i) as u16) << 8 | self.load(i + 1) as u16
+        } else {
+            self.load(i) as u16
+        }
+    }
+    fn skip(&mut self) {
+        // skips the next instruction, which is 4 bytes long if it is XO-CHIP's F000 NNNN
+        // (running off the end of memory is left for the next fetch to report)
+        let next = self.read_word(self.pc as usize).unwrap_or(0);
+        let size = Instruction::decode(next, 0).map_or(2, |instr| instr.size());
+        self.pc = self.pc.wrapping_add(size as u16);
+    }
+    fn gfx_width(&self) -> usize { if self.hires { HIRES_GFX_COLS } else { GFX_COLS } }
+    fn gfx_height(&self) -> usize { if self.hires { HIRES_GFX_ROWS } else { GFX_ROWS } }
     //
     // INSTRUCTIONS
     //
     // split into separate functions in attempt to improve readability of
-    // the decoding of opcodes
+    // the decoding of opcodes. Operands come from the decoded Instruction, the same
+    // one the disassembler and assembler use.
     //
 
-    fn clear_screen(&mut self) { self.gfx = [false; GFX_ROWS * GFX_COLS]; } //0x00E0
-    fn subroutine_return(&mut self) {
+    fn clear_screen(&mut self) {
+        //0x00E0, only clears the selected planes
+        let planes = self.planes;
+        self.gfx.iter_mut().for_each(|px| *px &= !planes);
+        self.redraw = true;
+    }
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        // moves the selected planes by (dx, dy), filling in with unlit pixels
+        let (cols, rows) = (self.gfx_width() as isize, self.gfx_height() as isize);
+        let planes = self.planes;
+        let old = self.gfx.clone();
+        for y in 0..rows {
+            for x in 0..cols {
+                let (src_x, src_y) = (x - dx, y - dy);
+                let src = if src_x >= 0 && src_x < cols && src_y >= 0 && src_y < rows {
+                    old[coords_to_index(src_x as usize, src_y as usize, cols as usize)]
+                } else {
+                    0
+                };
+                let px = &mut self.gfx[coords_to_index(x as usize, y as usize, cols as usize)];
+                *px = (*px & !planes) | (src & planes);
+            }
+        }
+        self.redraw = true;
+    }
+    fn scroll_down(&mut self, n: u8) { self.scroll(0, n as isize); } //0x00CN
+    fn scroll_up(&mut self, n: u8) { self.scroll(0, -(n as isize)); } //0x00DN
+    fn scroll_right(&mut self) { self.scroll(4, 0); } //0x00FB
+    fn scroll_left(&mut self) { self.scroll(-4, 0); } //0x00FC
+    fn exit(&mut self) {
+        //0x00FD
+        self.exited = true;
+        self.pc = self.pc.wrapping_sub(2); // stay put, in case the frontend keeps cycling anyway
+    }
+    fn set_hires(&mut self, hires: bool) {
+        //0x00FE (lores), 0x00FF (hires)
+        self.hires = hires;
+        self.gfx = vec![0; self.gfx_width() * self.gfx_height()]; // all planes
+        self.redraw = true;
+    }
+    fn subroutine_return(&mut self) -> Result<(), CpuErrorKind> {
         // 0x00EE
+        if self.sp == 0 {
+            return Err(CpuErrorKind::StackUnderflow);
+        }
         self.sp -= 1; //predecrement operator would be nice here...
         self.pc = self.stack[self.sp as usize];
+        Ok(())
     }
-    fn jump(&mut self) { self.pc = self.lower_12_val(); } // 0x1NNN
-    fn subroutine_call(&mut self) {
+    fn jump(&mut self, nnn: u16) { self.pc = nnn; } // 0x1NNN
+    fn subroutine_call(&mut self, nnn: u16) -> Result<(), CpuErrorKind> {
         // 0x2NNN
+        if self.sp as usize >= self.stack.len() {
+            return Err(CpuErrorKind::StackOverflow);
+        }
         self.stack[self.sp as usize] = self.pc;
-        self.pc = self.opcode & 0xFFF;
+        self.pc = nnn;
         self.sp += 1;
+        Ok(())
     }
-    fn skip_if(&mut self) {
+    fn skip_if(&mut self, x: u8, nn: u8) {
         // 0x3XNN
-        if *self.nibble2_reg() == self.lower_8_val() {
-            self.pc += 2;
+        if *self.reg(x) == nn {
+            self.skip();
         }
     }
-    fn skip_if_not(&mut self) {
+    fn skip_if_not(&mut self, x: u8, nn: u8) {
         // 0x4XNN
-        if *self.nibble2_reg() != self.lower_8_val() {
-            self.pc += 2;
+        if *self.reg(x) != nn {
+            self.skip();
         }
     }
-    fn skip_if_xy_eq(&mut self) {
+    fn skip_if_xy_eq(&mut self, x: u8, y: u8) {
         // 0x5XY0
-        if *self.nibble2_reg() == *self.nibble3_reg() {
-            self.pc += 2;
+        if *self.reg(x) == *self.reg(y) {
+            self.skip();
         }
     }
-    fn set_immediate(&mut self) { *self.nibble2_reg() = self.lower_8_val(); } //0x6XNN
-    fn add_immediate(&mut self) {
+    fn set_immediate(&mut self, x: u8, nn: u8) { *self.reg(x) = nn; } //0x6XNN
+    fn add_immediate(&mut self, x: u8, nn: u8) {
         //0x7XNN
-        *self.nibble2_reg() = self.nibble2_reg().wrapping_add(self.lower_8_val());
+        *self.reg(x) = self.reg(x).wrapping_add(nn);
+    }
+    fn set(&mut self, x: u8, y: u8) { *self.reg(x) = *self.reg(y); } //0x8XY0
+    fn or(&mut self, x: u8, y: u8) {
+        //0x8XY1
+        *self.reg(x) |= *self.reg(y);
+        self.reset_vf();
     }
-    fn set(&mut self) { *self.nibble2_reg() = *self.nibble3_reg(); } //0x8XY0
-    fn or(&mut self) { *self.nibble2_reg() |= *self.nibble3_reg(); } //0x8XY1
-    fn and(&mut self) { *self.nibble2_reg() &= *self.nibble3_reg(); } //0x8XY2
-    fn xor(&mut self) { *self.nibble2_reg() ^= *self.nibble3_reg(); } //0x8XY3
-    fn add(&mut self) {
+    fn and(&mut self, x: u8, y: u8) {
+        //0x8XY2
+        *self.reg(x) &= *self.reg(y);
+        self.reset_vf();
+    }
+    fn xor(&mut self, x: u8, y: u8) {
+        //0x8XY3
+        *self.reg(x) ^= *self.reg(y);
+        self.reset_vf();
+    }
+    fn add(&mut self, x: u8, y: u8) {
         //0x8XY4
-        let (val, overflow) = self.nibble2_reg().overflowing_add(*self.nibble3_reg());
-        self.regs[15] = overflow as u8;
-        *self.nibble2_reg() = val;
+        let (val, overflow) = self.reg(x).overflowing_add(*self.reg(y));
+        *self.reg(x) = val;
+        self.regs[15] = overflow as u8; // set last so VF as X keeps the flag
     }
-    fn sub_xy(&mut self) {
+    fn sub_xy(&mut self, x: u8, y: u8) {
         //0x8XY5
-        let (val, overflow) = self.nibble2_reg().overflowing_sub(*self.nibble3_reg());
+        let (val, overflow) = self.reg(x).overflowing_sub(*self.reg(y));
+        *self.reg(x) = val;
         self.regs[15] = !overflow as u8;
-        *self.nibble2_reg() = val;
     }
-    fn right_shift(&mut self) {
+    fn right_shift(&mut self, x: u8, y: u8) {
         //0x8XY6
-        self.regs[15] = *self.nibble2_reg() & 0x1;
+        let val = self.shift_source(x, y);
         //TODO: confirm if logical or arithmetic shift... found conflicting info
-        *self.nibble2_reg() >>= 1;
+        *self.reg(x) = val >> 1;
+        self.regs[15] = val & 0x1; // set last so VF as X keeps the flag
     }
-    fn sub_yx(&mut self) {
+    fn sub_yx(&mut self, x: u8, y: u8) {
         //0x8XY7
-        let (val, overflow) = self.nibble3_reg().overflowing_sub(*self.nibble2_reg());
+        let (val, overflow) = self.reg(y).overflowing_sub(*self.reg(x));
+        *self.reg(x) = val;
         self.regs[15] = !overflow as u8;
-        *self.nibble2_reg() = val;
     }
-    fn left_shift(&mut self) {
+    fn left_shift(&mut self, x: u8, y: u8) {
         //0x8XYE
-        self.regs[15] = *self.nibble2_reg() >> 7; //only first bit
-        *self.nibble2_reg() <<= 1;
+        let val = self.shift_source(x, y);
+        *self.reg(x) = val << 1;
+        self.regs[15] = val >> 7; //only first bit
     }
-    fn skip_if_xy_neq(&mut self) {
+    fn skip_if_xy_neq(&mut self, x: u8, y: u8) {
         //0x9XY0
-        if *self.nibble2_reg() != *self.nibble3_reg() {
-            self.pc += 2;
+        if *self.reg(x) != *self.reg(y) {
+            self.skip();
+        }
+    }
+    fn set_i_immediate(&mut self, nnn: u16) { self.i = nnn; } //ANNN
+    fn set_i_long(&mut self, nnnn: u16) -> Result<(), CpuErrorKind> {
+        //0xF000 NNNN, the address word has to be in memory even though it was decoded already
+        self.mem_range(self.pc as usize, 2)?;
+        self.i = nnnn;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+    fn reg_range(x: u8, y: u8) -> std::ops::RangeInclusive<usize> { x.min(y) as usize..=x.max(y) as usize }
+    fn reg_range_dump(&mut self, x: u8, y: u8) -> Result<(), CpuErrorKind> {
+        //0x5XY2, stores VX..VY (in that order, even if X > Y) at I, I is not changed
+        let regs = CPU::reg_range(x, y);
+        let dest = self.mem_range(self.i as usize, regs.clone().count())?;
+        let (x, y) = (x as usize, y as usize);
+        for (offset, reg_num) in regs.enumerate() {
+            let reg = if x <= y { reg_num } else { x + y - reg_num };
+            self.store(dest.start + offset, self.regs[reg]);
         }
+        Ok(())
     }
-    fn set_i_immediate(&mut self) { self.i = self.lower_12_val(); } //ANNN
-    fn jump_offset(&mut self) { self.pc = self.lower_12_val() + self.regs[0] as u16 } //0xBNNN
-    fn set_rand(&mut self) {
+    fn reg_range_load(&mut self, x: u8, y: u8) -> Result<(), CpuErrorKind> {
+        //0x5XY3
+        let regs = CPU::reg_range(x, y);
+        let src = self.mem_range(self.i as usize, regs.clone().count())?;
+        let (x, y) = (x as usize, y as usize);
+        for (offset, reg_num) in regs.enumerate() {
+            let reg = if x <= y { reg_num } else { x + y - reg_num };
+            self.regs[reg] = self.load(src.start + offset);
+        }
+        Ok(())
+    }
+    fn select_planes(&mut self, n: u8) { self.planes = n & PLANE_MASK; } //0xFN01
+    fn jump_offset(&mut self, nnn: u16) {
+        //0xBNNN, or 0xBXNN with quirks.jump_uses_vx
+        let offset = if self.quirks.jump_uses_vx {
+            *self.reg((nnn >> 8) as u8)
+        } else {
+            self.regs[0]
+        };
+        self.pc = nnn + offset as u16;
+    }
+    fn set_rand(&mut self, x: u8, nn: u8) {
         //0xCNNN
-        let mut rng = rand::thread_rng();
-        let rng_val: u8 = rng.gen();
-        *self.nibble2_reg() = rng_val & self.lower_8_val();
+        // xorshift64*, so a run can be reproduced from its seed
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        let rng_val = (self.rng.wrapping_mul(0x2545F4914F6CDD1D) >> 56) as u8;
+        *self.reg(x) = rng_val & nn;
     }
 
-    fn draw_sprite(&mut self) {
-        //std::thread::sleep(Duration::from_millis(1000));
+    fn draw_sprite(&mut self, x: u8, y: u8, n: u8) -> Result<(), CpuErrorKind> {
         // opcode = DXYN
         // Draws a sprite at coordinate (VX, VY) that has a width of 8 pixels and a height of N pixels.
         // Each row of 8 pixels is read as bit-coded starting from memory location I; I value doesn’t
         // change after the execution of this instruction. As described above, VF is set to 1 if any
         // pixels are flipped from set to unset when the sprite is drawn, and to 0 if that doesn’t happen.
-        // pixels wrap around.
-        let vx: u8 = *self.nibble2_reg() % GFX_COLS as u8;
-        let vy: u8 = *self.nibble3_reg() % GFX_ROWS as u8;
-        let height: u8 = self.lower_4_val();
-        let mut ret = false;
-        let mut mem_i = self.i as usize; // dont modify i
-        for row in vy..vy + height {
-            let wrapped_y = row % GFX_ROWS as u8;
-            let sprite_row: [bool; 8] = self.fetch_sprite_row(mem_i);
-            mem_i += 1; //prep for next row
-            let draw_row: [bool; 8] = [
-                sprite_row[0] ^ self.gfx[coords_to_index(vx, wrapped_y)],
-                sprite_row[1] ^ self.gfx[coords_to_index((vx + 1) % GFX_COLS as u8, wrapped_y)],
-                sprite_row[2] ^ self.gfx[coords_to_index((vx + 2) % GFX_COLS as u8, wrapped_y)],
-                sprite_row[3] ^ self.gfx[coords_to_index((vx + 3) % GFX_COLS as u8, wrapped_y)],
-                sprite_row[4] ^ self.gfx[coords_to_index((vx + 4) % GFX_COLS as u8, wrapped_y)],
-                sprite_row[5] ^ self.gfx[coords_to_index((vx + 5) % GFX_COLS as u8, wrapped_y)],
-                sprite_row[6] ^ self.gfx[coords_to_index((vx + 6) % GFX_COLS as u8, wrapped_y)],
-                sprite_row[7] ^ self.gfx[coords_to_index((vx + 7) % GFX_COLS as u8, wrapped_y)],
-            ];
-            if !ret {
-                for i in 0..draw_row.len() {
-                    if !draw_row[i] && sprite_row[i] {
-                        ret = true;
+        // The starting coordinate always wraps; the rest of the sprite either wraps or is clipped
+        // at the edges depending on quirks.clip_sprites.
+        // DXY0 draws a 16x16 sprite instead (SCHIP). With quirks.collision_rows, in hires mode VF is
+        // set to the number of rows that collided plus the rows clipped off the bottom, like SCHIP 1.1
+        // does, rather than just 0 or 1.
+        if self.quirks.display_wait {
+            if !self.vblank {
+                self.pc = self.pc.wrapping_sub(2); // block until the next timer tick, same trick as get_key
+                return Ok(());
+            }
+            self.vblank = false;
+        }
+        let (cols, rows) = (self.gfx_width(), self.gfx_height());
+        let vx = *self.reg(x) as usize % cols;
+        let vy = *self.reg(y) as usize % rows;
+        let (width, height) = match n {
+            0 => (16, 16),
+            n => (8, n as usize),
+        };
+        let sprite_len = height * width / 8;
+        let num_planes = self.planes.count_ones() as usize;
+        self.mem_range(self.i as usize, sprite_len * num_planes)?; // so nothing is drawn on error
+        let mut collided_rows: u8 = 0;
+        let mut clipped_rows: u8 = 0;
+        let mut mem_i = self.i as usize;
+        for plane in 0..NUM_PLANES {
+            let plane_bit = 1 << plane;
+            if self.planes & plane_bit == 0 {
+                continue;
+            }
+            for row in 0..height {
+                let y = vy + row;
+                if y >= rows && self.quirks.clip_sprites {
+                    clipped_rows += (height - row) as u8;
+                    break;
+                }
+                let sprite_row = self.fetch_sprite_row(mem_i + row * width / 8, width);
+                let mut collision = false;
+                for col in 0..width {
+                    let x = vx + col;
+                    if x >= cols && self.quirks.clip_sprites {
                         break;
                     }
+                    if sprite_row & (1 << (width - 1 - col)) == 0 {
+                        continue;
+                    }
+                    let gfx_i = coords_to_index(x % cols, y % rows, cols);
+                    collision |= self.gfx[gfx_i] & plane_bit != 0;
+                    self.gfx[gfx_i] ^= plane_bit;
                 }
+                collided_rows += collision as u8;
             }
-            // set the pixels.
-            // would look cleaner if gfx implemented as circular array,
-            // eg    self.gfx[gfx_i..gfx_i + 8].copy_from_slice(&draw_row);
-            // until then, using multiple single assignments instead of
-            // branching in the hopes that the compiler will optimize it better
-            // than when trying to deal with the branch.
-            // ...maybe will actually get around to testing that...
-            for col in 0..8 {
-                let wrapped_i = coords_to_index((vx + col) % GFX_COLS as u8, wrapped_y);
-                self.gfx[wrapped_i] = draw_row[col as usize];
-            }
+            mem_i += sprite_len;
         }
-        self.regs[15] = ret as u8;
+        self.regs[15] = if self.hires && self.quirks.collision_rows {
+            collided_rows + clipped_rows
+        } else {
+            (collided_rows > 0) as u8
+        };
+        self.redraw = true;
+        Ok(())
     }
-    fn skip_if_key(&mut self) {
+    fn skip_if_key(&mut self, x: u8) {
         //0xEX9E
-        if self.keys[*self.nibble2_reg() as usize] {
-            self.pc += 2;
+        if self.keys[(*self.reg(x) & 0xF) as usize] {
+            self.skip();
         }
     }
-    fn skip_if_not_key(&mut self) {
+    fn skip_if_not_key(&mut self, x: u8) {
         //0xEXA1
-        if !self.keys[*self.nibble2_reg() as usize] {
-            self.pc += 2;
-        }
-    }
-    fn get_delay(&mut self) { *self.nibble2_reg() = self.delay_timer; } //FX07
-    fn get_key(&mut self) {
-        //FX0A
-        self.pc -= 2; //jump back to this same instruction as (poor) way of blocking
-        if self.ignore_keypress {
-            return;
-        }
-        for i in 0..self.keys.len() {
-            if self.keys[i] {
-                self.pc += 2; //jump to next instruction
-                *self.nibble2_reg() = i as u8;
-                self.ignore_keypress = true;
-                break;
+        if !self.keys[(*self.reg(x) & 0xF) as usize] {
+            self.skip();
+        }
+    }
+    fn get_delay(&mut self, x: u8) { *self.reg(x) = self.delay_timer; } //FX07
+    fn get_key(&mut self, x: u8) {
+        //FX0A, keys move key_wait along in set_key
+        match self.key_wait {
+            KeyWait::Released(key) => {
+                *self.reg(x) = key;
+                self.key_wait = KeyWait::Idle;
             }
+            KeyWait::Idle => {
+                self.key_wait = KeyWait::Waiting;
+                self.pc = self.pc.wrapping_sub(2); // runs again until the key is released
+            }
+            KeyWait::Waiting | KeyWait::Pressed(_) => self.pc = self.pc.wrapping_sub(2),
+        }
+    }
+    fn set_delay(&mut self, x: u8) { self.delay_timer = *self.reg(x); } //0xFX15
+    fn set_sound(&mut self, x: u8) { self.sound_timer = *self.reg(x); } //0xFX18
+    fn load_audio_pattern(&mut self) -> Result<(), CpuErrorKind> {
+        //0xF002
+        let src = self.mem_range(self.i as usize, AUDIO_PATTERN_SIZE)?;
+        for (offset, addr) in src.enumerate() {
+            self.audio_pattern[offset] = self.load(addr);
         }
+        Ok(())
     }
-    fn set_delay(&mut self) { self.delay_timer = *self.nibble2_reg(); } //0xFX15
-    fn set_sound(&mut self) { self.sound_timer = *self.nibble2_reg(); } //0xFX18
-    fn add_i(&mut self) { self.i = self.i.wrapping_add(*self.nibble2_reg() as u16); } //0xFX1E
-    fn get_char(&mut self) {
+    fn set_pitch(&mut self, x: u8) { self.pitch = *self.reg(x); } //0xFX3A
+    fn add_i(&mut self, x: u8) { self.i = self.i.wrapping_add(*self.reg(x) as u16); } //0xFX1E
+    fn get_char(&mut self, x: u8) {
         //0xFX29
-        self.i = FONT_LOC as u16 + (*self.nibble2_reg() * FONT_NUM_ROWS as u8) as u16;
+        let digit = (*self.reg(x) & 0xF) as u16;
+        self.i = FONT_LOC as u16 + digit * FONT_NUM_ROWS as u16;
     }
-    fn store_bcd(&mut self) {
+    fn get_big_char(&mut self, x: u8) {
+        //0xFX30
+        let digit = (*self.reg(x) & 0xF) as u16;
+        self.i = BIG_FONT_LOC as u16 + digit * BIG_FONT_NUM_ROWS as u16;
+    }
+    fn store_bcd(&mut self, x: u8) -> Result<(), CpuErrorKind> {
         //0xFX33
-        let val: u8 = *self.nibble2_reg();
-        self.mem[self.i as usize] = val / 100;
-        self.mem[self.i as usize + 1] = (val % 100) / 10;
-        self.mem[self.i as usize + 2] = val % 10;
+        let val: u8 = *self.reg(x);
+        let dest = self.mem_range(self.i as usize, 3)?;
+        for (addr, digit) in dest.zip([val / 100, (val % 100) / 10, val % 10].iter()) {
+            self.store(addr, *digit);
+        }
+        Ok(())
     }
-    fn reg_dump(&mut self) {
+    fn reg_dump(&mut self, x: u8) -> Result<(), CpuErrorKind> {
         //0xFX55
-        for reg_num in 0..=self.nibble2_usize() {
-            self.mem[self.i as usize + reg_num] = self.regs[reg_num];
+        let last = x as usize;
+        let dest = self.mem_range(self.i as usize, last + 1)?;
+        for (reg_num, addr) in dest.enumerate() {
+            self.store(addr, self.regs[reg_num]);
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(last as u16 + 1);
         }
+        Ok(())
     }
-    fn reg_load(&mut self) {
+    fn reg_load(&mut self, x: u8) -> Result<(), CpuErrorKind> {
         //0xFX65
-        for reg_num in 0..=self.nibble2_usize() {
-            self.regs[reg_num] = self.mem[self.i as usize + reg_num];
-        }
-    }
-
-    pub fn execute(&mut self) {
-        match self.opcode {
-            0x00E0 => self.clear_screen(),
-            0x00EE => self.subroutine_return(),
-            0x0000..=0x0FFF => self.jump(), //temp. good enough for now?
-            0x1000..=0x1FFF => self.jump(),
-            0x2000..=0x2FFF => self.subroutine_call(),
-            0x3000..=0x3FFF => self.skip_if(),
-            0x4000..=0x4FFF => self.skip_if_not(),
-            0x5000..=0x5FF0 => self.skip_if_xy_eq(),
-            0x6000..=0x6FFF => self.set_immediate(),
-            0x7000..=0x7FFF => self.add_immediate(),
-            0x8000..=0x8FFF => {
-                match self.lower_4_val() {
-                    0x0 => self.set(),
-                    0x1 => self.or(),
-                    0x2 => self.and(),
-                    0x3 => self.xor(),
-                    0x4 => self.add(),
-                    0x5 => self.sub_xy(),
-                    0x6 => self.right_shift(),
-                    0x7 => self.sub_yx(),
-                    0xE => self.left_shift(),
-                    _ => panic!("unknown opcode!"),
-                }
-            }
-            0x9000..=0x9FF0 => self.skip_if_xy_neq(),
-            0xA000..=0xAFFF => self.set_i_immediate(),
-            0xB000..=0xBFFF => self.jump_offset(),
-            0xC000..=0xCFFF => self.set_rand(),
-            0xD000..=0xDFFF => self.draw_sprite(),
-            0xE000..=0xEFFF => {
-                match self.lower_8_val() {
-                    0x9E => self.skip_if_key(),
-                    0xA1 => self.skip_if_not_key(),
-                    _ => panic!("unknown opcode!"),
-                }
-            }
-            0xF000..=0xFFFF => {
-                match self.lower_8_val() {
-                    0x07 => self.get_delay(), 
-                    0x0A => self.get_key(), 
-                    0x15 => self.set_delay(), 
-                    0x18 => self.set_sound(), 
-                    0x1E => self.add_i(), 
-                    0x29 => self.get_char(), 
-                    0x33 => self.store_bcd(), 
-                    0x55 => self.reg_dump(), 
-                    0x65 => self.reg_load(), 
-                    _ => panic!("unknown opcode!"),
-                }
-            }
-            _ => panic!("unknown opcode!"),
+        let last = x as usize;
+        let src = self.mem_range(self.i as usize, last + 1)?;
+        for (reg_num, addr) in src.enumerate() {
+            self.regs[reg_num] = self.load(addr);
+        }
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(last as u16 + 1);
         }
+        Ok(())
+    }
+    fn save_flags(&mut self, x: u8) {
+        //0xFX75
+        let last = (x as usize).min(NUM_RPL_FLAGS - 1);
+        self.rpl[0..=last].copy_from_slice(&self.regs[0..=last]);
+    }
+    fn load_flags(&mut self, x: u8) {
+        //0xFX85
+        let last = (x as usize).min(NUM_RPL_FLAGS - 1);
+        self.regs[0..=last].copy_from_slice(&self.rpl[0..=last]);
+    }
+
+    pub fn execute(&mut self) -> Result<(), CpuErrorKind> {
+        // F000's address word follows it, set_i_long reports it if that is past the end
+        use Instruction::*;
+        let next = self.read_word(self.pc as usize).unwrap_or(0);
+        let instr = Instruction::decode(self.opcode, next).ok_or(CpuErrorKind::UnknownOpcode)?;
+        let (pc, vx) = (self.pc, self.regs[(self.opcode >> 8 & 0xF) as usize]);
+        match instr {
+            ClearScreen => self.clear_screen(),
+            Return => self.subroutine_return()?,
+            ScrollDown(n) => self.scroll_down(n),
+            ScrollUp(n) => self.scroll_up(n),
+            ScrollRight => self.scroll_right(),
+            ScrollLeft => self.scroll_left(),
+            Exit => self.exit(),
+            Lores => self.set_hires(false),
+            Hires => self.set_hires(true),
+            Sys(nnn) => self.jump(nnn), //temp. good enough for now?
+            Jump(nnn) => self.jump(nnn),
+            Call(nnn) => self.subroutine_call(nnn)?,
+            SkipEqImm(x, nn) => self.skip_if(x, nn),
+            SkipNeImm(x, nn) => self.skip_if_not(x, nn),
+            SkipEq(x, y) => self.skip_if_xy_eq(x, y),
+            SaveRange(x, y) => self.reg_range_dump(x, y)?,
+            LoadRange(x, y) => self.reg_range_load(x, y)?,
+            SetImm(x, nn) => self.set_immediate(x, nn),
+            AddImm(x, nn) => self.add_immediate(x, nn),
+            Set(x, y) => self.set(x, y),
+            Or(x, y) => self.or(x, y),
+            And(x, y) => self.and(x, y),
+            Xor(x, y) => self.xor(x, y),
+            Add(x, y) => self.add(x, y),
+            Sub(x, y) => self.sub_xy(x, y),
+            ShiftRight(x, y) => self.right_shift(x, y),
+            SubReverse(x, y) => self.sub_yx(x, y),
+            ShiftLeft(x, y) => self.left_shift(x, y),
+            SkipNe(x, y) => self.skip_if_xy_neq(x, y),
+            SetI(nnn) => self.set_i_immediate(nnn),
+            JumpOffset(nnn) => self.jump_offset(nnn),
+            Random(x, nn) => self.set_rand(x, nn),
+            Draw(x, y, n) => self.draw_sprite(x, y, n)?,
+            SkipKey(x) => self.skip_if_key(x),
+            SkipNotKey(x) => self.skip_if_not_key(x),
+            SetILong(nnnn) => self.set_i_long(nnnn)?,
+            SelectPlanes(n) => self.select_planes(n),
+            LoadAudio => self.load_audio_pattern()?,
+            GetDelay(x) => self.get_delay(x),
+            WaitKey(x) => self.get_key(x),
+            SetDelay(x) => self.set_delay(x),
+            SetSound(x) => self.set_sound(x),
+            AddI(x) => self.add_i(x),
+            Font(x) => self.get_char(x),
+            BigFont(x) => self.get_big_char(x),
+            Bcd(x) => self.store_bcd(x)?,
+            SetPitch(x) => self.set_pitch(x),
+            Store(x) => self.reg_dump(x)?,
+            Load(x) => self.reg_load(x)?,
+            SaveFlags(x) => self.save_flags(x),
+            LoadFlags(x) => self.load_flags(x),
+        }
+        // skips move pc forward, blocking instructions move it back to themselves
+        self.cycles = timing::vip_cycles(instr, vx, self.pc > pc, self.pc < pc);
+        Ok(())
     }
 
-    pub fn just_drew(&mut self) -> bool { (self.opcode & 0xF000) >> 12 == 0xD }
     pub fn should_play_sound(&self) -> bool { self.sound_timer > 0 }
-    // temp until better method implemented
-    pub fn set_key(&mut self, key: usize, state: bool) { self.keys[key] = state; }
+    pub fn get_audio_pattern(&self) -> [u8; AUDIO_PATTERN_SIZE] { self.audio_pattern }
+    pub fn get_pitch(&self) -> u8 { self.pitch }
+    // every frontend's key presses and releases come through here (or set_key_mask)
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        self.key_wait = match self.key_wait {
+            KeyWait::Waiting if pressed && !self.keys[key] => KeyWait::Pressed(key as u8),
+            KeyWait::Pressed(held) if !pressed && held as usize == key => KeyWait::Released(held),
+            key_wait => key_wait,
+        };
+        self.keys[key] = pressed;
+    }
+    // true while FX0A waits, nothing but the timers changes until a key goes down and up
+    pub fn waiting_for_key(&self) -> bool {
+        matches!(self.key_wait, KeyWait::Waiting | KeyWait::Pressed(_))
+    }
+    // bit n is key n
+    pub fn get_key_mask(&self) -> u16 {
+        self.keys.iter().enumerate().fold(0, |mask, (key, &pressed)| mask | (pressed as u16) << key)
+    }
+    pub fn set_key_mask(&mut self, mask: u16) {
+        for key in 0..self.keys.len() {
+            self.set_key(key, mask & 1 << key != 0);
+        }
+    }
+    // CXNN draws from this, the same seed gives the same sequence
+    pub fn seed_rng(&mut self, seed: u64) {
+        // xorshift gets stuck on 0
+        self.rng = if seed == 0 { 0x9E3779B97F4A7C15 } else { seed };
+    }
+    pub fn get_quirks(&self) -> Quirks { self.quirks }
+    // VIP machine cycles the last instruction took, see timing.rs
+    pub fn get_cycles(&self) -> u32 { self.cycles }
     pub fn update_timers(&mut self) {
+        self.vblank = true;
         self.delay_timer = self.delay_timer.saturating_sub(1);
         self.sound_timer = self.sound_timer.saturating_sub(1);
     }
 
-    pub fn perform_cycle(&mut self) {
-        self.fetch();
-        self.execute(); //also decodes
+    pub fn perform_cycle(&mut self) -> Result<StepOutcome, CpuError> {
+        let pc = self.pc;
+        self.op_pc = pc;
+        self.opcode = 0; // in case fetching fails
+        let result = self.fetch().and_then(|_| self.execute()); //execute also decodes
+        if let Err(kind) = result {
+            return Err(CpuError {
+                pc,
+                opcode: self.opcode,
+                kind,
+            });
+        }
+        Ok(if self.exited {
+            StepOutcome::Exited
+        } else if std::mem::take(&mut self.redraw) {
+            StepOutcome::Drew
+        } else {
+            StepOutcome::Executed
+        })
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) { self.load_rom_at(rom, ROM_START) }
+
+    // loads the rom somewhere else and starts running it there, e.g. 0x600 for ETI-660
+    // programs. Anything past the end of memory is dropped, see rom::read_rom for size checks.
+    pub fn load_rom_at(&mut self, rom: &[u8], addr: usize) {
+        let len = rom.len().min(MEM_SIZE - addr);
+        self.mem[addr..(addr + len)].copy_from_slice(&rom[..len]);
+        self.pc = addr as u16;
+        self.op_pc = self.pc;
+        self.rom_hash = state::rom_hash(rom);
+        self.load_address = addr as u16;
+    }
+
+    pub fn get_rom_hash(&self) -> &RomHash { &self.rom_hash }
+    pub fn get_load_address(&self) -> usize { self.load_address as usize }
+
+    // everything needed to resume execution later, see state.rs for the header.
+    // Keys aren't saved, they belong to whoever is holding the keyboard; neither are
+    // the debugger's watchpoints.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut out = state::Writer::default();
+        out.bytes(state::MAGIC);
+        out.u16(state::VERSION);
+        out.bytes(&self.rom_hash);
+        out.quirks(&self.quirks);
+        out.u16(self.opcode);
+        out.u16(self.pc);
+        out.u16(self.op_pc);
+        out.u16(self.i);
+        out.u8(self.sp);
+        for &addr in &self.stack {
+            out.u16(addr);
+        }
+        out.bytes(&self.regs);
+        out.u8(self.delay_timer);
+        out.u8(self.sound_timer);
+        let (key_wait, key) = match self.key_wait {
+            KeyWait::Idle => (0, 0),
+            KeyWait::Waiting => (1, 0),
+            KeyWait::Pressed(key) => (2, key),
+            KeyWait::Released(key) => (3, key),
+        };
+        out.u8(key_wait);
+        out.u8(key);
+        out.bool(self.vblank);
+        out.bool(self.exited);
+        out.bool(self.hires);
+        out.u8(self.planes);
+        out.bytes(&self.gfx);
+        out.bytes(&self.rpl);
+        out.bytes(&self.audio_pattern);
+        out.u8(self.pitch);
+        out.u64(self.rng);
+        out.bytes(&self.mem);
+        out.buf
+    }
+
+    // restores a save_state, which must have been made with the currently loaded rom.
+    // On error the CPU is left untouched.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut input = state::Reader::new(data);
+        input.header(&self.rom_hash)?;
+        let mut cpu = CPU::new(input.quirks()?);
+        cpu.opcode = input.u16()?;
+        cpu.pc = input.u16()?;
+        cpu.op_pc = input.u16()?;
+        cpu.i = input.u16()?;
+        cpu.sp = input.u8()?;
+        if cpu.sp as usize > cpu.stack.len() {
+            return Err(StateError::Corrupt);
+        }
+        for addr in cpu.stack.iter_mut() {
+            *addr = input.u16()?;
+        }
+        cpu.regs = input.array()?;
+        cpu.delay_timer = input.u8()?;
+        cpu.sound_timer = input.u8()?;
+        let (key_wait, key) = (input.u8()?, input.u8()?);
+        if key as usize >= cpu.keys.len() {
+            return Err(StateError::Corrupt);
+        }
+        cpu.key_wait = match key_wait {
+            0 => KeyWait::Idle,
+            1 => KeyWait::Waiting,
+            // keys aren't part of the state, one let go of in the meantime counts as released
+            2 if self.keys[key as usize] => KeyWait::Pressed(key),
+            2 | 3 => KeyWait::Released(key),
+            _ => return Err(StateError::Corrupt),
+        };
+        cpu.vblank = input.bool()?;
+        cpu.exited = input.bool()?;
+        cpu.hires = input.bool()?;
+        cpu.planes = input.u8()?;
+        cpu.gfx = input.bytes(cpu.gfx_width() * cpu.gfx_height())?.to_vec();
+        // frontends index their palettes with these
+        if cpu.planes & !PLANE_MASK != 0 || cpu.gfx.iter().any(|px| px & !PLANE_MASK != 0) {
+            return Err(StateError::Corrupt);
+        }
+        cpu.rpl = input.array()?;
+        cpu.audio_pattern = input.array()?;
+        cpu.pitch = input.u8()?;
+        cpu.seed_rng(input.u64()?);
+        cpu.mem = input.array()?;
+
+        cpu.keys = self.keys;
+        cpu.watchpoints = std::mem::take(&mut self.watchpoints);
+        cpu.rom_hash = self.rom_hash;
+        cpu.load_address = self.load_address;
+        cpu.redraw = true;
+        *self = cpu;
+        Ok(())
+    }
+
+    pub fn get_gfx(&self) -> &[u8] { &self.gfx } // plane bitmask per pixel
+    pub fn get_pc(&self) -> u16 { self.pc }
+    pub fn get_i(&self) -> u16 { self.i }
+    pub fn get_sp(&self) -> u8 { self.sp }
+    pub fn get_regs(&self) -> &[u8; 16] { &self.regs }
+    pub fn get_stack(&self) -> &[u16] { &self.stack[0..self.sp as usize] }
+    pub fn get_timers(&self) -> (u8, u8) { (self.delay_timer, self.sound_timer) }
+    pub fn get_gfx_size(&self) -> (usize, usize) { (self.gfx_width(), self.gfx_height()) }
+    pub fn get_mem(&self) -> &[u8] { &self.mem }
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) { self.watchpoints.push(watchpoint); }
+    pub fn remove_watchpoints(&mut self, start: u16) { self.watchpoints.retain(|w| w.start != start); }
+    pub fn get_watchpoints(&self) -> &[Watchpoint] { &self.watchpoints }
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> { self.watch_hit.take() }
+
+    // human readable registers, timers and stack, for error reports and dumps
+    pub fn dump_registers(&self) -> String {
+        let mut out = format!("pc: {:#06X}  i: {:#06X}  sp: {}\n", self.pc, self.i, self.sp);
+        for (reg_num, val) in self.regs.iter().enumerate() {
+            let sep = if reg_num % 8 == 7 { "\n" } else { "  " };
+            out += &format!("V{:X}: {:02X}{}", reg_num, val, sep);
+        }
+        out += &format!("stack: {:04X?}\n", self.get_stack());
+        out += &format!("delay timer: {}  sound timer: {}\n", self.delay_timer, self.sound_timer);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // draws a digit on both planes and leaves some state behind
+    fn running_cpu() -> CPU {
+        let mut cpu = CPU::new(Quirks::xochip());
+        cpu.load_rom(&[0x60, 0x05, 0xF3, 0x01, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x08]);
+        for _ in 0..5 {
+            cpu.perform_cycle().unwrap();
+        }
+        cpu
+    }
+
+    // a cpu with rom loaded that has run steps instructions
+    fn run(quirks: Quirks, rom: &[u8], steps: usize) -> CPU {
+        let mut cpu = CPU::new(quirks);
+        cpu.load_rom(rom);
+        for _ in 0..steps {
+            cpu.perform_cycle().unwrap();
+        }
+        cpu
+    }
+
+    // offsets from the end of a state, everything after gfx has a fixed size
+    fn gfx_end(state: &[u8]) -> usize { state.len() - MEM_SIZE - 8 - 1 - AUDIO_PATTERN_SIZE - NUM_RPL_FLAGS }
+
+    #[test]
+    fn state_round_trip() {
+        let cpu = running_cpu();
+        let state = cpu.save_state();
+        let mut loaded = CPU::new(Quirks::default());
+        loaded.load_rom(&[0x60, 0x05, 0xF3, 0x01, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x08]);
+        loaded.load_state(&state).unwrap();
+        assert_eq!(loaded.save_state(), state);
+        assert_eq!(loaded.get_gfx(), cpu.get_gfx());
+        assert_eq!(loaded.get_quirks(), Quirks::xochip());
+    }
+
+    #[test]
+    fn state_keeps_the_load_address() {
+        let rom = [0x60, 0x05, 0x16, 0x00];
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom_at(&rom, 0x600);
+        cpu.perform_cycle().unwrap();
+        let state = cpu.save_state();
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.get_load_address(), 0x600);
+    }
+
+    #[test]
+    fn state_with_bad_pixels_or_planes_is_corrupt() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+        let gfx_end = gfx_end(&state);
+        let gfx_start = gfx_end - cpu.get_gfx().len();
+        assert_eq!(&state[gfx_start..gfx_end], cpu.get_gfx());
+
+        let mut bad_pixel = state.clone();
+        bad_pixel[gfx_start + 10] = 1 << NUM_PLANES;
+        assert_eq!(cpu.load_state(&bad_pixel), Err(StateError::Corrupt));
+        let mut bad_planes = state.clone();
+        bad_planes[gfx_start - 1] = 0xFF;
+        assert_eq!(cpu.load_state(&bad_planes), Err(StateError::Corrupt));
+        // left untouched
+        assert_eq!(cpu.save_state(), state);
+    }
+
+    // hires, then a 4 row sprite at (0, 0) twice and once at (0, 62), VF after each draw
+    fn hires_collisions(quirks: Quirks) -> [u8; 3] {
+        let rom = [
+            0x00, 0xFF, 0xA2, 0x10, 0x60, 0x00, 0x61, 0x00,
+            0xD0, 0x14, 0xD0, 0x14, 0x61, 0x3E, 0xD0, 0x14,
+            0xFF, 0xFF, 0xFF, 0xFF,
+        ];
+        let mut cpu = run(quirks, &rom, 4);
+        let mut vf = [0; 3];
+        for (i, steps) in [1, 1, 2].iter().enumerate() {
+            for _ in 0..*steps {
+                cpu.perform_cycle().unwrap();
+            }
+            vf[i] = cpu.get_regs()[15];
+        }
+        vf
+    }
+
+    #[test]
+    fn schip_counts_collided_and_clipped_rows_in_hires() {
+        // all 4 rows collide, then 2 of them are clipped off the bottom
+        assert_eq!(hires_collisions(Quirks::schip()), [0, 4, 2]);
+        let lores = run(Quirks::schip(), &[0xA0, 0x50, 0xD0, 0x05, 0xD0, 0x05], 3);
+        assert_eq!(lores.get_regs()[15], 1);
+    }
+
+    #[test]
+    fn xochip_collisions_are_0_or_1_in_hires() {
+        assert_eq!(hires_collisions(Quirks::xochip()), [0, 1, 0]);
+        let without_clipping = Quirks { clip_sprites: false, ..Quirks::schip() };
+        assert_eq!(hires_collisions(without_clipping), [0, 4, 0]);
+    }
+
+    fn pixel(cpu: &CPU, x: usize, y: usize) -> u8 { cpu.get_gfx()[coords_to_index(x, y, cpu.get_gfx_size().0)] }
+
+    #[test]
+    fn shift_quirk() {
+        let rom = [0x60, 0x03, 0x61, 0x06, 0x80, 0x16];
+        let vip = run(Quirks::cosmac_vip(), &rom, 3);
+        assert_eq!((vip.get_regs()[0], vip.get_regs()[15]), (3, 0)); // VY >> 1
+        let schip = run(Quirks::schip(), &rom, 3);
+        assert_eq!((schip.get_regs()[0], schip.get_regs()[15]), (1, 1)); // VX >> 1
+    }
+
+    #[test]
+    fn load_store_quirk() {
+        let rom = [0x60, 0x0A, 0x61, 0x0B, 0xA3, 0x00, 0xF1, 0x55, 0xA3, 0x00, 0xF1, 0x65];
+        let vip = run(Quirks::cosmac_vip(), &rom, 4);
+        assert_eq!((&vip.get_mem()[0x300..0x302], vip.get_i()), (&[0x0A, 0x0B][..], 0x302));
+        let schip = run(Quirks::schip(), &rom, 4);
+        assert_eq!(schip.get_i(), 0x300);
+        assert_eq!(run(Quirks::cosmac_vip(), &rom, 6).get_i(), 0x302);
+        assert_eq!(run(Quirks::schip(), &rom, 6).get_i(), 0x300);
+    }
+
+    #[test]
+    fn jump_quirk() {
+        let rom = [0x60, 0x10, 0x62, 0x20, 0xB2, 0x10];
+        assert_eq!(run(Quirks::cosmac_vip(), &rom, 3).get_pc(), 0x220); // 210 + V0
+        assert_eq!(run(Quirks::schip(), &rom, 3).get_pc(), 0x230); // 210 + V2
+    }
+
+    #[test]
+    fn vf_reset_quirk() {
+        for op in [0x11, 0x12, 0x13] {
+            let rom = [0x6F, 0x05, 0x80, op];
+            assert_eq!(run(Quirks::cosmac_vip(), &rom, 2).get_regs()[15], 0);
+            assert_eq!(run(Quirks::schip(), &rom, 2).get_regs()[15], 5);
+        }
+    }
+
+    #[test]
+    fn clip_quirk() {
+        // a row of 8 pixels from x = 60
+        let rom = [0x60, 0x3C, 0x61, 0x1F, 0xA2, 0x0A, 0xD0, 0x12, 0x00, 0x00, 0xFF, 0xFF];
+        let clipped = run(Quirks { display_wait: false, ..Quirks::cosmac_vip() }, &rom, 4);
+        assert_eq!((pixel(&clipped, 63, 31), pixel(&clipped, 0, 31), pixel(&clipped, 0, 0)), (1, 0, 0));
+        let wrapped = run(Quirks::xochip(), &rom, 4);
+        assert_eq!((pixel(&wrapped, 63, 31), pixel(&wrapped, 3, 31), pixel(&wrapped, 3, 0)), (1, 1, 1));
+        assert_eq!(pixel(&wrapped, 4, 31), 0);
     }
 
-    pub fn load_rom(&mut self, rom: &[u8; ROM_SIZE]) {
-        self.mem[ROM_START..(ROM_START + rom.len())].copy_from_slice(rom);
+    #[test]
+    fn display_wait_quirk() {
+        let rom = [0xA0, 0x50, 0xD0, 0x05];
+        let mut cpu = run(Quirks::cosmac_vip(), &rom, 2);
+        assert_eq!(cpu.get_pc(), 0x202); // waiting for the vertical blank
+        assert_eq!(pixel(&cpu, 0, 0), 0);
+        cpu.update_timers();
+        cpu.perform_cycle().unwrap();
+        assert_eq!((cpu.get_pc(), pixel(&cpu, 0, 0)), (0x204, 1));
+        assert_eq!(run(Quirks::schip(), &rom, 2).get_pc(), 0x204);
     }
 
-    pub fn get_gfx(&self) -> [bool; GFX_ROWS * GFX_COLS] { self.gfx }
+    #[test]
+    fn lores_and_hires() {
+        let mut cpu = run(Quirks::schip(), &[0x00, 0xFF, 0xA0, 0x50, 0xD0, 0x05, 0x00, 0xFE], 1);
+        assert_eq!(cpu.get_gfx_size(), (128, 64));
+        for _ in 0..2 {
+            cpu.perform_cycle().unwrap();
+        }
+        assert_eq!(pixel(&cpu, 0, 0), 1);
+        cpu.perform_cycle().unwrap();
+        assert_eq!(cpu.get_gfx_size(), (64, 32));
+        assert!(cpu.get_gfx().iter().all(|&px| px == 0)); // switching clears the screen
+    }
+
+    #[test]
+    fn big_sprites() {
+        let mut rom = vec![0x00, 0xFF, 0xA2, 0x08, 0x60, 0x02, 0xD0, 0x00];
+        rom.extend([0xFF; 32]);
+        let cpu = run(Quirks::schip(), &rom, 4);
+        assert_eq!((pixel(&cpu, 2, 2), pixel(&cpu, 17, 17), pixel(&cpu, 18, 2), pixel(&cpu, 2, 18)), (1, 1, 0, 0));
+        assert_eq!(cpu.get_gfx().iter().filter(|&&px| px != 0).count(), 16 * 16);
+    }
+
+    #[test]
+    fn scrolls() {
+        // a pixel at (8, 8), scrolled down 3, right 4 and left 4 twice
+        let rom = [
+            0x60, 0x08, 0xA2, 0x10, 0xD0, 0x01, 0x00, 0xC3, 0x00, 0xFB, 0x00, 0xFC, 0x00, 0xFC, 0x00, 0x00,
+            0x80,
+        ];
+        let mut cpu = run(Quirks::schip(), &rom, 3);
+        assert_eq!(pixel(&cpu, 8, 8), 1);
+        let expected = [(8, 11), (12, 11), (8, 11), (4, 11)];
+        for (x, y) in expected {
+            cpu.perform_cycle().unwrap();
+            assert_eq!(cpu.get_gfx().iter().position(|&px| px != 0), Some(coords_to_index(x, y, 64)));
+        }
+        // pixels scrolled off the edge are gone
+        let rom = [0x60, 0x08, 0xA2, 0x0A, 0xD0, 0x01, 0x00, 0xCF, 0x00, 0xCF, 0x80];
+        let mut cpu = run(Quirks::schip(), &rom, 4);
+        assert_eq!(pixel(&cpu, 8, 23), 1);
+        cpu.perform_cycle().unwrap();
+        assert!(cpu.get_gfx().iter().all(|&px| px == 0));
+    }
+
+    #[test]
+    fn big_font() {
+        let cpu = run(Quirks::schip(), &[0x60, 0x05, 0xF0, 0x30], 2);
+        assert_eq!(cpu.get_i() as usize, BIG_FONT_LOC + 5 * BIG_FONT_NUM_ROWS);
+    }
+
+    #[test]
+    fn rpl_flags() {
+        let rom = [0x60, 0x01, 0x61, 0x02, 0x62, 0x03, 0xF1, 0x75, 0x60, 0x00, 0x61, 0x00, 0x62, 0x00, 0xF2, 0x85];
+        let cpu = run(Quirks::schip(), &rom, 8);
+        assert_eq!(&cpu.get_regs()[0..3], &[1, 2, 0]); // only V0..V1 were saved
+    }
+
+    #[test]
+    fn register_ranges() {
+        let rom = [0x60, 0x11, 0x61, 0x22, 0x62, 0x33, 0xA3, 0x00, 0x50, 0x22, 0xA3, 0x10, 0x52, 0x02];
+        let cpu = run(Quirks::xochip(), &rom, 7);
+        assert_eq!(&cpu.get_mem()[0x300..0x303], &[0x11, 0x22, 0x33]);
+        assert_eq!(&cpu.get_mem()[0x310..0x313], &[0x33, 0x22, 0x11]); // VX first even if X > Y
+        assert_eq!(cpu.get_i(), 0x310); // unlike FX55
+        let rom = [0xA2, 0x08, 0x51, 0x33, 0xA2, 0x08, 0x53, 0x13, 0xAA, 0xBB, 0xCC];
+        let cpu = run(Quirks::xochip(), &rom, 2);
+        assert_eq!(&cpu.get_regs()[0..4], &[0, 0xAA, 0xBB, 0xCC]);
+        let cpu = run(Quirks::xochip(), &rom, 4);
+        assert_eq!(&cpu.get_regs()[1..4], &[0xCC, 0xBB, 0xAA]);
+    }
+
+    #[test]
+    fn long_i() {
+        let rom = [0xF0, 0x00, 0xFF, 0xF0, 0x60, 0x07, 0xF0, 0x55];
+        let cpu = run(Quirks::xochip(), &rom, 3);
+        assert_eq!((cpu.get_i(), cpu.get_pc()), (0xFFF1, 0x208));
+        assert_eq!(cpu.get_mem()[0xFFF0], 7); // the whole 64 KiB are memory
+        // skips step over both words
+        let cpu = run(Quirks::xochip(), &[0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x60, 0x01], 2);
+        assert_eq!((cpu.get_pc(), cpu.get_regs()[0]), (0x208, 1));
+    }
+
+    #[test]
+    fn planes() {
+        // plane 1's sprite row, then plane 2's
+        let rom = [0xF3, 0x01, 0xA2, 0x0C, 0xD0, 0x01, 0xF2, 0x01, 0xD0, 0x01, 0x00, 0xE0, 0xC0, 0x60];
+        let mut cpu = run(Quirks::xochip(), &rom, 3);
+        assert_eq!([pixel(&cpu, 0, 0), pixel(&cpu, 1, 0), pixel(&cpu, 2, 0), pixel(&cpu, 3, 0)], [1, 3, 2, 0]);
+        // only plane 2, with plane 1's row as its sprite
+        for _ in 0..2 {
+            cpu.perform_cycle().unwrap();
+        }
+        assert_eq!([pixel(&cpu, 0, 0), pixel(&cpu, 1, 0), pixel(&cpu, 2, 0)], [3, 1, 2]);
+        assert_eq!(cpu.get_regs()[15], 1);
+        // 00E0 leaves plane 1 alone
+        cpu.perform_cycle().unwrap();
+        assert_eq!([pixel(&cpu, 0, 0), pixel(&cpu, 1, 0), pixel(&cpu, 2, 0)], [1, 1, 0]);
+        // no planes draws nothing
+        let cpu = run(Quirks::xochip(), &[0xF0, 0x01, 0xA0, 0x50, 0xD0, 0x05], 3);
+        assert!(cpu.get_gfx().iter().all(|&px| px == 0));
+    }
+
+    #[test]
+    fn wait_for_key_press_and_release() {
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&[0xF0, 0x0A, 0x12, 0x02]);
+        cpu.set_key(3, true); // already down before FX0A, doesn't count
+        let step = |cpu: &mut CPU| {
+            cpu.perform_cycle().unwrap();
+            (cpu.get_pc(), cpu.waiting_for_key())
+        };
+        assert_eq!(step(&mut cpu), (0x200, true));
+        cpu.set_key(3, false);
+        assert_eq!(step(&mut cpu), (0x200, true));
+        cpu.set_key(5, true);
+        assert_eq!(step(&mut cpu), (0x200, true)); // still down
+        cpu.set_key(7, true);
+        cpu.set_key(7, false);
+        assert_eq!(step(&mut cpu), (0x200, true)); // a different key
+        cpu.set_key(5, false);
+        assert_eq!(step(&mut cpu), (0x202, false));
+        assert_eq!(cpu.get_regs()[0], 5);
+    }
 }
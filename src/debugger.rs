@@ -0,0 +1,561 @@
+// Interactive debugger: breakpoints on pc, memory watchpoints, conditional
+// breakpoints, stepping and state inspection.
+//
+// The emulator asks `should_break` before every instruction and stops running
+// the CPU (timers included) while paused; commands come in as text lines, from
+// a `Console` on stdin or anywhere else.
+
+use crate::cpu::{MemAccess, Watchpoint, CPU};
+use crate::disasm;
+
+use std::collections::BTreeSet;
+use std::io::{BufRead, Write};
+use std::sync::mpsc;
+
+const HELP: &str = "\
+commands (addresses and numbers are hex, an optional 0x prefix is allowed):
+  b, break ADDR      add a breakpoint
+  d, delete ADDR     remove a breakpoint
+  bl, breakpoints    list breakpoints
+  w, watch ADDR [END] [r|w|rw]
+                     break when memory in ADDR..=END is read and/or written (default rw)
+  unwatch ADDR       remove the watchpoints starting at ADDR
+  watches            list watchpoints
+  cond LHS OP RHS    break when the condition becomes true, e.g. cond V3 == 10, cond I > F00
+                     (operands: V0-VF, I, PC, SP, DT, ST or a number; OP: == != < <= > >=)
+  uncond N           remove condition number N
+  conds              list conditions
+  s, step            execute one instruction
+  n, next            step over subroutine calls
+  o, out             run until the current subroutine returns
+  u, until ADDR      run until pc reaches ADDR
+  c, continue        resume execution
+  p, pause           pause execution
+  regs               registers, timers and stack
+  i | sp | stack | timers
+  m, mem ADDR [LEN]  hex view of memory (LEN defaults to 0x40)";
+const DEFAULT_MEM_LEN: usize = 0x40;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Operand {
+    Reg(usize),
+    I,
+    Pc,
+    Sp,
+    DelayTimer,
+    SoundTimer,
+    Const(u16),
+}
+
+impl Operand {
+    fn parse(text: &str) -> Result<Operand, String> {
+        let upper = text.to_ascii_uppercase();
+        Ok(match upper.as_str() {
+            "I" => Operand::I,
+            "PC" => Operand::Pc,
+            "SP" => Operand::Sp,
+            "DT" => Operand::DelayTimer,
+            "ST" => Operand::SoundTimer,
+            _ if upper.len() == 2 && upper.starts_with('V') => {
+                Operand::Reg(parse_hex(Some(&upper[1..]))?)
+            }
+            _ => Operand::Const(parse_addr(Some(text))?),
+        })
+    }
+
+    fn eval(&self, cpu: &CPU) -> u16 {
+        match *self {
+            Operand::Reg(reg_num) => cpu.get_regs()[reg_num] as u16,
+            Operand::I => cpu.get_i(),
+            Operand::Pc => cpu.get_pc(),
+            Operand::Sp => cpu.get_sp() as u16,
+            Operand::DelayTimer => cpu.get_timers().0 as u16,
+            Operand::SoundTimer => cpu.get_timers().1 as u16,
+            Operand::Const(val) => val,
+        }
+    }
+}
+
+// longest operators first, so "<=" isn't taken for "<"
+const COMPARISONS: [&str; 6] = ["==", "!=", "<=", ">=", "<", ">"];
+
+struct Condition {
+    text: String,
+    lhs: Operand,
+    op: &'static str,
+    rhs: Operand,
+    was_true: bool, // only the transition from false to true breaks
+}
+
+impl Condition {
+    fn parse(text: &str) -> Result<Condition, String> {
+        for &op in COMPARISONS.iter() {
+            if let Some(pos) = text.find(op) {
+                return Ok(Condition {
+                    text: text.trim().to_string(),
+                    lhs: Operand::parse(text[..pos].trim())?,
+                    op,
+                    rhs: Operand::parse(text[pos + op.len()..].trim())?,
+                    was_true: false,
+                });
+            }
+        }
+        Err(format!("no comparison in condition: {}", text))
+    }
+
+    fn eval(&self, cpu: &CPU) -> bool {
+        let (lhs, rhs) = (self.lhs.eval(cpu), self.rhs.eval(cpu));
+        match self.op {
+            "==" => lhs == rhs,
+            "!=" => lhs != rhs,
+            "<=" => lhs <= rhs,
+            ">=" => lhs >= rhs,
+            "<" => lhs < rhs,
+            _ => lhs > rhs,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum RunMode {
+    Continue,
+    Step,
+    StepOver { pc: u16, sp: u8 }, // break once back at pc with the same stack depth
+    StepOut { sp: u8 },           // break once the stack is shallower than sp
+    RunTo(u16),
+}
+
+pub struct Debugger {
+    breakpoints: BTreeSet<u16>,
+    paused: bool,
+    mode: RunMode,
+    resumed: bool, // nothing executed since resuming, so don't break on the current pc again
+    conditions: Vec<Condition>,
+    reason: Option<String>, // why the last pause happened, if it wasn't a plain breakpoint/step
+}
+
+fn parse_hex(arg: Option<&str>) -> Result<usize, String> {
+    let arg = arg.ok_or("missing argument")?;
+    let digits = arg.trim_start_matches("0x").trim_start_matches("0X");
+    usize::from_str_radix(digits, 16).map_err(|_| format!("not a hex number: {}", arg))
+}
+
+pub fn parse_addr(arg: Option<&str>) -> Result<u16, String> {
+    let addr = parse_hex(arg)?;
+    if addr > 0xFFFF {
+        return Err(format!("address out of range: {:#X}", addr));
+    }
+    Ok(addr as u16)
+}
+
+pub fn hex_dump(mem: &[u8], start: usize, len: usize) -> String {
+    let end = start.saturating_add(len).min(mem.len());
+    let mut out = String::new();
+    for line_start in (start..end).step_by(16) {
+        let line = &mem[line_start..(line_start + 16).min(end)];
+        let bytes: Vec<String> = line.iter().map(|b| format!("{:02X}", b)).collect();
+        out += &format!("{:04X}: {}\n", line_start, bytes.join(" "));
+    }
+    out
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeSet::new(),
+            paused: false,
+            mode: RunMode::Continue,
+            resumed: false,
+            conditions: vec![],
+            reason: None,
+        }
+    }
+
+    pub fn is_paused(&self) -> bool { self.paused }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.mode = RunMode::Continue;
+    }
+
+    pub fn add_breakpoint(&mut self, addr: u16) { self.breakpoints.insert(addr); }
+
+    fn resume(&mut self, mode: RunMode) {
+        self.paused = false;
+        self.mode = mode;
+        self.resumed = true;
+    }
+
+    pub fn take_reason(&mut self) -> Option<String> { self.reason.take() }
+
+    // re-evaluates every condition, returning the first one that just became true
+    fn check_conditions(&mut self, cpu: &CPU) -> Option<String> {
+        let mut hit = None;
+        for cond in self.conditions.iter_mut() {
+            let is_true = cond.eval(cpu);
+            if is_true && !cond.was_true && hit.is_none() {
+                hit = Some(format!("condition {} is true", cond.text));
+            }
+            cond.was_true = is_true;
+        }
+        hit
+    }
+
+    // called before each instruction, returns true (and pauses) if execution should stop there
+    pub fn should_break(&mut self, cpu: &mut CPU) -> bool {
+        if self.paused {
+            return true;
+        }
+        let (pc, sp) = (cpu.get_pc(), cpu.get_sp());
+        let first = std::mem::take(&mut self.resumed);
+        // both are caused by the instruction that just ran, which was before the pause if first
+        let watch_hit = cpu.take_watch_hit().map(|hit| {
+            let access = match hit.access {
+                MemAccess::Read => "read from",
+                MemAccess::Write => "write to",
+            };
+            format!("watchpoint: {} {:#06X} by {:#06X}", access, hit.addr, hit.pc)
+        });
+        let cond_hit = self.check_conditions(cpu); // always, to keep track of transitions
+        let reason = watch_hit.or(cond_hit);
+        if reason.is_some() && !first {
+            self.pause();
+            self.reason = reason;
+            return true;
+        }
+        let hit = match self.mode {
+            RunMode::Continue => false,
+            RunMode::Step => !first,
+            RunMode::StepOver { pc: ret_pc, sp: ret_sp } => pc == ret_pc && sp == ret_sp,
+            RunMode::StepOut { sp: out_sp } => sp < out_sp,
+            RunMode::RunTo(addr) => pc == addr && !first,
+        };
+        if hit || (!first && self.breakpoints.contains(&pc)) {
+            self.pause();
+            return true;
+        }
+        false
+    }
+
+    // one line description of where execution stopped
+    pub fn location(&self, cpu: &CPU) -> String {
+        let pc = cpu.get_pc() as usize;
+        let mem = cpu.get_mem();
+        match disasm::disassemble_at(mem, pc, disasm::Syntax::Cowgod) {
+            Some((text, _)) => format!("{:#06X}: {:02X}{:02X}  {}", pc, mem[pc], mem[pc + 1], text),
+            None => format!("{:#06X}: <out of memory>", pc),
+        }
+    }
+
+    // runs one console command, returning what should be printed
+    pub fn command(&mut self, line: &str, cpu: &mut CPU) -> String {
+        match self.try_command(line, cpu) {
+            Ok(out) => out,
+            Err(msg) => format!("error: {}\n", msg),
+        }
+    }
+
+    fn try_command(&mut self, line: &str, cpu: &mut CPU) -> Result<String, String> {
+        let mut args = line.split_whitespace();
+        let cmd = match args.next() {
+            Some(cmd) => cmd,
+            None => return Ok(String::new()),
+        };
+        let (pc, sp) = (cpu.get_pc(), cpu.get_sp());
+        Ok(match cmd {
+            "h" | "help" | "?" => format!("{}\n", HELP),
+            "b" | "break" => {
+                let addr = parse_addr(args.next())?;
+                self.add_breakpoint(addr);
+                format!("breakpoint at {:#06X}\n", addr)
+            }
+            "d" | "delete" => {
+                let addr = parse_addr(args.next())?;
+                if !self.breakpoints.remove(&addr) {
+                    return Err(format!("no breakpoint at {:#06X}", addr));
+                }
+                format!("deleted breakpoint at {:#06X}\n", addr)
+            }
+            "bl" | "breakpoints" => {
+                self.breakpoints.iter().map(|addr| format!("{:#06X}\n", addr)).collect()
+            }
+            "w" | "watch" => {
+                let start = parse_addr(args.next())?;
+                let mut end = start;
+                let mut access = "rw";
+                for arg in args {
+                    match arg {
+                        "r" | "w" | "rw" => access = arg,
+                        _ => end = parse_addr(Some(arg))?,
+                    }
+                }
+                if end < start {
+                    return Err("end of the range is before its start".to_string());
+                }
+                cpu.add_watchpoint(Watchpoint {
+                    start,
+                    end,
+                    read: access.contains('r'),
+                    write: access.contains('w'),
+                });
+                format!("watching {:#06X}..={:#06X} ({})\n", start, end, access)
+            }
+            "unwatch" => {
+                let start = parse_addr(args.next())?;
+                let before = cpu.get_watchpoints().len();
+                cpu.remove_watchpoints(start);
+                if cpu.get_watchpoints().len() == before {
+                    return Err(format!("no watchpoint at {:#06X}", start));
+                }
+                format!("removed watchpoints at {:#06X}\n", start)
+            }
+            "watches" => cpu
+                .get_watchpoints()
+                .iter()
+                .map(|w| {
+                    let access = if w.read && w.write { "rw" } else if w.read { "r" } else { "w" };
+                    format!("{:#06X}..={:#06X} ({})\n", w.start, w.end, access)
+                })
+                .collect(),
+            "cond" => {
+                let text = line.trim_start()[cmd.len()..].trim();
+                let mut cond = Condition::parse(text)?;
+                cond.was_true = cond.eval(cpu);
+                let out = format!("condition {:X}: {}\n", self.conditions.len(), cond.text);
+                self.conditions.push(cond);
+                out
+            }
+            "uncond" => {
+                let index = parse_hex(args.next())?;
+                if index >= self.conditions.len() {
+                    return Err(format!("no condition {:X}", index));
+                }
+                format!("removed condition {}\n", self.conditions.remove(index).text)
+            }
+            "conds" => self
+                .conditions
+                .iter()
+                .enumerate()
+                .map(|(index, cond)| format!("{:X}: {}\n", index, cond.text))
+                .collect(),
+            "s" | "step" => {
+                self.resume(RunMode::Step);
+                String::new()
+            }
+            "n" | "next" => {
+                let op = cpu.get_mem().get(pc as usize).copied().unwrap_or(0);
+                if op >> 4 == 0x2 {
+                    // 2NNN, run until it returns to the next instruction
+                    self.resume(RunMode::StepOver { pc: pc.wrapping_add(2), sp });
+                } else {
+                    self.resume(RunMode::Step);
+                }
+                String::new()
+            }
+            "o" | "out" | "finish" => {
+                if sp == 0 {
+                    return Err("not in a subroutine".to_string());
+                }
+                self.resume(RunMode::StepOut { sp });
+                String::new()
+            }
+            "u" | "until" => {
+                self.resume(RunMode::RunTo(parse_addr(args.next())?));
+                String::new()
+            }
+            "c" | "continue" => {
+                self.resume(RunMode::Continue);
+                String::new()
+            }
+            "p" | "pause" => {
+                self.pause();
+                format!("{}\n", self.location(cpu))
+            }
+            "regs" => cpu.dump_registers(),
+            "i" => format!("i: {:#06X}\n", cpu.get_i()),
+            "sp" => format!("sp: {}\n", sp),
+            "stack" => format!("stack: {:04X?}\n", cpu.get_stack()),
+            "timers" => {
+                let (delay, sound) = cpu.get_timers();
+                format!("delay timer: {}  sound timer: {}\n", delay, sound)
+            }
+            "m" | "mem" => {
+                let start = parse_hex(args.next())?;
+                let len = match args.next() {
+                    Some(len) => parse_hex(Some(len))?,
+                    None => DEFAULT_MEM_LEN,
+                };
+                hex_dump(cpu.get_mem(), start, len)
+            }
+            _ => return Err(format!("unknown command: {} (try help)", cmd)),
+        })
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger { Debugger::new() }
+}
+
+// reads debugger commands from stdin on a background thread, their output goes to stdout
+pub struct Console {
+    lines: mpsc::Receiver<String>,
+}
+
+impl Console {
+    pub fn stdin() -> Console {
+        let (tx, rx) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Console { lines: rx }
+    }
+
+    pub fn try_read_line(&self) -> Option<String> { self.lines.try_recv().ok() }
+
+    pub fn print(&self, text: &str) {
+        print!("{}", text);
+        let _ = std::io::stdout().flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quirks::Quirks;
+
+    // 0x200 calls a subroutine at 0x208 that writes V0 to 0x300, reads it back and sets V3,
+    // then loops at 0x206 forever
+    const ROM: [u8; 20] = [
+        0x60, 0x05, 0x22, 0x08, 0x70, 0x01, 0x12, 0x06, 0xA3, 0x00, 0xF0, 0x55, 0xA3, 0x00, 0xF0, 0x65, 0x63, 0x07,
+        0x00, 0xEE,
+    ];
+
+    fn cpu() -> CPU {
+        let mut cpu = CPU::new(Quirks::default());
+        cpu.load_rom(&ROM);
+        cpu
+    }
+
+    // runs the cpu like the emulator does, returning how many instructions ran before the
+    // debugger stopped it, or None if it didn't within 100
+    fn run(debugger: &mut Debugger, cpu: &mut CPU) -> Option<usize> {
+        for count in 0..100 {
+            if debugger.should_break(cpu) {
+                return Some(count);
+            }
+            cpu.perform_cycle().unwrap();
+        }
+        None
+    }
+
+    #[test]
+    fn breakpoints() {
+        let (mut debugger, mut cpu) = (Debugger::new(), cpu());
+        assert_eq!(debugger.command("b 0x20C", &mut cpu), "breakpoint at 0x020C\n");
+        assert_eq!(debugger.command("break 204", &mut cpu), "breakpoint at 0x0204\n");
+        assert_eq!(debugger.command("bl", &mut cpu), "0x0204\n0x020C\n");
+        assert_eq!(run(&mut debugger, &mut cpu), Some(4));
+        assert!(debugger.is_paused());
+        assert_eq!(cpu.get_pc(), 0x20C);
+        assert_eq!(debugger.location(&cpu), "0x020C: A300  LD I, 0x300");
+        // paused stays put, continuing doesn't stop on the same breakpoint again
+        assert_eq!(run(&mut debugger, &mut cpu), Some(0));
+        debugger.command("c", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(4));
+        assert_eq!(cpu.get_pc(), 0x204);
+        assert_eq!(debugger.command("d 204", &mut cpu), "deleted breakpoint at 0x0204\n");
+        assert_eq!(debugger.command("d 204", &mut cpu), "error: no breakpoint at 0x0204\n");
+        debugger.command("c", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+        assert_eq!(debugger.command("b 10000", &mut cpu), "error: address out of range: 0x10000\n");
+        assert_eq!(debugger.command("b", &mut cpu), "error: missing argument\n");
+    }
+
+    #[test]
+    fn stepping() {
+        let (mut debugger, mut cpu) = (Debugger::new(), cpu());
+        debugger.pause();
+        debugger.command("s", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(1));
+        assert_eq!(cpu.get_pc(), 0x202);
+        // next runs the whole subroutine
+        debugger.command("n", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(7));
+        assert_eq!((cpu.get_pc(), cpu.get_sp(), cpu.get_regs()[3]), (0x204, 0, 7));
+        // and steps over anything else
+        debugger.command("next", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(1));
+        assert_eq!(cpu.get_pc(), 0x206);
+        assert_eq!(debugger.command("o", &mut cpu), "error: not in a subroutine\n");
+
+        // out finishes the subroutine, stopping right after the call
+        let (mut debugger, mut cpu) = (Debugger::new(), self::cpu());
+        debugger.add_breakpoint(0x20A);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(3));
+        assert_eq!(cpu.get_sp(), 1);
+        debugger.command("out", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(5));
+        assert_eq!((cpu.get_pc(), cpu.get_sp()), (0x204, 0));
+        debugger.command("until 206", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), Some(1));
+        assert_eq!(cpu.get_pc(), 0x206);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let (mut debugger, mut cpu) = (Debugger::new(), cpu());
+        assert_eq!(debugger.command("w 2FF 300 w", &mut cpu), "watching 0x02FF..=0x0300 (w)\n");
+        assert_eq!(run(&mut debugger, &mut cpu), Some(4));
+        assert_eq!(debugger.take_reason().as_deref(), Some("watchpoint: write to 0x0300 by 0x020A"));
+        assert_eq!(cpu.get_pc(), 0x20C);
+        // the read back doesn't count
+        debugger.command("c", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+
+        let (mut debugger, mut cpu) = (Debugger::new(), self::cpu());
+        debugger.command("watch 300 r", &mut cpu);
+        assert_eq!(debugger.command("watches", &mut cpu), "0x0300..=0x0300 (r)\n");
+        assert_eq!(run(&mut debugger, &mut cpu), Some(6));
+        assert_eq!(debugger.take_reason().as_deref(), Some("watchpoint: read from 0x0300 by 0x020E"));
+        assert_eq!(debugger.command("unwatch 300", &mut cpu), "removed watchpoints at 0x0300\n");
+        assert_eq!(debugger.command("unwatch 300", &mut cpu), "error: no watchpoint at 0x0300\n");
+        assert_eq!(debugger.command("w 301 300", &mut cpu), "error: end of the range is before its start\n");
+    }
+
+    #[test]
+    fn conditions() {
+        let (mut debugger, mut cpu) = (Debugger::new(), cpu());
+        assert_eq!(debugger.command("cond V3 == 7", &mut cpu), "condition 0: V3 == 7\n");
+        assert_eq!(run(&mut debugger, &mut cpu), Some(7));
+        assert_eq!(debugger.take_reason().as_deref(), Some("condition V3 == 7 is true"));
+        assert_eq!(cpu.get_pc(), 0x212);
+        // only becoming true breaks, not staying true
+        debugger.command("c", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+
+        // numbers are hex like everywhere else, with or without 0x
+        let (mut debugger, mut cpu) = (Debugger::new(), self::cpu());
+        debugger.command("cond i >= 0x300", &mut cpu);
+        debugger.command("cond V0 == a", &mut cpu);
+        assert_eq!(debugger.command("conds", &mut cpu), "0: i >= 0x300\n1: V0 == a\n");
+        assert_eq!(run(&mut debugger, &mut cpu), Some(3));
+        assert_eq!(debugger.take_reason().as_deref(), Some("condition i >= 0x300 is true"));
+        assert_eq!(debugger.command("uncond 0", &mut cpu), "removed condition i >= 0x300\n");
+        assert_eq!(debugger.command("uncond 1", &mut cpu), "error: no condition 1\n");
+        debugger.command("c", &mut cpu);
+        assert_eq!(run(&mut debugger, &mut cpu), None);
+        assert_eq!(debugger.command("cond V0 < 6", &mut cpu), "condition 1: V0 < 6\n");
+
+        assert_eq!(debugger.command("cond V3 = 7", &mut cpu), "error: no comparison in condition: V3 = 7\n");
+        assert_eq!(debugger.command("cond V3 == 10G", &mut cpu), "error: not a hex number: 10G\n");
+        assert_eq!(debugger.command("cond VG > 1", &mut cpu), "error: not a hex number: G\n");
+    }
+}
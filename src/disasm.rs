@@ -0,0 +1,185 @@
+// Turns opcodes back into text. Decoding is `Instruction::decode`, the same one the
+// CPU executes, this only knows how to spell the result.
+
+use crate::instruction::Instruction;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    Cowgod, // mnemonics from Cowgod's technical reference, SCHIP/XO-CHIP ones in the same style
+    Octo,   // Octo's statements, so listings read like the source most XO-CHIP roms come from
+}
+
+impl Syntax {
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name.to_ascii_lowercase().as_str() {
+            "cowgod" => Some(Syntax::Cowgod),
+            "octo" => Some(Syntax::Octo),
+            _ => None,
+        }
+    }
+}
+
+pub fn format(instr: Instruction, syntax: Syntax) -> String {
+    match syntax {
+        Syntax::Cowgod => cowgod(instr),
+        Syntax::Octo => octo(instr),
+    }
+}
+
+fn cowgod(instr: Instruction) -> String {
+    use Instruction::*;
+    match instr {
+        Sys(nnn) => format!("SYS {:#05X}", nnn),
+        ClearScreen => "CLS".to_string(),
+        Return => "RET".to_string(),
+        ScrollDown(n) => format!("SCD {}", n),
+        ScrollUp(n) => format!("SCU {}", n),
+        ScrollRight => "SCR".to_string(),
+        ScrollLeft => "SCL".to_string(),
+        Exit => "EXIT".to_string(),
+        Lores => "LOW".to_string(),
+        Hires => "HIGH".to_string(),
+        Jump(nnn) => format!("JP {:#05X}", nnn),
+        Call(nnn) => format!("CALL {:#05X}", nnn),
+        SkipEqImm(x, nn) => format!("SE V{:X}, {:#04X}", x, nn),
+        SkipNeImm(x, nn) => format!("SNE V{:X}, {:#04X}", x, nn),
+        SkipEq(x, y) => format!("SE V{:X}, V{:X}", x, y),
+        SaveRange(x, y) => format!("SAVE V{:X} - V{:X}", x, y),
+        LoadRange(x, y) => format!("LOAD V{:X} - V{:X}", x, y),
+        SetImm(x, nn) => format!("LD V{:X}, {:#04X}", x, nn),
+        AddImm(x, nn) => format!("ADD V{:X}, {:#04X}", x, nn),
+        Set(x, y) => format!("LD V{:X}, V{:X}", x, y),
+        Or(x, y) => format!("OR V{:X}, V{:X}", x, y),
+        And(x, y) => format!("AND V{:X}, V{:X}", x, y),
+        Xor(x, y) => format!("XOR V{:X}, V{:X}", x, y),
+        Add(x, y) => format!("ADD V{:X}, V{:X}", x, y),
+        Sub(x, y) => format!("SUB V{:X}, V{:X}", x, y),
+        ShiftRight(x, y) => format!("SHR V{:X}, V{:X}", x, y),
+        SubReverse(x, y) => format!("SUBN V{:X}, V{:X}", x, y),
+        ShiftLeft(x, y) => format!("SHL V{:X}, V{:X}", x, y),
+        SkipNe(x, y) => format!("SNE V{:X}, V{:X}", x, y),
+        SetI(nnn) => format!("LD I, {:#05X}", nnn),
+        JumpOffset(nnn) => format!("JP V0, {:#05X}", nnn),
+        Random(x, nn) => format!("RND V{:X}, {:#04X}", x, nn),
+        Draw(x, y, n) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        SkipKey(x) => format!("SKP V{:X}", x),
+        SkipNotKey(x) => format!("SKNP V{:X}", x),
+        SetILong(nnnn) => format!("LD I, LONG {:#06X}", nnnn),
+        SelectPlanes(n) => format!("PLANE {}", n),
+        LoadAudio => "AUDIO".to_string(),
+        GetDelay(x) => format!("LD V{:X}, DT", x),
+        WaitKey(x) => format!("LD V{:X}, K", x),
+        SetDelay(x) => format!("LD DT, V{:X}", x),
+        SetSound(x) => format!("LD ST, V{:X}", x),
+        AddI(x) => format!("ADD I, V{:X}", x),
+        Font(x) => format!("LD F, V{:X}", x),
+        BigFont(x) => format!("LD HF, V{:X}", x),
+        Bcd(x) => format!("LD B, V{:X}", x),
+        SetPitch(x) => format!("PITCH V{:X}", x),
+        Store(x) => format!("LD [I], V{:X}", x),
+        Load(x) => format!("LD V{:X}, [I]", x),
+        SaveFlags(x) => format!("LD R, V{:X}", x),
+        LoadFlags(x) => format!("LD V{:X}, R", x),
+    }
+}
+
+fn octo(instr: Instruction) -> String {
+    use Instruction::*;
+    // conditional skips become the opposite `if ... then`, which is what octo compiles them from
+    match instr {
+        Sys(nnn) => format!("{:#04X} {:#04X} # machine code", nnn >> 8, nnn & 0xFF),
+        ClearScreen => "clear".to_string(),
+        Return => "return".to_string(),
+        ScrollDown(n) => format!("scroll-down {}", n),
+        ScrollUp(n) => format!("scroll-up {}", n),
+        ScrollRight => "scroll-right".to_string(),
+        ScrollLeft => "scroll-left".to_string(),
+        Exit => "exit".to_string(),
+        Lores => "lores".to_string(),
+        Hires => "hires".to_string(),
+        Jump(nnn) => format!("jump {:#05X}", nnn),
+        Call(nnn) => format!(":call {:#05X}", nnn),
+        SkipEqImm(x, nn) => format!("if v{:x} != {:#04X} then", x, nn),
+        SkipNeImm(x, nn) => format!("if v{:x} == {:#04X} then", x, nn),
+        SkipEq(x, y) => format!("if v{:x} != v{:x} then", x, y),
+        SaveRange(x, y) => format!("save v{:x} - v{:x}", x, y),
+        LoadRange(x, y) => format!("load v{:x} - v{:x}", x, y),
+        SetImm(x, nn) => format!("v{:x} := {:#04X}", x, nn),
+        AddImm(x, nn) => format!("v{:x} += {:#04X}", x, nn),
+        Set(x, y) => format!("v{:x} := v{:x}", x, y),
+        Or(x, y) => format!("v{:x} |= v{:x}", x, y),
+        And(x, y) => format!("v{:x} &= v{:x}", x, y),
+        Xor(x, y) => format!("v{:x} ^= v{:x}", x, y),
+        Add(x, y) => format!("v{:x} += v{:x}", x, y),
+        Sub(x, y) => format!("v{:x} -= v{:x}", x, y),
+        ShiftRight(x, y) => format!("v{:x} >>= v{:x}", x, y),
+        SubReverse(x, y) => format!("v{:x} =- v{:x}", x, y),
+        ShiftLeft(x, y) => format!("v{:x} <<= v{:x}", x, y),
+        SkipNe(x, y) => format!("if v{:x} == v{:x} then", x, y),
+        SetI(nnn) => format!("i := {:#05X}", nnn),
+        JumpOffset(nnn) => format!("jump0 {:#05X}", nnn),
+        Random(x, nn) => format!("v{:x} := random {:#04X}", x, nn),
+        Draw(x, y, n) => format!("sprite v{:x} v{:x} {}", x, y, n),
+        SkipKey(x) => format!("if v{:x} -key then", x),
+        SkipNotKey(x) => format!("if v{:x} key then", x),
+        SetILong(nnnn) => format!("i := long {:#06X}", nnnn),
+        SelectPlanes(n) => format!("plane {}", n),
+        LoadAudio => "audio".to_string(),
+        GetDelay(x) => format!("v{:x} := delay", x),
+        WaitKey(x) => format!("v{:x} := key", x),
+        SetDelay(x) => format!("delay := v{:x}", x),
+        SetSound(x) => format!("buzzer := v{:x}", x),
+        AddI(x) => format!("i += v{:x}", x),
+        Font(x) => format!("i := hex v{:x}", x),
+        BigFont(x) => format!("i := bighex v{:x}", x),
+        Bcd(x) => format!("bcd v{:x}", x),
+        SetPitch(x) => format!("pitch := v{:x}", x),
+        Store(x) => format!("save v{:x}", x),
+        Load(x) => format!("load v{:x}", x),
+        SaveFlags(x) => format!("saveflags v{:x}", x),
+        LoadFlags(x) => format!("loadflags v{:x}", x),
+    }
+}
+
+// raw bytes that don't decode to anything
+fn data(bytes: &[u8], syntax: Syntax) -> String {
+    let bytes: Vec<String> = bytes.iter().map(|b| format!("{:#04X}", b)).collect();
+    match syntax {
+        Syntax::Cowgod => format!("db {}", bytes.join(", ")),
+        Syntax::Octo => bytes.join(" "),
+    }
+}
+
+// decodes mem[addr..], returning the text and the number of bytes it took up
+pub fn disassemble_at(mem: &[u8], addr: usize, syntax: Syntax) -> Option<(String, usize)> {
+    let word = |addr: usize| mem.get(addr..addr + 2).map(|w| (w[0] as u16) << 8 | w[1] as u16);
+    let opcode = word(addr)?;
+    match Instruction::decode(opcode, word(addr + 2).unwrap_or(0)) {
+        // a long load cut off by the end of memory is just data
+        Some(Instruction::SetILong(_)) if word(addr + 2).is_none() => None,
+        decoded => decoded,
+    }
+    .map(|instr| (format(instr, syntax), instr.size()))
+    .or_else(|| Some((data(&mem[addr..addr + 2], syntax), 2)))
+}
+
+// one line per instruction: address, raw bytes, text. rom is assumed to be loaded at start.
+// Data mixed in with the code gets decoded as if it were code, as with any linear disassembler.
+// Octo listings keep the address and bytes in a comment so the output still assembles.
+pub fn listing(rom: &[u8], start: u16, syntax: Syntax) -> String {
+    let mut out = String::new();
+    let mut offset = 0;
+    while offset < rom.len() {
+        // a trailing odd byte is the only thing disassemble_at gives up on
+        let (text, size) = disassemble_at(rom, offset, syntax)
+            .unwrap_or_else(|| (data(&rom[offset..], syntax), rom.len() - offset));
+        let addr = start as usize + offset;
+        let bytes: String = rom[offset..offset + size].iter().map(|b| format!("{:02X}", b)).collect();
+        out += &match syntax {
+            Syntax::Cowgod => format!("{:#06X}: {:<8}  {}\n", addr, bytes, text),
+            Syntax::Octo => format!("{:<28} # {:#06X}: {}\n", text, addr, bytes),
+        };
+        offset += size;
+    }
+    out
+}
@@ -1,223 +1,750 @@
 use crate::cpu;
+use crate::debugger::{Console, Debugger};
+use crate::frontend::{Audio, Clock, Display, Input, InputEvent};
+use crate::movie::Movie;
+use crate::palette::Palette;
+use crate::rewind::Rewind;
+use crate::timing;
 
-use std::time::SystemTime;
-//use std::thread;
-
-use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
-use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
-use sdl2::rect::Rect;
-use sdl2::render::WindowCanvas;
+use std::collections::VecDeque;
+use std::path::PathBuf;
 use std::time::Duration;
 
+pub const DEFAULT_IPF: u64 = 8; // ~500 instructions per second at 60 frames per second
+const TIMER_FREQ: u64 = 60; // frames per second, the timers tick once per frame
+const NUM_STATE_SLOTS: u8 = 10;
 
-const CPU_FREQ: u64 = 500; //adjust as desired. I saw this rate recommended
-const TIMER_FREQ: u64 = 60;
-const SPEC_FREQ: i32 = 44100;
-const SCR_WIDTH: usize = cpu::GFX_COLS * ((2 * PADDING) + PX_WIDTH);
-const SCR_HEIGHT: usize = cpu::GFX_ROWS * ((2 * PADDING) + PX_HEIGHT);
-//const PADDING: usize = 1;
-//const PX_WIDTH: usize = 22; //+ 2*padding==24
-//const PX_HEIGHT: usize = 10; //+ 2*padding==12
-const PADDING: usize = 0;
-const PX_WIDTH: usize = 12;
-const PX_HEIGHT: usize = 12;
-
-const BG_COLOR: Color = Color::RGB(0, 0, 0);
-const FG_COLOR: Color = Color::RGB(128, 128, 128);
-
-fn select_key(keycode: Keycode) -> Option<usize> {
-    return match keycode {
-        Keycode::Num1 => Some(0x1),
-        Keycode::Num2 => Some(0x2),
-        Keycode::Num3 => Some(0x3),
-        Keycode::Num4 => Some(0xC),
-        Keycode::Q => Some(0x4),
-        Keycode::W => Some(0x5),
-        Keycode::E => Some(0x6),
-        Keycode::R => Some(0xD),
-        Keycode::A => Some(0x7),
-        Keycode::S => Some(0x8),
-        Keycode::D => Some(0x9),
-        Keycode::F => Some(0xE),
-        Keycode::Z => Some(0xA),
-        Keycode::X => Some(0x0),
-        Keycode::C => Some(0xB),
-        Keycode::V => Some(0xF),
-        _ => None,
-    };
+// what to do when the CPU reports an error (unknown opcode, stack or memory fault)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorPolicy {
+    Halt,  // stop executing, show the error and wait for the frontend to quit
+    Skip,  // treat the instruction as a NOP and keep going
+    Debug, // pause in the debugger (or halt and dump the CPU state without a console)
 }
 
-// source: https://docs.rs/sdl2/0.32.1/sdl2/audio/index.html
-struct SquareWave {
-    phase_inc: f32,
-    phase: f32,
-    volume: f32,
-}
-impl AudioCallback for SquareWave {
-    type Channel = f32;
-
-    fn callback(&mut self, out: &mut [f32]) {
-        // Generate a square wave
-        for x in out.iter_mut() {
-            *x = if self.phase <= 0.5 {
-                self.volume
-            } else {
-                -self.volume
-            };
-            self.phase = (self.phase + self.phase_inc) % 1.0;
+impl ErrorPolicy {
+    pub fn from_name(name: &str) -> Option<ErrorPolicy> {
+        match name.to_ascii_lowercase().as_str() {
+            "halt" => Some(ErrorPolicy::Halt),
+            "skip" | "nop" => Some(ErrorPolicy::Skip),
+            "debug" => Some(ErrorPolicy::Debug),
+            _ => None,
         }
     }
 }
 
-pub struct Emulator {
+// recording or replaying a movie
+enum MovieMode {
+    Recording(Movie),        // handed to the caller by take_movie
+    Replaying(Movie, usize), // next frame
+}
+
+// measured speed, shown in the status line
+struct SpeedMeter {
+    start: Duration,
+    frames: u64,
+    instructions: u64,
+}
+
+// why Emulator::run returned
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Stop {
+    Quit,                 // the frontend asked to quit
+    Exited,               // the ROM executed 00FD
+    Error(cpu::CpuError), // halted on an error
+    Limit,                // ran for as long as set_limits allows
+    Display(String),      // the display failed to draw
+}
+
+pub struct Emulator<D: Display, A: Audio, I: Input, C: Clock> {
     cpu: cpu::CPU,
-    canvas: WindowCanvas,
-    event_pump: sdl2::EventPump,
-    audio: AudioDevice<SquareWave>,
+    display: D,
+    audio: A,
+    input: I,
+    clock: C,
+    audio_state: ([u8; cpu::AUDIO_PATTERN_SIZE], u8), // last pattern and pitch handed to audio
+    sound_playing: bool,
+    on_error: ErrorPolicy,
+    debugger: Debugger,
+    console: Option<Console>,
+    announced_pause: bool,
+    state_path: Option<PathBuf>, // slot files are <state_path>.<slot>.state
+    state_slot: u8,
+    rewind: Option<Rewind>, // a snapshot per frame
+    rewinding: bool,
+    movie: Option<MovieMode>,
+    key_events: VecDeque<(usize, bool)>, // presses and releases waiting for a frame to start
+    ipf: u64,        // instructions per frame
+    vip_timing: bool, // frames last timing::INTERPRETER_CYCLES instead of ipf instructions
+    frame_drew: bool, // gfx changed during the current frame, presented when it ends
+    display_error: Option<String>, // the first failed draw, run returns it before going on
+    user_paused: bool,  // by the pause hotkey, unlike the debugger this stops between frames
+    advance: bool,      // run one frame while user_paused
+    fast_forward: bool, // don't wait for frames, presenting at most at the normal rate
+    speed: SpeedMeter,
+    palettes: Vec<Palette>, // cycled through by the palette hotkey
+    palette: usize,
+    max_frames: Option<u64>,
+    max_instructions: Option<u64>,
+    frames_run: u64,
+    instructions_run: u64,
 }
 
-impl Emulator {
-    pub fn new() -> Emulator {
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let window = video_subsystem
-            .window("chip8", SCR_WIDTH as u32, SCR_HEIGHT as u32)
-            .position_centered()
-            .build()
-            .unwrap();
-        let canvas: WindowCanvas = window.into_canvas().build().unwrap();
-        let event_pump = sdl_context.event_pump().unwrap();
-        let spec = AudioSpecDesired {
-            freq: Some(SPEC_FREQ),
-            channels: Some(1),
-            samples: None,
-        };
-        let audio = sdl_context
-            .audio()
-            .unwrap()
-            .open_playback(None, &spec, |spec| SquareWave {
-                phase_inc: 440.0 / spec.freq as f32,
-                phase: 0.0,
-                volume: 0.25,
-            })
-            .unwrap();
+impl<D: Display, A: Audio, I: Input, C: Clock> Emulator<D, A, I, C> {
+    pub fn new(
+        cpu: cpu::CPU,
+        display: D,
+        mut audio: A,
+        input: I,
+        clock: C,
+        on_error: ErrorPolicy,
+    ) -> Emulator<D, A, I, C> {
+        let audio_state = (cpu.get_audio_pattern(), cpu.get_pitch());
+        audio.set_pattern(audio_state.0, audio_state.1);
         Emulator {
-            cpu: cpu::CPU::new(),
-            canvas: canvas,
-            event_pump: event_pump,
-            audio: audio,
+            cpu,
+            display,
+            audio,
+            input,
+            clock,
+            audio_state,
+            sound_playing: false,
+            on_error,
+            debugger: Debugger::new(),
+            console: None,
+            announced_pause: false,
+            state_path: None,
+            state_slot: 0,
+            rewind: None,
+            rewinding: false,
+            movie: None,
+            key_events: VecDeque::new(),
+            ipf: DEFAULT_IPF,
+            vip_timing: false,
+            frame_drew: false,
+            display_error: None,
+            user_paused: false,
+            advance: false,
+            fast_forward: false,
+            speed: SpeedMeter { start: Duration::ZERO, frames: 0, instructions: 0 },
+            palettes: vec![],
+            palette: 0,
+            max_frames: None,
+            max_instructions: None,
+            frames_run: 0,
+            instructions_run: 0,
         }
     }
 
-    fn draw(&mut self) {
-        self.canvas.set_draw_color(BG_COLOR);
-        self.canvas.clear();
-        let mut rects: Vec<Rect> = vec![];
-        self.canvas.set_draw_color(FG_COLOR);
-        let rects_to_draw = self.cpu.get_gfx();
-        for i in 0..rects_to_draw.len() {
-            if rects_to_draw[i] {
-                let (x, y) = cpu::index_to_coords(i as u16);
-                // actual width = 2x padding + px_width
-                // actual height = 2x padding + px_height
-                rects.push(Rect::new(
-                    (x * (PADDING + PX_WIDTH + PADDING) + PADDING) as i32,
-                    (y * (PADDING + PX_HEIGHT + PADDING) + PADDING) as i32,
-                    PX_WIDTH as u32,
-                    PX_HEIGHT as u32,
-                ));
+    pub fn cpu(&self) -> &cpu::CPU { &self.cpu }
+    pub fn cpu_mut(&mut self) -> &mut cpu::CPU { &mut self.cpu }
+    pub fn debugger_mut(&mut self) -> &mut Debugger { &mut self.debugger }
+    // where debugger commands are read from, without one the debugger can't be resumed
+    pub fn set_console(&mut self, console: Console) { self.console = Some(console); }
+    // where the save state hotkeys write to, usually the rom's path. Without one they do nothing.
+    pub fn set_state_path(&mut self, path: PathBuf) { self.state_path = Some(path); }
+
+    pub fn ipf(&self) -> u64 { self.ipf }
+    // emulation speed, takes effect from the next frame. Movies run at a fixed speed,
+    // so it is ignored while one is recorded or replayed.
+    pub fn set_ipf(&mut self, ipf: u64) {
+        if self.movie.is_none() {
+            self.ipf = ipf.max(1);
+        }
+    }
+    // runs each instruction for about as long as it took on the COSMAC VIP instead of a fixed
+    // number per frame, see timing.rs. Ignored while a movie is active, like set_ipf.
+    pub fn set_vip_timing(&mut self, vip_timing: bool) {
+        if self.movie.is_none() {
+            self.vip_timing = vip_timing;
+        }
+    }
+
+    pub fn set_paused(&mut self, paused: bool) { self.user_paused = paused; }
+
+    // run returns Stop::Limit once this many frames or instructions have run, for
+    // frontends without a user to quit them (tests, CI)
+    pub fn set_limits(&mut self, frames: Option<u64>, instructions: Option<u64>) {
+        self.max_frames = frames;
+        self.max_instructions = instructions;
+    }
+
+    // the palettes to switch between, starting with palettes[selected]
+    pub fn set_palettes(&mut self, palettes: Vec<Palette>, selected: usize) {
+        self.palettes = palettes;
+        self.palette = selected;
+        if let Some(palette) = self.palettes.get(selected) {
+            self.display.set_palette(palette);
+        }
+    }
+
+    fn next_palette(&mut self) {
+        if self.palettes.is_empty() {
+            return;
+        }
+        self.palette = (self.palette + 1) % self.palettes.len();
+        let palette = &self.palettes[self.palette];
+        self.display.set_palette(palette);
+        let message = format!("palette: {}", palette.name);
+        self.notify(&message);
+        self.draw();
+    }
+
+    fn change_speed(&mut self, faster: bool) {
+        if self.movie.is_some() || self.vip_timing {
+            self.notify("speed is fixed by the movie or --vip-timing");
+            return;
+        }
+        let step = (self.ipf / 4).max(1);
+        self.set_ipf(if faster { self.ipf + step } else { self.ipf.saturating_sub(step) });
+        self.notify(&format!("speed: {} instructions per frame", self.ipf));
+    }
+
+    // keeps history to rewind through, without one the rewind key does nothing
+    pub fn set_rewind(&mut self, rewind: Rewind) { self.rewind = Some(rewind); }
+
+    // records every frame's keys from now on, seeding the rng with seed so the run can
+    // be replayed. take_movie hands it over once run returns.
+    pub fn record_movie(&mut self, seed: u64) {
+        self.cpu.seed_rng(seed);
+        let ipf = if self.vip_timing { 0 } else { self.ipf };
+        let cpu = &self.cpu;
+        let movie = Movie::new(*cpu.get_rom_hash(), cpu.get_load_address() as u16, seed, cpu.get_quirks(), ipf);
+        self.movie = Some(MovieMode::Recording(movie));
+    }
+
+    // the movie being recorded, if any, for the caller to save
+    pub fn take_movie(&mut self) -> Option<Movie> {
+        match self.movie.take() {
+            Some(MovieMode::Recording(movie)) => Some(movie),
+            other => {
+                self.movie = other;
+                None
             }
         }
+    }
 
-        self.canvas.fill_rects(&rects).unwrap();
-        self.canvas.present();
+    // the cpu must have been created with the movie's quirks and have its rom loaded at
+    // the movie's load address.
+    // Keyboard input is ignored until the movie runs out.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), String> {
+        if movie.rom_hash != *self.cpu.get_rom_hash() {
+            return Err("movie was recorded with a different rom".to_string());
+        }
+        if movie.quirks != self.cpu.get_quirks() {
+            return Err("movie was recorded with different quirks".to_string());
+        }
+        if movie.load_address as usize != self.cpu.get_load_address() {
+            return Err(format!("movie was recorded with the rom loaded at {:#05X}", movie.load_address));
+        }
+        self.cpu.seed_rng(movie.seed);
+        self.vip_timing = movie.ipf == 0;
+        self.ipf = movie.ipf.max(1);
+        self.movie = Some(MovieMode::Replaying(movie, 0));
+        Ok(())
+    }
+
+    fn replaying(&self) -> bool { matches!(self.movie, Some(MovieMode::Replaying(..))) }
+
+    // the next queued press or release that changes anything, one per frame. A key that
+    // goes down and up between two polls (or while the debugger is paused mid-frame)
+    // still gets a frame of its own, and the key mask logged for a movie differs from
+    // the previous frame's in at most one key, so replaying it can't reorder anything.
+    fn apply_key_event(&mut self) {
+        while let Some((key_num, pressed)) = self.key_events.pop_front() {
+            if self.cpu.get_key_mask() & 1 << key_num != (pressed as u16) << key_num {
+                self.cpu.set_key(key_num, pressed);
+                return;
+            }
+        }
+    }
+
+    // applies (replaying) or logs (recording) the keys for the frame that is starting
+    fn movie_frame(&mut self) {
+        self.apply_key_event();
+        let finished = match &mut self.movie {
+            Some(MovieMode::Recording(movie)) => {
+                movie.frames.push(self.cpu.get_key_mask());
+                return;
+            }
+            Some(MovieMode::Replaying(movie, frame)) => match movie.frames.get(*frame) {
+                Some(&mask) => {
+                    self.cpu.set_key_mask(mask);
+                    *frame += 1;
+                    return;
+                }
+                None => *frame,
+            },
+            None => return,
+        };
+        self.cpu.set_key_mask(0);
+        self.movie = None;
+        self.notify(&format!("movie finished after {} frames", finished));
+    }
+
+    fn slot_path(&self) -> Option<PathBuf> {
+        let mut path = self.state_path.clone()?.into_os_string();
+        path.push(format!(".{}.state", self.state_slot));
+        Some(path.into())
+    }
+
+    fn save_state(&mut self) {
+        if let Some(path) = self.slot_path() {
+            let message = match std::fs::write(&path, self.cpu.save_state()) {
+                Ok(()) => format!("saved state {} to {}", self.state_slot, path.display()),
+                Err(e) => format!("{}: {}", path.display(), e),
+            };
+            self.notify(&message);
+        }
+    }
+
+    fn load_state(&mut self) {
+        if let Some(path) = self.slot_path() {
+            let result = std::fs::read(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|data| self.cpu.load_state(&data).map_err(|e| e.to_string()));
+            match result {
+                Ok(()) => {
+                    self.notify(&format!("loaded state {} from {}", self.state_slot, path.display()));
+                    self.draw();
+                }
+                Err(e) => self.notify(&format!("{}: {}", path.display(), e)),
+            }
+        }
+    }
+
+    fn draw(&mut self) {
+        let (cols, rows) = self.cpu.get_gfx_size();
+        if let Err(err) = self.display.draw(self.cpu.get_gfx(), cols, rows) {
+            self.display_error.get_or_insert(err);
+        }
+        self.frame_drew = false;
+    }
+
+    fn present(&mut self) {
+        if self.frame_drew {
+            self.draw();
+        }
+    }
+
+    fn silence(&mut self) {
+        self.audio.set_playing(false);
+        self.sound_playing = false;
     }
 
     fn read_input(&mut self) -> bool {
         //returns true if should quit
-        for event in self.event_pump.poll_iter() {
+        for event in self.input.poll() {
             match event {
-                Event::Quit { .. } => return true,
-                Event::KeyDown {
-                    keycode: Some(Keycode::Escape),
-                    ..
-                } => return true,
-                Event::KeyDown {
-                    keycode: Some(key), ..
-                } => {
-                    if let Some(key_num) = select_key(key) {
-                        self.cpu.set_key(key_num, true);
-                    }
+                InputEvent::Quit => return true,
+                InputEvent::Break if self.debugger.is_paused() => {
+                    self.debugger.command("continue", &mut self.cpu);
+                }
+                InputEvent::Break if self.console.is_some() => self.debugger.pause(),
+                InputEvent::Break => {}
+                InputEvent::SaveState => self.save_state(),
+                // both would break the movie's sync
+                InputEvent::LoadState | InputEvent::Rewind(true) if self.movie.is_some() => {
+                    self.notify("not available while recording or replaying a movie");
+                }
+                InputEvent::LoadState => self.load_state(),
+                InputEvent::Rewind(held) => self.rewinding = held && self.rewind.is_some(),
+                InputEvent::Pause => {
+                    self.user_paused = !self.user_paused;
+                    self.display.set_status(if self.user_paused { "paused" } else { "" });
+                }
+                InputEvent::FrameAdvance if self.user_paused => self.advance = true,
+                InputEvent::FrameAdvance => {
+                    self.user_paused = true;
+                    self.display.set_status("paused");
+                }
+                InputEvent::SpeedUp => self.change_speed(true),
+                InputEvent::SpeedDown => self.change_speed(false),
+                InputEvent::FastForward(held) => self.fast_forward = held,
+                InputEvent::ToggleFullscreen => {
+                    self.display.toggle_fullscreen();
+                    self.draw();
                 }
-                Event::KeyUp {
-                    keycode: Some(key), ..
-                } => {
-                    if let Some(key_num) = select_key(key) {
-                        self.cpu.ignore_keypress = false;
-                        self.cpu.set_key(key_num, false);
-                    }
+                InputEvent::Refresh => self.draw(),
+                InputEvent::NextPalette => self.next_palette(),
+                InputEvent::NextSlot => {
+                    self.state_slot = (self.state_slot + 1) % NUM_STATE_SLOTS;
+                    self.notify(&format!("state slot {}", self.state_slot));
                 }
-                _ => {}
+                InputEvent::KeyDown(_) | InputEvent::KeyUp(_) if self.replaying() => {}
+                InputEvent::KeyDown(key_num) => self.key_events.push_back((key_num, true)),
+                InputEvent::KeyUp(key_num) => self.key_events.push_back((key_num, false)),
             }
         }
-        return false;
+        false
     }
 
-    pub fn run(&mut self, rom: &[u8; cpu::ROM_SIZE]) {
-        //TODO: move all cycling into cpu; use callbacks for drawing, sound, input, etc
-        self.cpu.load_rom(rom);
-        self.draw(); //init
-        let mut nanos_per_cycle = 1000000000 / CPU_FREQ;
-        let mut cycles = 0;
-        let mut time = SystemTime::now();
-        let mut last_timer_update = time.clone();
-        let mut sound_playing = false;
-        loop {
-            //do some time keeping
-            let cycle_start = SystemTime::now();
-            cycles = (cycles + 1) % CPU_FREQ;
-            if cycles == 0 {
-                //calculate seconds per CPU_FREQ
-                let actual_time = match cycle_start.duration_since(time) {
-                    Ok(t) => t.as_secs_f32(),
-                    _ => 0.0,
-                };
-                time = cycle_start;
-                // update nanos to try to more closely match
-                let actual_nanos = (actual_time * 1000000000 as f32) as u64 / CPU_FREQ;
-                let adjustment: i64 = ((1000000000 / CPU_FREQ) as i64 - actual_nanos as i64) / 2;
-                nanos_per_cycle = (nanos_per_cycle as i64 + adjustment) as u64;
+    fn update_audio(&mut self) {
+        let audio_state = (self.cpu.get_audio_pattern(), self.cpu.get_pitch());
+        if audio_state != self.audio_state {
+            self.audio.set_pattern(audio_state.0, audio_state.1);
+            self.audio_state = audio_state;
+        }
+        if self.sound_playing ^ self.cpu.should_play_sound() {
+            self.sound_playing = !self.sound_playing;
+            self.audio.set_playing(self.sound_playing);
+        }
+    }
+
+    fn read_console(&mut self) {
+        let lines: Vec<String> = match &self.console {
+            Some(console) => std::iter::from_fn(|| console.try_read_line()).collect(),
+            None => return,
+        };
+        for line in lines {
+            let out = self.debugger.command(&line, &mut self.cpu);
+            self.console_print(&out);
+        }
+    }
+
+    fn console_print(&self, text: &str) {
+        if let Some(console) = &self.console {
+            console.print(text);
+        }
+    }
+
+    // a message for the status line, kept there until the next speed update a second later
+    fn notify(&mut self, message: &str) {
+        self.display.set_status(message);
+        self.reset_speed();
+    }
+
+    // shows where execution stopped, once per pause
+    fn announce_pause(&mut self) {
+        if !self.announced_pause {
+            if let Some(reason) = self.debugger.take_reason() {
+                self.console_print(&format!("{}\n", reason));
             }
-            //update timers
-            if last_timer_update.elapsed().unwrap() >= Duration::from_nanos(1000000000 / TIMER_FREQ)
-            {
-                self.cpu.update_timers();
-                last_timer_update = cycle_start.clone();
+            self.console_print(&format!("paused at {}\n", self.debugger.location(&self.cpu)));
+            self.display.set_status("paused");
+            self.announced_pause = true;
+        }
+    }
+
+    fn announce_resume(&mut self) {
+        if self.announced_pause {
+            self.display.set_status("");
+            self.announced_pause = false;
+        }
+    }
+
+    // run returns the error for the caller to report
+    fn halt(&mut self, err: cpu::CpuError) {
+        self.present();
+        self.silence();
+        self.display.set_status(&format!("halted: {}", err));
+    }
+
+    // keeps the last frame on screen until the user quits, for after run returned
+    // Stop::Error
+    pub fn wait_for_quit(&mut self) {
+        while !self.read_input() {
+            self.clock.sleep(Duration::from_millis(1000 / TIMER_FREQ));
+        }
+    }
+
+    fn reset_speed(&mut self) {
+        self.speed = SpeedMeter { start: self.clock.now(), frames: 0, instructions: 0 };
+    }
+
+    // shows the measured speed about once a second
+    fn update_speed(&mut self) {
+        self.speed.frames += 1;
+        let elapsed = self.clock.now() - self.speed.start;
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        let secs = elapsed.as_secs_f64();
+        let status = format!(
+            "{:.0} ips, {:.0} fps{}",
+            self.speed.instructions as f64 / secs,
+            self.speed.frames as f64 / secs,
+            if self.fast_forward { ", fast forward" } else { "" }
+        );
+        self.display.set_status(&status);
+        self.reset_speed();
+    }
+
+    // goes back one snapshot, where it stays once the history runs out
+    fn rewind_frame(&mut self) {
+        let state = match self.rewind.as_mut().and_then(|rewind| rewind.step_back()) {
+            Some(state) => state,
+            None => return,
+        };
+        if self.cpu.load_state(state).is_ok() {
+            self.draw();
+        }
+    }
+
+    fn record_frame(&mut self) {
+        if let Some(rewind) = &mut self.rewind {
+            rewind.push(self.cpu.save_state());
+        }
+    }
+
+    // runs one instruction, Some when run should return
+    fn step(&mut self) -> Option<Stop> {
+        match self.cpu.perform_cycle() {
+            Ok(cpu::StepOutcome::Exited) => return Some(Stop::Exited),
+            Ok(cpu::StepOutcome::Drew) => self.frame_drew = true,
+            Ok(cpu::StepOutcome::Executed) => {}
+            Err(err) if self.on_error == ErrorPolicy::Skip => {
+                self.display.set_status(&format!("skipping: {}", err));
+            }
+            Err(err) if self.on_error == ErrorPolicy::Debug && self.console.is_some() => {
+                // pc is already past the faulting instruction, continuing skips it
+                self.console_print(&format!("error: {}\n", err));
+                self.debugger.pause();
             }
+            Err(err) => {
+                self.halt(err);
+                return Some(Stop::Error(err));
+            }
+        }
+        None
+    }
 
-            if self.read_input() {
-                return;
-            };
-            self.cpu.perform_cycle();
-            if self.cpu.just_drew() {
-                self.draw();
+    // true while paused in the debugger, after sleeping for a frame
+    fn paused(&mut self) -> bool {
+        if !self.debugger.should_break(&mut self.cpu) {
+            self.announce_resume();
+            return false;
+        }
+        self.announce_pause();
+        self.present(); // single steps show up right away
+        self.silence();
+        self.clock.sleep(Duration::from_millis(1000 / TIMER_FREQ));
+        true
+    }
+
+    // sleeps until next_frame and moves it on by one frame
+    fn wait_frame(&mut self, next_frame: &mut Duration) {
+        let frame_time = Duration::from_nanos(1000000000 / TIMER_FREQ);
+        let now = self.clock.now();
+        if *next_frame > now {
+            self.clock.sleep(*next_frame - now);
+            *next_frame += frame_time;
+        } else {
+            // running behind, don't try to catch up
+            *next_frame = now + frame_time;
+        }
+    }
+
+    // ipf instructions (or INTERPRETER_CYCLES worth of them) per 60 Hz frame, then the
+    // timers tick once and the screen is presented once. Input only changes between
+    // frames, one key at a time, so a run only depends on emulated time and not on how fast the host is.
+    pub fn run(&mut self) -> Stop {
+        self.draw(); //init
+        self.reset_speed();
+        if self.user_paused {
+            self.display.set_status("paused");
+        }
+        let mut next_frame = self.clock.now();
+        self.wait_frame(&mut next_frame);
+        let mut executed = 0;
+        let mut frame_started = false;
+        loop {
+            if let Some(err) = self.display_error.take() {
+                return Stop::Display(err);
             }
-            if sound_playing ^ self.cpu.should_play_sound() {
-                sound_playing = !sound_playing;
-                if sound_playing {
-                    self.audio.resume();
-                } else {
-                    self.audio.pause();
+            if !frame_started {
+                if self.read_input() {
+                    return Stop::Quit;
+                }
+                if self.rewinding {
+                    self.rewind_frame();
+                    self.silence();
+                    self.wait_frame(&mut next_frame);
+                    self.reset_speed();
+                    continue;
                 }
+                if self.user_paused && !std::mem::take(&mut self.advance) {
+                    self.read_console();
+                    self.silence();
+                    self.wait_frame(&mut next_frame);
+                    self.reset_speed();
+                    continue;
+                }
+                if self.max_frames.is_some_and(|max| self.frames_run >= max) {
+                    return Stop::Limit;
+                }
+                self.movie_frame();
+                frame_started = true;
+            }
+            self.read_console();
+            if self.paused() {
+                // time spent paused doesn't count as emulated time
+                if self.read_input() {
+                    return Stop::Quit;
+                }
+                next_frame = self.clock.now();
+                self.wait_frame(&mut next_frame);
+                self.reset_speed();
+                continue;
             }
 
-            let sleep_time = Duration::from_nanos(nanos_per_cycle)
-                .checked_sub(SystemTime::now().duration_since(cycle_start).unwrap());
-            if let Some(pos_sleep_time) = sleep_time {
-                std::thread::sleep(pos_sleep_time);
+            if self.max_instructions.is_some_and(|max| self.instructions_run >= max) {
+                self.present();
+                return Stop::Limit;
+            }
+            self.instructions_run += 1;
+            if let Some(stop) = self.step() {
+                self.present();
+                return stop;
+            }
+            self.speed.instructions += 1;
+            let (cost, frame_len) = if self.vip_timing {
+                (self.cpu.get_cycles() as u64, timing::INTERPRETER_CYCLES as u64)
+            } else {
+                (1, self.ipf)
+            };
+            executed += cost;
+            // FX0A idles until the frame ends, only the timers and input can change anything
+            if executed >= frame_len || self.cpu.waiting_for_key() {
+                executed = executed.saturating_sub(frame_len); // running over takes from the next frame
+                frame_started = false;
+                self.frames_run += 1;
+                self.cpu.update_timers();
+                self.record_frame();
+                self.update_audio();
+                self.update_speed();
+                if !self.fast_forward {
+                    self.present();
+                    self.wait_frame(&mut next_frame);
+                } else if self.clock.now() >= next_frame {
+                    // uncapped, only presenting as often as a normal frame would
+                    self.present();
+                    next_frame = self.clock.now() + Duration::from_nanos(1000000000 / TIMER_FREQ);
+                }
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction;
+    use crate::quirks::Quirks;
+
+    struct TestDisplay;
+
+    impl Display for TestDisplay {
+        fn draw(&mut self, _gfx: &[u8], _cols: usize, _rows: usize) -> Result<(), String> { Ok(()) }
+    }
+
+    struct TestAudio;
+
+    impl Audio for TestAudio {
+        fn set_playing(&mut self, _playing: bool) {}
+        fn set_pattern(&mut self, _pattern: [u8; cpu::AUDIO_PATTERN_SIZE], _pitch: u8) {}
+    }
+
+    // each poll gets the next frame's events
+    struct TestInput(VecDeque<Vec<InputEvent>>);
+
+    impl Input for TestInput {
+        fn poll(&mut self) -> Vec<InputEvent> { self.0.pop_front().unwrap_or_default() }
+    }
+
+    #[derive(Default)]
+    struct TestClock(Duration);
+
+    impl Clock for TestClock {
+        fn now(&self) -> Duration { self.0 }
+        fn sleep(&mut self, duration: Duration) { self.0 += duration; }
+    }
+
+    type TestEmulator = Emulator<TestDisplay, TestAudio, TestInput, TestClock>;
+
+    // sets the delay timer to FF, then counts up V1 forever
+    const COUNTER: [u8; 8] = [0x60, 0xFF, 0xF0, 0x15, 0x71, 0x01, 0x12, 0x04];
+
+    fn emulator(rom: &[u8], events: Vec<Vec<InputEvent>>) -> TestEmulator {
+        let mut cpu = cpu::CPU::new(Quirks::default());
+        cpu.load_rom(rom);
+        Emulator::new(cpu, TestDisplay, TestAudio, TestInput(events.into()), TestClock::default(), ErrorPolicy::Halt)
+    }
+
+    #[test]
+    fn frames_run_ipf_instructions() {
+        let mut emu = emulator(&COUNTER, vec![]);
+        emu.set_limits(Some(10), None);
+        assert_eq!(emu.run(), Stop::Limit);
+        assert_eq!((emu.frames_run, emu.instructions_run), (10, 10 * DEFAULT_IPF));
+        assert_eq!(emu.cpu().get_regs()[1] as u64, (10 * DEFAULT_IPF - 2) / 2);
+        // one timer tick per frame, at 60 Hz of emulated time
+        assert_eq!(emu.cpu().get_timers().0, 0xFF - 10);
+        assert!(emu.clock.0 >= Duration::from_millis(10 * 1000 / 60));
+
+        let mut emu = emulator(&COUNTER, vec![]);
+        emu.set_ipf(3);
+        emu.set_limits(Some(4), None);
+        emu.run();
+        assert_eq!(emu.instructions_run, 12);
+        // the instruction limit can stop mid-frame
+        let mut emu = emulator(&COUNTER, vec![]);
+        emu.set_limits(None, Some(21));
+        assert_eq!(emu.run(), Stop::Limit);
+        assert_eq!((emu.frames_run, emu.instructions_run), (2, 21));
+        assert_eq!(emu.cpu().get_timers().0, 0xFF - 2);
+    }
+
+    #[test]
+    fn vip_timing_frames_last_interpreter_cycles() {
+        let mut emu = emulator(&COUNTER, vec![]);
+        emu.set_vip_timing(true);
+        emu.set_limits(Some(10), None);
+        emu.run();
+        // 6XNN and FX15, then 7XNN and 1NNN until the 10 frames' cycles are used up, the
+        // one that runs over ending the last frame
+        let start = timing::vip_cycles(Instruction::SetImm(0, 0xFF), 0, false, false)
+            + timing::vip_cycles(Instruction::SetDelay(0), 0, false, false);
+        let add = timing::vip_cycles(Instruction::AddImm(1, 1), 0, false, false);
+        let jump = timing::vip_cycles(Instruction::Jump(0x204), 0, false, false);
+        let budget = 10 * timing::INTERPRETER_CYCLES;
+        let loops = (budget - start) / (add + jump);
+        assert!(start + loops * (add + jump) + add >= budget);
+        assert_eq!(emu.cpu().get_regs()[1], (loops + 1) as u8);
+        assert_eq!(emu.instructions_run, 2 + 2 * loops as u64 + 1);
+        assert_eq!(emu.cpu().get_timers().0, 0xFF - 10);
+
+        // a screen clear leaves room for little else in its frame
+        let mut emu = emulator(&[0x00, 0xE0, 0x12, 0x00], vec![]);
+        emu.set_vip_timing(true);
+        emu.set_limits(Some(30), None);
+        emu.run();
+        assert!(emu.instructions_run < 30 * 3);
+    }
+
+    #[test]
+    fn waiting_for_a_key_ends_the_frame() {
+        let mut emu = emulator(&[0xF0, 0x0A, 0x12, 0x00], vec![]);
+        emu.set_limits(Some(10), None);
+        emu.run();
+        assert_eq!(emu.instructions_run, 10);
+        // the press and the release each get a frame, then the loop starts over
+        let events = vec![vec![], vec![InputEvent::KeyDown(7), InputEvent::KeyUp(7)]];
+        let mut emu = emulator(&[0xF0, 0x0A, 0x12, 0x00], events);
+        emu.set_limits(Some(10), None);
+        emu.run();
+        assert_eq!(emu.cpu().get_regs()[0], 7);
+        assert_eq!(emu.instructions_run, 12);
+    }
+
+    #[test]
+    fn quitting_and_exiting() {
+        let mut emu = emulator(&COUNTER, vec![vec![InputEvent::Quit]]);
+        assert_eq!(emu.run(), Stop::Quit);
+        assert_eq!(emu.instructions_run, 0);
+        let mut emu = emulator(&[0x00, 0xFD], vec![]);
+        assert_eq!(emu.run(), Stop::Exited);
+        let mut emu = emulator(&[0x00, 0xEE], vec![]);
+        assert!(matches!(emu.run(), Stop::Error(_)));
+    }
+}
@@ -0,0 +1,75 @@
+// Everything the emulator needs from the outside world. The SDL implementation
+// lives in sdl.rs (behind the "sdl" feature); other frontends (headless runners,
+// bots, web) only have to implement these.
+
+use crate::cpu;
+use crate::palette::Palette;
+
+use std::time::{Duration, Instant};
+
+pub trait Display {
+    // gfx is cols * rows pixels, row-major, each one a bitmask of the lit planes. An error
+    // ends Emulator::run with Stop::Display.
+    fn draw(&mut self, gfx: &[u8], cols: usize, rows: usize) -> Result<(), String>;
+    // status line (window title or similar), frontends without one can ignore it
+    fn set_status(&mut self, _status: &str) {}
+    // between a window and the whole screen, for frontends that have a choice
+    fn toggle_fullscreen(&mut self) {}
+    // colors for the following draws
+    fn set_palette(&mut self, _palette: &Palette) {}
+}
+
+pub trait Audio {
+    fn set_playing(&mut self, playing: bool);
+    // XO-CHIP sound buffer and pitch register, see CPU::get_audio_pattern
+    fn set_pattern(&mut self, pattern: [u8; cpu::AUDIO_PATTERN_SIZE], pitch: u8);
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InputEvent {
+    KeyDown(usize), // hex key 0x0..=0xF
+    KeyUp(usize),
+    Break, // pause in the debugger, or resume if already paused
+    SaveState, // to the selected slot
+    LoadState,
+    NextSlot,
+    Rewind(bool), // held down, rewinding until released
+    Pause,        // toggle
+    FrameAdvance, // run one frame and pause again
+    SpeedUp,
+    SpeedDown,
+    FastForward(bool), // held down, running uncapped until released
+    ToggleFullscreen,
+    NextPalette,
+    Refresh, // the display lost its contents (resized, uncovered) and needs drawing again
+    Quit,
+}
+
+pub trait Input {
+    // everything that happened since the last call
+    fn poll(&mut self) -> Vec<InputEvent>;
+}
+
+pub trait Clock {
+    // monotonic time since some arbitrary starting point
+    fn now(&self) -> Duration;
+    fn sleep(&mut self, duration: Duration);
+}
+
+// wall clock time, for frontends running in real time
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> SystemClock { SystemClock { start: Instant::now() } }
+}
+
+impl Default for SystemClock {
+    fn default() -> SystemClock { SystemClock::new() }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration { self.start.elapsed() }
+    fn sleep(&mut self, duration: Duration) { std::thread::sleep(duration); }
+}
@@ -0,0 +1,216 @@
+// Decoded form of every CHIP-8, SCHIP and XO-CHIP instruction. Both the CPU and
+// the disassembler go through `Instruction::decode`, so they always agree on
+// what an opcode means; the assembler goes the other way with `encode`.
+//
+// x and y are register numbers, n a 4 bit value, nn a byte and nnn an address.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Sys(u16),              // 0NNN, machine code routine (treated as a jump)
+    ClearScreen,           // 00E0
+    Return,                // 00EE
+    ScrollDown(u8),        // 00CN, SCHIP
+    ScrollUp(u8),          // 00DN, XO-CHIP
+    ScrollRight,           // 00FB, SCHIP
+    ScrollLeft,            // 00FC, SCHIP
+    Exit,                  // 00FD, SCHIP
+    Lores,                 // 00FE, SCHIP
+    Hires,                 // 00FF, SCHIP
+    Jump(u16),             // 1NNN
+    Call(u16),             // 2NNN
+    SkipEqImm(u8, u8),     // 3XNN
+    SkipNeImm(u8, u8),     // 4XNN
+    SkipEq(u8, u8),        // 5XY0
+    SaveRange(u8, u8),     // 5XY2, XO-CHIP
+    LoadRange(u8, u8),     // 5XY3, XO-CHIP
+    SetImm(u8, u8),        // 6XNN
+    AddImm(u8, u8),        // 7XNN
+    Set(u8, u8),           // 8XY0
+    Or(u8, u8),            // 8XY1
+    And(u8, u8),           // 8XY2
+    Xor(u8, u8),           // 8XY3
+    Add(u8, u8),           // 8XY4
+    Sub(u8, u8),           // 8XY5
+    ShiftRight(u8, u8),    // 8XY6
+    SubReverse(u8, u8),    // 8XY7
+    ShiftLeft(u8, u8),     // 8XYE
+    SkipNe(u8, u8),        // 9XY0
+    SetI(u16),             // ANNN
+    JumpOffset(u16),       // BNNN (BXNN with quirks.jump_uses_vx)
+    Random(u8, u8),        // CXNN
+    Draw(u8, u8, u8),      // DXYN
+    SkipKey(u8),           // EX9E
+    SkipNotKey(u8),        // EXA1
+    SetILong(u16),         // F000 NNNN, XO-CHIP
+    SelectPlanes(u8),      // FN01, XO-CHIP
+    LoadAudio,             // F002, XO-CHIP
+    GetDelay(u8),          // FX07
+    WaitKey(u8),           // FX0A
+    SetDelay(u8),          // FX15
+    SetSound(u8),          // FX18
+    AddI(u8),              // FX1E
+    Font(u8),              // FX29
+    BigFont(u8),           // FX30, SCHIP
+    Bcd(u8),               // FX33
+    SetPitch(u8),          // FX3A, XO-CHIP
+    Store(u8),             // FX55
+    Load(u8),              // FX65
+    SaveFlags(u8),         // FX75, SCHIP
+    LoadFlags(u8),         // FX85, SCHIP
+}
+
+impl Instruction {
+    // next is the word following the opcode, only used by F000 NNNN.
+    // None for opcodes that don't mean anything on any of the supported platforms.
+    pub fn decode(opcode: u16, next: u16) -> Option<Instruction> {
+        use Instruction::*;
+        let x = ((opcode & 0xF00) >> 8) as u8;
+        let y = ((opcode & 0xF0) >> 4) as u8;
+        let n = (opcode & 0xF) as u8;
+        let nn = (opcode & 0xFF) as u8;
+        let nnn = opcode & 0xFFF;
+        Some(match opcode >> 12 {
+            0x0 => match opcode {
+                0x00E0 => ClearScreen,
+                0x00EE => Return,
+                0x00C0..=0x00CF => ScrollDown(n),
+                0x00D0..=0x00DF => ScrollUp(n),
+                0x00FB => ScrollRight,
+                0x00FC => ScrollLeft,
+                0x00FD => Exit,
+                0x00FE => Lores,
+                0x00FF => Hires,
+                _ => Sys(nnn),
+            },
+            0x1 => Jump(nnn),
+            0x2 => Call(nnn),
+            0x3 => SkipEqImm(x, nn),
+            0x4 => SkipNeImm(x, nn),
+            0x5 => match n {
+                0x0 => SkipEq(x, y),
+                0x2 => SaveRange(x, y),
+                0x3 => LoadRange(x, y),
+                _ => return None,
+            },
+            0x6 => SetImm(x, nn),
+            0x7 => AddImm(x, nn),
+            0x8 => match n {
+                0x0 => Set(x, y),
+                0x1 => Or(x, y),
+                0x2 => And(x, y),
+                0x3 => Xor(x, y),
+                0x4 => Add(x, y),
+                0x5 => Sub(x, y),
+                0x6 => ShiftRight(x, y),
+                0x7 => SubReverse(x, y),
+                0xE => ShiftLeft(x, y),
+                _ => return None,
+            },
+            0x9 if n == 0 => SkipNe(x, y),
+            0xA => SetI(nnn),
+            0xB => JumpOffset(nnn),
+            0xC => Random(x, nn),
+            0xD => Draw(x, y, n),
+            0xE => match nn {
+                0x9E => SkipKey(x),
+                0xA1 => SkipNotKey(x),
+                _ => return None,
+            },
+            0xF => match nn {
+                0x00 if x == 0 => SetILong(next),
+                0x01 => SelectPlanes(x),
+                0x02 if x == 0 => LoadAudio,
+                0x07 => GetDelay(x),
+                0x0A => WaitKey(x),
+                0x15 => SetDelay(x),
+                0x18 => SetSound(x),
+                0x1E => AddI(x),
+                0x29 => Font(x),
+                0x30 => BigFont(x),
+                0x33 => Bcd(x),
+                0x3A => SetPitch(x),
+                0x55 => Store(x),
+                0x65 => Load(x),
+                0x75 => SaveFlags(x),
+                0x85 => LoadFlags(x),
+                _ => return None,
+            },
+            _ => return None,
+        })
+    }
+
+    // inverse of decode, big-endian bytes as they sit in memory.
+    // Operands are truncated to their field widths (x and y 4 bits, nnn 12 bits, ...).
+    pub fn encode(&self) -> Vec<u8> {
+        use Instruction::*;
+        let xy = |base: u16, x: u8, y: u8, n: u16| base | (x as u16 & 0xF) << 8 | (y as u16 & 0xF) << 4 | n;
+        let xnn = |base: u16, x: u8, nn: u8| base | (x as u16 & 0xF) << 8 | nn as u16;
+        let fx = |x: u8, nn: u16| 0xF000 | (x as u16 & 0xF) << 8 | nn;
+        let opcode = match *self {
+            Sys(nnn) => nnn & 0xFFF,
+            ClearScreen => 0x00E0,
+            Return => 0x00EE,
+            ScrollDown(n) => 0x00C0 | (n as u16 & 0xF),
+            ScrollUp(n) => 0x00D0 | (n as u16 & 0xF),
+            ScrollRight => 0x00FB,
+            ScrollLeft => 0x00FC,
+            Exit => 0x00FD,
+            Lores => 0x00FE,
+            Hires => 0x00FF,
+            Jump(nnn) => 0x1000 | (nnn & 0xFFF),
+            Call(nnn) => 0x2000 | (nnn & 0xFFF),
+            SkipEqImm(x, nn) => xnn(0x3000, x, nn),
+            SkipNeImm(x, nn) => xnn(0x4000, x, nn),
+            SkipEq(x, y) => xy(0x5000, x, y, 0x0),
+            SaveRange(x, y) => xy(0x5000, x, y, 0x2),
+            LoadRange(x, y) => xy(0x5000, x, y, 0x3),
+            SetImm(x, nn) => xnn(0x6000, x, nn),
+            AddImm(x, nn) => xnn(0x7000, x, nn),
+            Set(x, y) => xy(0x8000, x, y, 0x0),
+            Or(x, y) => xy(0x8000, x, y, 0x1),
+            And(x, y) => xy(0x8000, x, y, 0x2),
+            Xor(x, y) => xy(0x8000, x, y, 0x3),
+            Add(x, y) => xy(0x8000, x, y, 0x4),
+            Sub(x, y) => xy(0x8000, x, y, 0x5),
+            ShiftRight(x, y) => xy(0x8000, x, y, 0x6),
+            SubReverse(x, y) => xy(0x8000, x, y, 0x7),
+            ShiftLeft(x, y) => xy(0x8000, x, y, 0xE),
+            SkipNe(x, y) => xy(0x9000, x, y, 0x0),
+            SetI(nnn) => 0xA000 | (nnn & 0xFFF),
+            JumpOffset(nnn) => 0xB000 | (nnn & 0xFFF),
+            Random(x, nn) => xnn(0xC000, x, nn),
+            Draw(x, y, n) => xy(0xD000, x, y, n as u16 & 0xF),
+            SkipKey(x) => xnn(0xE000, x, 0x9E),
+            SkipNotKey(x) => xnn(0xE000, x, 0xA1),
+            SetILong(_) => 0xF000,
+            SelectPlanes(n) => fx(n, 0x01),
+            LoadAudio => 0xF002,
+            GetDelay(x) => fx(x, 0x07),
+            WaitKey(x) => fx(x, 0x0A),
+            SetDelay(x) => fx(x, 0x15),
+            SetSound(x) => fx(x, 0x18),
+            AddI(x) => fx(x, 0x1E),
+            Font(x) => fx(x, 0x29),
+            BigFont(x) => fx(x, 0x30),
+            Bcd(x) => fx(x, 0x33),
+            SetPitch(x) => fx(x, 0x3A),
+            Store(x) => fx(x, 0x55),
+            Load(x) => fx(x, 0x65),
+            SaveFlags(x) => fx(x, 0x75),
+            LoadFlags(x) => fx(x, 0x85),
+        };
+        let mut bytes = opcode.to_be_bytes().to_vec();
+        if let SetILong(nnnn) = *self {
+            bytes.extend_from_slice(&nnnn.to_be_bytes());
+        }
+        bytes
+    }
+
+    // size in bytes, including the address word of F000 NNNN
+    pub fn size(&self) -> usize {
+        match self {
+            Instruction::SetILong(_) => 4,
+            _ => 2,
+        }
+    }
+}
@@ -0,0 +1,157 @@
+// Which keyboard keys press which of the 16 hex keys.
+//
+// Keys go by their SDL names. "W" or "Up" is whichever key produces that symbol in the
+// current layout, "scancode:W" is the key where W is on a US keyboard, whatever the
+// layout puts there. The default layout uses scancodes, so the 4x4 block of
+// 1234/QWER/ASDF/ZXCV stays in the same place on AZERTY, Dvorak, ...
+//
+// Game controllers use the names from SDL's controller mappings: "button:a",
+// "button:dpup", ... and "axis:leftx+" or "axis:lefty-" for a stick pushed far enough
+// in that direction. By default the d-pad and left stick are 5/7/8/9 (up, left, down,
+// right), A is 6 and B is 4.
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Binding {
+    Keycode(String),
+    Scancode(String),
+    Button(String),     // game controller button
+    Axis(String, bool), // game controller axis, true for the positive direction
+}
+
+impl Binding {
+    pub fn parse(name: &str) -> Binding {
+        if let Some(name) = name.strip_prefix("scancode:") {
+            return Binding::Scancode(name.to_string());
+        }
+        if let Some(name) = name.strip_prefix("button:") {
+            return Binding::Button(name.to_string());
+        }
+        if let Some(axis) = name.strip_prefix("axis:") {
+            // triggers only go one way, a direction is optional for them
+            return match axis.strip_suffix('-') {
+                Some(axis) => Binding::Axis(axis.to_string(), false),
+                None => Binding::Axis(axis.strip_suffix('+').unwrap_or(axis).to_string(), true),
+            };
+        }
+        Binding::Keycode(name.to_string())
+    }
+}
+
+// US positions of the hex keys, indexed by hex key:
+//   1 2 3 C      1 2 3 4
+//   4 5 6 D      Q W E R
+//   7 8 9 E      A S D F
+//   A 0 B F      Z X C V
+const DEFAULT_LAYOUT: [&str; 16] =
+    ["X", "1", "2", "3", "Q", "W", "E", "A", "S", "D", "Z", "C", "4", "R", "F", "V"];
+
+const DEFAULT_CONTROLLER: [(usize, &str); 10] = [
+    (0x5, "button:dpup"),
+    (0x5, "axis:lefty-"),
+    (0x7, "button:dpleft"),
+    (0x7, "axis:leftx-"),
+    (0x8, "button:dpdown"),
+    (0x8, "axis:lefty+"),
+    (0x9, "button:dpright"),
+    (0x9, "axis:leftx+"),
+    (0x6, "button:a"),
+    (0x4, "button:b"),
+];
+
+pub const DEFAULT_AXIS_THRESHOLD: f32 = 0.5;
+
+// hex key and the keys that press it, replacing its default ones
+pub type KeyOverrides = Vec<(usize, Vec<Binding>)>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    pub keys: [Vec<Binding>; 16], // indexed by hex key, any of them presses it
+    pub axis_threshold: f32,      // fraction of the full range an axis has to be pushed
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        let mut keys = DEFAULT_LAYOUT.map(|name| vec![Binding::Scancode(name.to_string())]);
+        for (key, name) in DEFAULT_CONTROLLER {
+            keys[key].push(Binding::parse(name));
+        }
+        Keymap { keys, axis_threshold: DEFAULT_AXIS_THRESHOLD }
+    }
+}
+
+// what the rom database's "keys" name: the arrow keys, d-pad and left stick for the
+// directions and the A and B buttons
+const ACTIONS: [(&str, &[&str]); 6] = [
+    ("up", &["Up", "button:dpup", "axis:lefty-"]),
+    ("down", &["Down", "button:dpdown", "axis:lefty+"]),
+    ("left", &["Left", "button:dpleft", "axis:leftx-"]),
+    ("right", &["Right", "button:dpright", "axis:leftx+"]),
+    ("a", &["button:a"]),
+    ("b", &["button:b"]),
+];
+
+impl Keymap {
+    pub fn apply(&mut self, overrides: &KeyOverrides) {
+        for (key, bindings) in overrides {
+            self.keys[*key] = bindings.clone();
+        }
+    }
+
+    // moves the bindings of a rom database action ("up", "a", ...) to a hex key. Actions
+    // without any (the second player's, ...) are ignored.
+    pub fn bind_action(&mut self, action: &str, key: usize) {
+        let names = match ACTIONS.iter().find(|(name, _)| *name == action) {
+            Some((_, names)) => names,
+            None => return,
+        };
+        for binding in names.iter().map(|name| Binding::parse(name)) {
+            for bindings in self.keys.iter_mut() {
+                bindings.retain(|bound| *bound != binding);
+            }
+            self.keys[key].push(binding);
+        }
+    }
+}
+
+// "A", "a" or "0xA"
+pub fn parse_hex_key(name: &str) -> Option<usize> {
+    let digit = name.strip_prefix("0x").unwrap_or(name);
+    if digit.len() != 1 {
+        return None;
+    }
+    usize::from_str_radix(digit, 16).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bindings() {
+        let cases = [
+            ("W", Binding::Keycode("W".to_string())),
+            ("Up", Binding::Keycode("Up".to_string())),
+            ("scancode:W", Binding::Scancode("W".to_string())),
+            ("button:dpup", Binding::Button("dpup".to_string())),
+            ("button:a", Binding::Button("a".to_string())),
+            ("axis:leftx+", Binding::Axis("leftx".to_string(), true)),
+            ("axis:lefty-", Binding::Axis("lefty".to_string(), false)),
+            ("axis:triggerleft", Binding::Axis("triggerleft".to_string(), true)), // no direction
+        ];
+        for (name, binding) in cases {
+            assert_eq!(Binding::parse(name), binding, "{}", name);
+        }
+    }
+
+    #[test]
+    fn actions_move_their_bindings() {
+        let mut keymap = Keymap::default();
+        keymap.bind_action("up", 0x1);
+        let up = Binding::Button("dpup".to_string());
+        assert!(keymap.keys[0x1].contains(&up));
+        assert!(keymap.keys[0x1].contains(&Binding::Axis("lefty".to_string(), false)));
+        assert!(!keymap.keys[0x5].contains(&up));
+        keymap.bind_action("player2up", 0x2); // not an action, nothing changes
+        assert_eq!(keymap.keys[0x2], vec![Binding::Scancode("2".to_string())]);
+    }
+}
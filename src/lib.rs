@@ -0,0 +1,28 @@
+// chip-8 (plus SCHIP and XO-CHIP) interpreter core. Nothing in here depends on
+// SDL, frontends plug in through the traits in `frontend`; the SDL one is in
+// `sdl`, behind the "sdl" feature.
+
+pub mod asm;
+pub mod config;
+pub mod cpu;
+pub mod debugger;
+pub mod disasm;
+pub mod emulator;
+pub mod frontend;
+pub mod instruction;
+pub mod keymap;
+pub mod movie;
+pub mod octo;
+pub mod palette;
+pub mod quirks;
+pub mod rewind;
+pub mod rom;
+pub mod romdb;
+#[cfg(feature = "sdl")]
+pub mod sdl;
+pub mod state;
+pub mod timing;
+
+pub use cpu::CPU;
+pub use emulator::Emulator;
+pub use quirks::Quirks;
@@ -1,22 +1,390 @@
-mod cpu;
-mod emulator;
+use chip8::disasm;
+use chip8::emulator::ErrorPolicy;
+use chip8::quirks;
 
-use std::{env, fs};
+use std::env;
 
-fn read_rom(path: String) -> [u8; cpu::ROM_SIZE] {
-    let vector: Vec<u8> = fs::read(&path).unwrap();
-    let mut rom: [u8; cpu::ROM_SIZE] = [0u8; cpu::ROM_SIZE];
-    rom[0..vector.len()].copy_from_slice(&vector);
-    rom
+const EXIT_ERROR: i32 = 1;
+const EXIT_USAGE: i32 = 64; // bad arguments, as in chip8-headless
+
+#[cfg_attr(not(feature = "sdl"), allow(dead_code))]
+struct Options {
+    quirks: Option<quirks::Quirks>, // picked from the rom database if not given
+    on_error: ErrorPolicy,
+    debug: bool,             // start paused in the debugger
+    paused: bool,            // start paused, P resumes
+    scale: u32,              // initial window size in pixels per lores pixel
+    fullscreen: bool,
+    palette: Option<String>, // overrides the config file's
+    config: Option<String>,  // instead of config::default_path
+    breakpoints: Vec<u16>,
+    load_state: Option<String>,
+    rewind_secs: u64, // 0 turns rewinding off
+    ipf: Option<u64>, // instructions per frame
+    vip_timing: bool, // approximate COSMAC VIP instruction timing instead of ipf
+    record: Option<String>,
+    replay: Option<String>,
+    seed: Option<u64>, // random by default
+    load_address: Option<u16>, // where the rom goes and starts running
+    no_database: bool,         // don't use the rom database, only guess the platform
+    rom_file: String,
+}
+
+struct DisasmOptions {
+    syntax: disasm::Syntax,
+    start: u16, // address the rom is loaded at
+    rom_file: String,
+}
+
+struct AsmOptions {
+    origin: u16, // address the output will be loaded at
+    source_file: String,
+    out_file: String,
+}
+
+enum Command {
+    Run(Options),
+    Disasm(DisasmOptions),
+    Asm(AsmOptions),
+}
+
+fn parse_args(args: &[String]) -> Result<Command, String> {
+    match args.first().map(|arg| arg.as_str()) {
+        Some("run") => Ok(Command::Run(parse_run_args(&args[1..])?)),
+        Some("disasm") => Ok(Command::Disasm(parse_disasm_args(&args[1..])?)),
+        Some("asm") => Ok(Command::Asm(parse_asm_args(&args[1..])?)),
+        _ => Ok(Command::Run(parse_run_args(args)?)),
+    }
+}
+
+fn parse_disasm_args(args: &[String]) -> Result<DisasmOptions, String> {
+    let mut syntax = disasm::Syntax::Cowgod;
+    let mut start = chip8::cpu::ROM_START as u16;
+    let mut rom_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--octo" => syntax = disasm::Syntax::Octo,
+            "--start" => start = chip8::debugger::parse_addr(args.next().map(|s| s.as_str()))?,
+            _ if rom_file.is_none() && !arg.starts_with("--") => rom_file = Some(arg.to_string()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    Ok(DisasmOptions { syntax, start, rom_file: rom_file.ok_or("No rom given")? })
+}
+
+fn parse_asm_args(args: &[String]) -> Result<AsmOptions, String> {
+    let mut origin = chip8::cpu::ROM_START as u16;
+    let mut source_file = None;
+    let mut out_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" | "--out" => out_file = Some(args.next().ok_or("-o needs a file")?.to_string()),
+            "--start" => origin = chip8::debugger::parse_addr(args.next().map(|s| s.as_str()))?,
+            _ if source_file.is_none() && !arg.starts_with('-') => source_file = Some(arg.to_string()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    let source_file: String = source_file.ok_or("No source given")?;
+    // game.asm -> game.ch8 next to it
+    let out_file = out_file.unwrap_or_else(|| {
+        std::path::Path::new(&source_file).with_extension("ch8").to_string_lossy().into_owned()
+    });
+    Ok(AsmOptions { origin, source_file, out_file })
+}
+
+fn parse_run_args(args: &[String]) -> Result<Options, String> {
+    let mut quirks = None;
+    let mut on_error = ErrorPolicy::Halt;
+    let mut debug = false;
+    let mut paused = false;
+    let mut scale = 12;
+    let mut fullscreen = false;
+    let mut palette = None;
+    let mut config = None;
+    let mut breakpoints = vec![];
+    let mut load_state = None;
+    let mut rewind_secs = 10;
+    let mut ipf = None;
+    let mut vip_timing = false;
+    let mut record = None;
+    let mut replay = None;
+    let mut seed = None;
+    let mut load_address = None;
+    let mut no_database = false;
+    let mut rom_file = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--quirks" => {
+                let name = args.next().ok_or("--quirks needs a value")?;
+                quirks = Some(
+                    quirks::Quirks::from_name(name).ok_or(format!("Unknown quirk profile: {}", name))?,
+                );
+            }
+            "--on-error" => {
+                let name = args.next().ok_or("--on-error needs a value")?;
+                on_error = ErrorPolicy::from_name(name)
+                    .ok_or(format!("Unknown error policy: {}", name))?;
+            }
+            "--debug" => debug = true,
+            "--paused" => paused = true,
+            "--scale" => {
+                let val = args.next().ok_or("--scale needs a number")?;
+                scale = val.parse().map_err(|_| format!("--scale: not a number: {}", val))?;
+            }
+            "--fullscreen" => fullscreen = true,
+            "--palette" => palette = Some(args.next().ok_or("--palette needs a name")?.clone()),
+            "--config" => config = Some(args.next().ok_or("--config needs a file")?.clone()),
+            "--load-state" => load_state = Some(args.next().ok_or("--load-state needs a file")?.clone()),
+            "--rewind" => {
+                let secs = args.next().ok_or("--rewind needs a number of seconds")?;
+                rewind_secs = secs.parse().map_err(|_| format!("--rewind: not a number: {}", secs))?;
+            }
+            "--ipf" => {
+                let val = args.next().ok_or("--ipf needs a number")?;
+                ipf = Some(val.parse().map_err(|_| format!("--ipf: not a number: {}", val))?);
+            }
+            "--ips" => {
+                let val = args.next().ok_or("--ips needs a number")?;
+                let ips: u64 = val.parse().map_err(|_| format!("--ips: not a number: {}", val))?;
+                ipf = Some((ips + 30) / 60); // nearest whole number per 60 Hz frame
+            }
+            "--vip-timing" => vip_timing = true,
+            "--record" => record = Some(args.next().ok_or("--record needs a file")?.clone()),
+            "--replay" => replay = Some(args.next().ok_or("--replay needs a file")?.clone()),
+            "--seed" => {
+                let val = args.next().ok_or("--seed needs a number")?;
+                seed = Some(val.parse().map_err(|_| format!("--seed: not a number: {}", val))?);
+            }
+            "--load-address" => {
+                load_address = Some(chip8::debugger::parse_addr(args.next().map(|s| s.as_str()))?)
+            }
+            "--no-database" => no_database = true,
+            "--break" => {
+                breakpoints.push(chip8::debugger::parse_addr(args.next().map(|s| s.as_str()))?)
+            }
+            _ if rom_file.is_none() && !arg.starts_with("--") => rom_file = Some(arg.to_string()),
+            _ => return Err(format!("Unexpected argument: {}", arg)),
+        }
+    }
+    // movies start from power on and can't be mixed
+    if replay.is_some() && (record.is_some() || load_state.is_some()) {
+        return Err("--replay can't be combined with --record or --load-state".to_string());
+    }
+    if record.is_some() && load_state.is_some() {
+        return Err("--record can't be combined with --load-state".to_string());
+    }
+    Ok(Options {
+        quirks,
+        on_error,
+        debug,
+        paused,
+        scale,
+        fullscreen,
+        palette,
+        config,
+        breakpoints,
+        load_state,
+        rewind_secs,
+        ipf,
+        vip_timing,
+        record,
+        replay,
+        seed,
+        load_address,
+        no_database,
+        rom_file: rom_file.ok_or("No rom given")?,
+    })
+}
+
+fn disasm(options: DisasmOptions) -> Result<(), String> {
+    // the load address decides where Intel HEX records go
+    let load_address = (options.start as usize).max(chip8::cpu::ROM_START);
+    let rom = chip8::rom::read_rom(&options.rom_file, load_address)
+        .map_err(|e| format!("{}: {}", options.rom_file, e))?;
+    print!("{}", disasm::listing(&rom, options.start, options.syntax));
+    Ok(())
+}
+
+fn asm(options: AsmOptions) -> Result<(), String> {
+    let source = std::fs::read_to_string(&options.source_file)
+        .map_err(|e| format!("{}: {}", options.source_file, e))?;
+    let rom = chip8::asm::assemble(&source, options.origin)
+        .map_err(|e| format!("{}: {}", options.source_file, e))?;
+    std::fs::write(&options.out_file, rom).map_err(|e| format!("{}: {}", options.out_file, e))
+}
+
+// the config file's rom database, or the bundled one
+#[cfg(feature = "sdl")]
+fn load_database(config: &chip8::config::Config) -> Result<chip8::romdb::Database, String> {
+    match &config.database {
+        Some(path) => chip8::romdb::Database::load(path),
+        None => Ok(chip8::romdb::Database::bundled()),
+    }
+}
+
+// --palette, then the rom's own colors, then the config file's palette
+#[cfg(feature = "sdl")]
+fn choose_palette(
+    config: &mut chip8::config::Config,
+    name: Option<&String>,
+    info: &chip8::romdb::RomInfo,
+) -> Result<usize, String> {
+    let rom_palette = chip8::palette::from_colors(info.title.as_deref().unwrap_or("rom"), &info.colors);
+    let name = match (name, rom_palette) {
+        (Some(name), _) => name,
+        (None, Some(rom_palette)) => {
+            config.palettes.push(rom_palette);
+            return Ok(config.palettes.len() - 1);
+        }
+        (None, None) => match &config.palette {
+            Some(name) => name,
+            None => return Ok(0),
+        },
+    };
+    config.find_palette(name).ok_or_else(|| {
+        format!("Unknown palette: {} (known: {})", name, config.palette_names().join(", "))
+    })
+}
+
+// the default config file is optional, one given with --config isn't
+#[cfg(feature = "sdl")]
+fn load_config(path: &Option<String>) -> Result<chip8::config::Config, String> {
+    use chip8::config;
+    match (path, config::default_path()) {
+        (Some(path), _) => config::load(path.as_ref()),
+        (None, Some(path)) if path.exists() => config::load(&path),
+        _ => Ok(config::Config::default()),
+    }
+}
+
+#[cfg(feature = "sdl")]
+fn run(mut options: Options) -> Result<(), String> {
+    let mut config = load_config(&options.config)?;
+    let movie = match &options.replay {
+        Some(path) => {
+            let data = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+            Some(chip8::movie::Movie::from_bytes(&data).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        None => None,
+    };
+    // a movie brings its own quirks and load address
+    let movie_address = movie.as_ref().map(|movie| movie.load_address);
+    let fixed_address = movie_address.or(options.load_address).map(usize::from);
+    let mut load_address = fixed_address.unwrap_or(chip8::cpu::ROM_START);
+    let data = chip8::rom::read_file(&options.rom_file).map_err(|e| format!("{}: {}", options.rom_file, e))?;
+    // .8o files are Octo source, their :breakpoints become breakpoints
+    let mut program = chip8::rom::decode_program(&options.rom_file, data.clone(), load_address)?;
+    // settings given on the command line win over the database's
+    let database = if options.no_database { None } else { Some(load_database(&config)?) };
+    let info = chip8::romdb::identify(database.as_ref(), &program.rom, load_address);
+    println!("{}", info);
+    let palette = choose_palette(&mut config, options.palette.as_ref(), &info)?;
+    // the database's start address, decoded again for it since text roms place their bytes
+    // by address (and Octo source refuses to move)
+    match (fixed_address, info.load_address) {
+        (None, Some(addr)) if addr != load_address => {
+            program = chip8::rom::decode_program(&options.rom_file, data, addr)?;
+            load_address = addr;
+        }
+        _ => {}
+    }
+    options.breakpoints.extend(program.breakpoints.iter().map(|&(_, addr)| addr));
+    let rom = program.rom;
+    let quirks = movie.as_ref().map_or(options.quirks.unwrap_or(info.quirks), |movie| movie.quirks);
+    let mut cpu = chip8::CPU::new(quirks);
+    cpu.load_rom_at(&rom, load_address);
+    if let Some(path) = &options.load_state {
+        let state = std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?;
+        cpu.load_state(&state).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    let (display, audio, mut input) = chip8::sdl::init(options.scale, options.fullscreen)?;
+    let mut keymap = chip8::keymap::Keymap::default();
+    for (action, key) in &info.keys {
+        keymap.bind_action(action, *key);
+    }
+    let keymap = config.keymap(keymap, &options.rom_file, cpu.get_rom_hash());
+    input.set_keymap(&keymap).map_err(|e| format!("keys: {}", e))?;
+    let clock = chip8::frontend::SystemClock::new();
+    let mut emu = chip8::Emulator::new(cpu, display, audio, input, clock, options.on_error);
+    emu.set_console(chip8::debugger::Console::stdin());
+    emu.set_state_path(options.rom_file.into());
+    emu.set_palettes(config.palettes, palette);
+    if options.rewind_secs > 0 {
+        // one snapshot per 60 Hz frame
+        let snapshots = options.rewind_secs as usize * 60;
+        emu.set_rewind(chip8::rewind::Rewind::new(snapshots, chip8::rewind::DEFAULT_MAX_BYTES));
+    }
+    // before recording, which keeps the speed it starts with. A replay uses the movie's.
+    emu.set_ipf(options.ipf.unwrap_or(info.ipf));
+    emu.set_vip_timing(options.vip_timing);
+    if let (Some(movie), Some(path)) = (movie, &options.replay) {
+        emu.play_movie(movie).map_err(|e| format!("{}: {}", path, e))?;
+    } else if options.record.is_some() {
+        emu.record_movie(options.seed.unwrap_or_else(rand::random));
+    } else if let Some(seed) = options.seed {
+        emu.cpu_mut().seed_rng(seed);
+    }
+    for &addr in &options.breakpoints {
+        emu.debugger_mut().add_breakpoint(addr);
+    }
+    if options.debug {
+        emu.debugger_mut().pause();
+    }
+    emu.set_paused(options.paused);
+    let stop = emu.run();
+    if let (Some(movie), Some(path)) = (emu.take_movie(), &options.record) {
+        std::fs::write(path, movie.to_bytes()).map_err(|e| format!("{}: {}", path, e))?;
+        println!("recorded {} frames to {}", movie.frames.len(), path);
+    }
+    match stop {
+        chip8::emulator::Stop::Error(err) => {
+            eprintln!("halted: {}", err);
+            if options.on_error == chip8::emulator::ErrorPolicy::Debug {
+                eprint!("{}", emu.cpu().dump_registers());
+            }
+            emu.wait_for_quit();
+        }
+        chip8::emulator::Stop::Display(err) => return Err(format!("display: {}", err)),
+        _ => {}
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sdl"))]
+fn run(_options: Options) -> Result<(), String> {
+    Err("built without the \"sdl\" feature, there is no window to run in".to_string())
 }
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        println!("Usage: {} path/to/rom", args[0]);
-        return;
+    let command = match parse_args(&args[1..]) {
+        Ok(command) => command,
+        Err(msg) => {
+            eprintln!("{}", msg);
+            eprintln!(
+                "Usage: {0} [run] [--quirks vip|schip|xochip] [--on-error halt|skip|debug] \
+                 [--debug] [--paused] [--break ADDR]... [--scale N] [--fullscreen] \
+                 [--palette NAME] [--config FILE] \
+                 [--load-state FILE] [--rewind SECS] [--ipf N | --ips N | --vip-timing] \
+                 [--record FILE | --replay FILE] [--seed N] [--load-address ADDR] [--no-database] \
+                 path/to/rom|game.8o|-\n       \
+                 {0} disasm [--octo] [--start ADDR] path/to/rom\n       \
+                 {0} asm [--start ADDR] [-o out.ch8] path/to/source.asm",
+                args[0]
+            );
+            std::process::exit(EXIT_USAGE);
+        }
+    };
+    let result = match command {
+        Command::Run(options) => run(options),
+        Command::Disasm(options) => disasm(options),
+        Command::Asm(options) => asm(options),
+    };
+    if let Err(msg) = result {
+        eprintln!("{}", msg);
+        std::process::exit(EXIT_ERROR);
     }
-    let rom_file = args[1].to_string();
-    let mut emu = emulator::Emulator::new();
-    emu.run(&read_rom(rom_file));
 }
@@ -0,0 +1,105 @@
+// Input movies: everything needed to replay a run bit for bit. A run is deterministic
+// given the rom, where it was loaded, the quirks, the rng seed and how many
+// instructions execute per frame, so on top of those a movie only has to store which
+// keys were down during each frame.
+//
+// Layout, big-endian: magic, version, rom SHA-1, load address, seed, quirks,
+// instructions per frame, frame count, then one 16 bit key mask per frame (bit n is
+// key n).
+
+use crate::quirks::Quirks;
+use crate::state::{self, RomHash, StateError};
+
+use std::error::Error;
+use std::fmt;
+
+pub const MAGIC: &[u8; 4] = b"C8MV";
+pub const VERSION: u16 = 1;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MovieError {
+    NotAMovie,
+    UnsupportedVersion(u16),
+    Truncated,
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::NotAMovie => write!(f, "not a movie file"),
+            MovieError::UnsupportedVersion(version) => {
+                write!(f, "movie version {} is not supported (expected {})", version, VERSION)
+            }
+            MovieError::Truncated => write!(f, "movie file is truncated"),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<StateError> for MovieError {
+    // the reader only ever runs out of data
+    fn from(_: StateError) -> MovieError { MovieError::Truncated }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Movie {
+    pub rom_hash: RomHash,
+    pub load_address: u16,
+    pub seed: u64,
+    pub quirks: Quirks,
+    pub ipf: u64,         // instructions per frame, 0 for VIP timing
+    pub frames: Vec<u16>, // key mask per frame
+}
+
+impl Movie {
+    pub fn new(rom_hash: RomHash, load_address: u16, seed: u64, quirks: Quirks, ipf: u64) -> Movie {
+        Movie { rom_hash, load_address, seed, quirks, ipf, frames: vec![] }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = state::Writer::default();
+        out.bytes(MAGIC);
+        out.u16(VERSION);
+        out.bytes(&self.rom_hash);
+        out.u16(self.load_address);
+        out.u64(self.seed);
+        out.quirks(&self.quirks);
+        out.u64(self.ipf);
+        out.u32(self.frames.len() as u32);
+        for &mask in &self.frames {
+            out.u16(mask);
+        }
+        out.buf
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut input = state::Reader::new(data);
+        if input.bytes(MAGIC.len()).map_err(|_| MovieError::NotAMovie)? != MAGIC {
+            return Err(MovieError::NotAMovie);
+        }
+        let version = input.u16()?;
+        if version != VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let mut movie = Movie::new(input.array()?, input.u16()?, input.u64()?, input.quirks()?, input.u64()?);
+        for _ in 0..input.u32()? {
+            movie.frames.push(input.u16()?);
+        }
+        Ok(movie)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut movie = Movie::new([7; 20], 0x600, 42, Quirks::schip(), 70000);
+        movie.frames = vec![0, 1 << 5, 1 << 5 | 1 << 0xF, 0];
+        assert_eq!(Movie::from_bytes(&movie.to_bytes()), Ok(movie.clone()));
+        let bytes = movie.to_bytes();
+        assert_eq!(Movie::from_bytes(&bytes[..bytes.len() - 1]), Err(MovieError::Truncated));
+    }
+}
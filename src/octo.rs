@@ -0,0 +1,1045 @@
+// Compiler for Octo (https://github.com/JohnEarnest/Octo), the language most SCHIP and
+// XO-CHIP homebrew is written in. It works like Octo's own compiler: a single pass over
+// the tokens, with references to labels that aren't defined yet patched in once they are.
+//
+// Supported: labels, `:const`, `:alias`, `:calc` (evaluated right to left, no precedence,
+// like Octo), `:macro`, `:stringmode`, `:unpack`, `:next`, `:org`, `:byte`, `:pointer`,
+// `:call`, `:assert`, `:breakpoint`, `if ... then`, `if ... begin ... else ... end`,
+// `loop ... while ... again`, every instruction statement and bare numbers as data
+// (sprites). `:monitor` and `:proto` are accepted and ignored.
+
+use crate::cpu::{MEM_SIZE, ROM_START};
+use crate::instruction::Instruction;
+
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+
+// words that can't be used as names
+const KEYWORDS: &[&str] = &[
+    ":", ":alias", ":assert", ":breakpoint", ":byte", ":calc", ":call", ":const", ":macro",
+    ":monitor", ":next", ":org", ":pointer", ":proto", ":stringmode", ":unpack", ";", "again",
+    "audio", "bcd", "begin", "bighex", "buzzer", "clear", "delay", "else", "end", "exit", "hex",
+    "hires", "i", "if", "jump", "jump0", "key", "load", "loadflags", "long", "loop", "lores",
+    "native", "pitch", "plane", "random", "return", "save", "saveflags", "scroll-down",
+    "scroll-left", "scroll-right", "scroll-up", "sprite", "then", "while", "-key",
+];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompileError {
+    pub line: usize, // 1 based
+    pub col: usize,  // 1 based
+    pub msg: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result { write!(f, "{}:{}: {}", self.line, self.col, self.msg) }
+}
+
+impl Error for CompileError {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Program {
+    pub rom: Vec<u8>,                    // loaded at 0x200
+    pub breakpoints: Vec<(String, u16)>, // from :breakpoint
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    quoted: bool, // a "string", text is without the quotes
+    line: usize,
+    col: usize,
+}
+
+fn error(tok: &Token, msg: impl Into<String>) -> CompileError {
+    CompileError { line: tok.line, col: tok.col, msg: msg.into() }
+}
+
+fn tokenize(source: &str) -> Result<Vec<Token>, CompileError> {
+    let mut tokens = vec![];
+    for (line_num, line) in source.lines().enumerate() {
+        let chars: Vec<char> = line.chars().collect();
+        let mut pos = 0;
+        while pos < chars.len() {
+            let start = pos;
+            let mut tok = Token { text: String::new(), quoted: false, line: line_num + 1, col: start + 1 };
+            match chars[pos] {
+                c if c.is_whitespace() => {
+                    pos += 1;
+                    continue;
+                }
+                '#' => break,
+                '{' | '}' => {
+                    tok.text.push(chars[pos]);
+                    pos += 1;
+                }
+                '"' => {
+                    tok.quoted = true;
+                    pos += 1;
+                    loop {
+                        match chars.get(pos) {
+                            None => return Err(error(&tok, "Missing a closing \" in a string literal.")),
+                            Some('"') => break,
+                            Some('\\') => {
+                                let escaped = match chars.get(pos + 1) {
+                                    Some('n') => '\n',
+                                    Some('t') => '\t',
+                                    Some('0') => '\0',
+                                    Some(&c) => c,
+                                    None => '\\',
+                                };
+                                tok.text.push(escaped);
+                                pos += 2;
+                            }
+                            Some(&c) => {
+                                tok.text.push(c);
+                                pos += 1;
+                            }
+                        }
+                    }
+                    pos += 1;
+                }
+                _ => {
+                    while pos < chars.len() && !chars[pos].is_whitespace() && !"{}".contains(chars[pos]) {
+                        tok.text.push(chars[pos]);
+                        pos += 1;
+                    }
+                }
+            }
+            tokens.push(tok);
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.chars().all(|c| c.is_ascii_digit()) && !digits.is_empty() {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+fn register_number(text: &str) -> Option<u8> {
+    let digit = text.strip_prefix('v').or_else(|| text.strip_prefix('V'))?;
+    if digit.len() == 1 { u8::from_str_radix(digit, 16).ok() } else { None }
+}
+
+// how a value gets written into an instruction that has already been emitted
+#[derive(Clone, Copy, Debug)]
+enum Patch {
+    Addr,                   // low 12 bits of the opcode at the address
+    Word,                   // a whole 16 bit word
+    UnpackHigh(Option<u8>), // high byte of the address, after a nibble for :unpack n
+    UnpackLow,
+}
+
+// open blocks, closed by end/again
+enum Frame {
+    If(usize, Token), // address of the jump to patch
+    Else(usize, Token),
+    Loop(usize, Vec<usize>, Token), // start, while jumps to patch
+}
+
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+    calls: usize,
+}
+
+// a parsed `vx op rhs` condition, emitted as a skip once it's known how it's used
+struct Condition {
+    x: u8,
+    op: Token,
+    rhs: Option<Rhs>,
+}
+
+enum Rhs {
+    Reg(u8),
+    Byte(u8),
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>, // macro expansions get pushed to the front
+    last: Token,             // for errors at the end of the input
+    rom: Vec<u8>,            // all of memory, only ROM_START.. ends up in the program
+    written: Vec<bool>,
+    here: usize,
+    end: usize, // one past the highest byte written
+    labels: HashMap<String, u16>,
+    consts: HashMap<String, f64>,
+    aliases: HashMap<String, u8>,
+    protos: HashMap<String, Vec<(usize, Patch, Token)>>, // forward references
+    frames: Vec<Frame>,
+    macros: HashMap<String, Macro>,
+    stringmodes: HashMap<String, HashMap<char, (usize, Vec<Token>)>>, // char -> (value, body)
+    breakpoints: Vec<(String, u16)>,
+}
+
+impl Compiler {
+    fn new(tokens: Vec<Token>) -> Compiler {
+        let mut written = vec![false; MEM_SIZE];
+        // the jump to main
+        written[ROM_START] = true;
+        written[ROM_START + 1] = true;
+        Compiler {
+            last: Token { text: String::new(), quoted: false, line: 1, col: 1 },
+            tokens: tokens.into(),
+            rom: vec![0; MEM_SIZE],
+            written,
+            here: ROM_START + 2,
+            end: ROM_START + 2,
+            labels: HashMap::new(),
+            consts: HashMap::new(),
+            aliases: HashMap::new(),
+            protos: HashMap::new(),
+            frames: vec![],
+            macros: HashMap::new(),
+            stringmodes: HashMap::new(),
+            breakpoints: vec![],
+        }
+    }
+
+    fn next(&mut self) -> Result<Token, CompileError> {
+        match self.tokens.pop_front() {
+            Some(tok) => {
+                self.last = tok.clone();
+                Ok(tok)
+            }
+            None => Err(error(&self.last, "Unexpected end of file.")),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool { self.tokens.front().is_some_and(|tok| !tok.quoted && tok.text == text) }
+
+    fn expect(&mut self, text: &str) -> Result<Token, CompileError> {
+        let tok = self.next()?;
+        if tok.quoted || tok.text != text {
+            return Err(error(&tok, format!("Expected '{}', got '{}'.", text, tok.text)));
+        }
+        Ok(tok)
+    }
+
+    fn reg_of(&self, tok: &Token) -> Option<u8> {
+        if tok.quoted {
+            return None;
+        }
+        register_number(&tok.text).or_else(|| self.aliases.get(&tok.text).copied())
+    }
+
+    fn register(&mut self) -> Result<u8, CompileError> {
+        let tok = self.next()?;
+        self.reg_of(&tok).ok_or_else(|| error(&tok, format!("Expected a register, got '{}'.", tok.text)))
+    }
+
+    fn is_name(&self, tok: &Token) -> bool {
+        !tok.quoted
+            && !tok.text.is_empty()
+            && parse_number(&tok.text).is_none()
+            && register_number(&tok.text).is_none()
+            && !KEYWORDS.contains(&tok.text.as_str())
+    }
+
+    fn name(&mut self) -> Result<Token, CompileError> {
+        let tok = self.next()?;
+        if !self.is_name(&tok) {
+            return Err(error(&tok, format!("'{}' can't be used as a name.", tok.text)));
+        }
+        Ok(tok)
+    }
+
+    fn check_undefined(&self, tok: &Token) -> Result<(), CompileError> {
+        if self.labels.contains_key(&tok.text) || self.consts.contains_key(&tok.text) {
+            return Err(error(tok, format!("The name '{}' has already been defined.", tok.text)));
+        }
+        Ok(())
+    }
+
+    // number, constant, defined label or { calc }; None for a name that isn't defined (yet)
+    fn lookup(&mut self, tok: &Token) -> Result<Option<f64>, CompileError> {
+        if tok.quoted {
+            return Err(error(tok, format!("Expected a value, got the string \"{}\".", tok.text)));
+        }
+        if tok.text == "{" {
+            return self.calc_block().map(Some);
+        }
+        Ok(parse_number(&tok.text)
+            .or_else(|| self.consts.get(&tok.text).copied())
+            .or_else(|| self.labels.get(&tok.text).map(|&addr| addr as f64)))
+    }
+
+    fn immediate(&mut self, tok: &Token, min: i64, max: i64, bits: u32) -> Result<i64, CompileError> {
+        let value = self
+            .lookup(tok)?
+            .ok_or_else(|| error(tok, format!("Undefined name '{}'.", tok.text)))? as i64;
+        if value < min || value > max {
+            return Err(error(tok, format!("Value '{}' cannot fit in {} bits.", tok.text, bits)));
+        }
+        Ok(value)
+    }
+
+    // negative bytes are stored as two's complement
+    fn byte(&mut self) -> Result<u8, CompileError> {
+        let tok = self.next()?;
+        Ok(self.immediate(&tok, -128, 255, 8)? as u8)
+    }
+
+    fn nibble(&mut self) -> Result<u8, CompileError> {
+        let tok = self.next()?;
+        Ok(self.immediate(&tok, 0, 15, 4)? as u8)
+    }
+
+    fn emit_byte(&mut self, tok: &Token, byte: u8) -> Result<(), CompileError> {
+        if self.here >= MEM_SIZE {
+            return Err(error(tok, "Program is too large to fit in memory."));
+        }
+        if self.written[self.here] {
+            return Err(error(tok, format!("Data overlap. Address {:#06X} has already been defined.", self.here)));
+        }
+        self.rom[self.here] = byte;
+        self.written[self.here] = true;
+        self.here += 1;
+        self.end = self.end.max(self.here);
+        Ok(())
+    }
+
+    // returns the address the instruction was written at, for patching
+    fn emit(&mut self, tok: &Token, instr: Instruction) -> Result<usize, CompileError> {
+        let addr = self.here;
+        for byte in instr.encode() {
+            self.emit_byte(tok, byte)?;
+        }
+        Ok(addr)
+    }
+
+    fn patch(&mut self, tok: &Token, at: usize, patch: Patch, value: i64) -> Result<(), CompileError> {
+        let max = match patch {
+            Patch::Addr | Patch::UnpackHigh(Some(_)) => 0xFFF,
+            Patch::Word | Patch::UnpackHigh(None) | Patch::UnpackLow => 0xFFFF,
+        };
+        if value < 0 || value > max {
+            let bits = if max == 0xFFF { 12 } else { 16 };
+            return Err(error(tok, format!("Value '{}' for '{}' cannot fit in {} bits.", value, tok.text, bits)));
+        }
+        let (high, low) = ((value >> 8) as u8, value as u8);
+        match patch {
+            Patch::Addr => {
+                self.rom[at] = self.rom[at] & 0xF0 | high;
+                self.rom[at + 1] = low;
+            }
+            Patch::Word => {
+                self.rom[at] = high;
+                self.rom[at + 1] = low;
+            }
+            Patch::UnpackHigh(nibble) => self.rom[at] = nibble.unwrap_or(0) << 4 | high,
+            Patch::UnpackLow => self.rom[at] = low,
+        }
+        Ok(())
+    }
+
+    // fills in a value now if it is known, otherwise once the label gets defined
+    fn reference(&mut self, tok: &Token, at: usize, patch: Patch) -> Result<(), CompileError> {
+        match self.lookup(tok)? {
+            Some(value) => self.patch(tok, at, patch, value as i64),
+            None if self.is_name(tok) => {
+                self.protos.entry(tok.text.clone()).or_default().push((at, patch, tok.clone()));
+                Ok(())
+            }
+            None => Err(error(tok, format!("Expected a label, got '{}'.", tok.text))),
+        }
+    }
+
+    fn define_label(&mut self, tok: &Token, addr: usize) -> Result<(), CompileError> {
+        self.check_undefined(tok)?;
+        if addr >= MEM_SIZE {
+            return Err(error(tok, "Program is too large to fit in memory."));
+        }
+        self.labels.insert(tok.text.clone(), addr as u16);
+        for (at, patch, ref_tok) in self.protos.remove(&tok.text).unwrap_or_default() {
+            self.patch(&ref_tok, at, patch, addr as i64)?;
+        }
+        Ok(())
+    }
+
+    // { expr } with the opening brace already taken
+    fn calc_block(&mut self) -> Result<f64, CompileError> {
+        let value = self.calc_expr()?;
+        self.expect("}")?;
+        Ok(value)
+    }
+
+    // Octo has no precedence, binary operators are applied right to left
+    fn calc_expr(&mut self) -> Result<f64, CompileError> {
+        let lhs = self.calc_term()?;
+        if self.tokens.is_empty() || self.peek_is("}") || self.peek_is(")") {
+            return Ok(lhs);
+        }
+        let op = self.next()?;
+        let rhs = self.calc_expr()?;
+        let int = |v: f64| v as i64;
+        let bool = |b: bool| if b { 1.0 } else { 0.0 };
+        Ok(match op.text.as_str() {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "&" => (int(lhs) & int(rhs)) as f64,
+            "|" => (int(lhs) | int(rhs)) as f64,
+            "^" => (int(lhs) ^ int(rhs)) as f64,
+            "<<" => (int(lhs) << int(rhs)) as f64,
+            ">>" => (int(lhs) >> int(rhs)) as f64,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "<" => bool(lhs < rhs),
+            "<=" => bool(lhs <= rhs),
+            "==" => bool(lhs == rhs),
+            "!=" => bool(lhs != rhs),
+            ">=" => bool(lhs >= rhs),
+            ">" => bool(lhs > rhs),
+            _ => return Err(error(&op, format!("Unknown binary operator '{}'.", op.text))),
+        })
+    }
+
+    fn calc_term(&mut self) -> Result<f64, CompileError> {
+        let tok = self.next()?;
+        if tok.quoted {
+            return Err(error(&tok, "Strings can only be used with strlen."));
+        }
+        let unary = |f: fn(f64) -> f64, this: &mut Compiler| this.calc_term().map(f);
+        Ok(match tok.text.as_str() {
+            "(" => {
+                let value = self.calc_expr()?;
+                self.expect(")")?;
+                value
+            }
+            "-" => unary(|v| -v, self)?,
+            "~" => unary(|v| !(v as i64) as f64, self)?,
+            "!" => unary(|v| if v == 0.0 { 1.0 } else { 0.0 }, self)?,
+            "sin" => unary(f64::sin, self)?,
+            "cos" => unary(f64::cos, self)?,
+            "tan" => unary(f64::tan, self)?,
+            "exp" => unary(f64::exp, self)?,
+            "log" => unary(f64::ln, self)?,
+            "abs" => unary(f64::abs, self)?,
+            "sqrt" => unary(f64::sqrt, self)?,
+            "sign" => unary(|v| if v == 0.0 { 0.0 } else { v.signum() }, self)?,
+            "ceil" => unary(f64::ceil, self)?,
+            "floor" => unary(f64::floor, self)?,
+            "@" => {
+                let addr = self.calc_term()? as i64;
+                *self.rom.get(addr as usize).ok_or_else(|| error(&tok, "Address out of range."))? as f64
+            }
+            "strlen" => {
+                let string = self.next()?;
+                if !string.quoted {
+                    return Err(error(&string, "strlen needs a string."));
+                }
+                string.text.chars().count() as f64
+            }
+            "HERE" => self.here as f64,
+            "PI" => std::f64::consts::PI,
+            "E" => std::f64::consts::E,
+            _ => self.lookup(&tok)?.ok_or_else(|| error(&tok, format!("Undefined name '{}'.", tok.text)))?,
+        })
+    }
+
+    fn condition(&mut self) -> Result<Condition, CompileError> {
+        let x = self.register()?;
+        let op = self.next()?;
+        let rhs = match op.text.as_str() {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => {
+                let tok = self.next()?;
+                Some(match self.reg_of(&tok) {
+                    Some(y) => Rhs::Reg(y),
+                    None => Rhs::Byte(self.immediate(&tok, -128, 255, 8)? as u8),
+                })
+            }
+            _ => return Err(error(&op, format!("Unknown comparison operator '{}'.", op.text))),
+        };
+        Ok(Condition { x, op, rhs })
+    }
+
+    // emits whatever skips the next instruction when the condition (negated or not) is false
+    fn emit_condition(&mut self, cond: &Condition, negate: bool) -> Result<(), CompileError> {
+        use Instruction::*;
+        let op = match (cond.op.text.as_str(), negate) {
+            (op, false) => op,
+            ("==", true) => "!=",
+            ("!=", true) => "==",
+            ("key", true) => "-key",
+            ("-key", true) => "key",
+            ("<", true) => ">=",
+            (">=", true) => "<",
+            (">", true) => "<=",
+            (_, true) => ">",
+        };
+        let tok = &cond.op;
+        let x = cond.x;
+        match (op, &cond.rhs) {
+            ("key", _) => self.emit(tok, SkipNotKey(x))?,
+            ("-key", _) => self.emit(tok, SkipKey(x))?,
+            ("==", Some(Rhs::Reg(y))) => self.emit(tok, SkipNe(x, *y))?,
+            ("==", Some(Rhs::Byte(nn))) => self.emit(tok, SkipNeImm(x, *nn))?,
+            ("!=", Some(Rhs::Reg(y))) => self.emit(tok, SkipEq(x, *y))?,
+            ("!=", Some(Rhs::Byte(nn))) => self.emit(tok, SkipEqImm(x, *nn))?,
+            // the rest compare through vf: vf := rhs, then a subtraction leaves the borrow flag in it
+            (_, Some(rhs)) => {
+                match rhs {
+                    Rhs::Reg(y) => self.emit(tok, Set(0xF, *y))?,
+                    Rhs::Byte(nn) => self.emit(tok, SetImm(0xF, *nn))?,
+                };
+                match op {
+                    // vf := x - rhs, vf is 1 when x >= rhs
+                    "<" => {
+                        self.emit(tok, SubReverse(0xF, x))?;
+                        self.emit(tok, SkipEqImm(0xF, 1))?
+                    }
+                    ">=" => {
+                        self.emit(tok, SubReverse(0xF, x))?;
+                        self.emit(tok, SkipEqImm(0xF, 0))?
+                    }
+                    // vf := rhs - x, vf is 1 when x <= rhs
+                    ">" => {
+                        self.emit(tok, Sub(0xF, x))?;
+                        self.emit(tok, SkipEqImm(0xF, 1))?
+                    }
+                    _ => {
+                        self.emit(tok, Sub(0xF, x))?;
+                        self.emit(tok, SkipEqImm(0xF, 0))?
+                    }
+                }
+            }
+            (_, None) => unreachable!(),
+        };
+        Ok(())
+    }
+
+    // a jump that gets its target later, returns where to patch it
+    fn forward_jump(&mut self, tok: &Token) -> Result<usize, CompileError> { self.emit(tok, Instruction::Jump(0)) }
+
+    fn register_statement(&mut self, x: u8) -> Result<(), CompileError> {
+        use Instruction::*;
+        let op = self.next()?;
+        let rhs = self.next()?;
+        let y = self.reg_of(&rhs);
+        let instr = match (op.text.as_str(), y) {
+            (":=", Some(y)) => Set(x, y),
+            (":=", None) if rhs.text == "random" => Random(x, self.byte()?),
+            (":=", None) if rhs.text == "key" => WaitKey(x),
+            (":=", None) if rhs.text == "delay" => GetDelay(x),
+            (":=", None) => SetImm(x, self.immediate(&rhs, -128, 255, 8)? as u8),
+            ("+=", Some(y)) => Add(x, y),
+            ("+=", None) => AddImm(x, self.immediate(&rhs, -128, 255, 8)? as u8),
+            ("-=", Some(y)) => Sub(x, y),
+            ("-=", None) => AddImm(x, (self.immediate(&rhs, -128, 255, 8)? as u8).wrapping_neg()),
+            ("|=", Some(y)) => Or(x, y),
+            ("&=", Some(y)) => And(x, y),
+            ("^=", Some(y)) => Xor(x, y),
+            ("=-", Some(y)) => SubReverse(x, y),
+            (">>=", Some(y)) => ShiftRight(x, y),
+            ("<<=", Some(y)) => ShiftLeft(x, y),
+            ("|=", None) | ("&=", None) | ("^=", None) | ("=-", None) | (">>=", None) | ("<<=", None) => {
+                return Err(error(&rhs, format!("Expected a register, got '{}'.", rhs.text)))
+            }
+            _ => return Err(error(&op, format!("Unrecognized operator '{}'.", op.text))),
+        };
+        self.emit(&op, instr)?;
+        Ok(())
+    }
+
+    fn i_statement(&mut self) -> Result<(), CompileError> {
+        use Instruction::*;
+        let op = self.next()?;
+        match op.text.as_str() {
+            ":=" => {
+                let rhs = self.next()?;
+                match rhs.text.as_str() {
+                    "long" => {
+                        let target = self.next()?;
+                        let at = self.emit(&rhs, SetILong(0))?;
+                        self.reference(&target, at + 2, Patch::Word)?;
+                    }
+                    "hex" => {
+                        let x = self.register()?;
+                        self.emit(&rhs, Font(x))?;
+                    }
+                    "bighex" => {
+                        let x = self.register()?;
+                        self.emit(&rhs, BigFont(x))?;
+                    }
+                    _ => {
+                        let at = self.emit(&rhs, SetI(0))?;
+                        self.reference(&rhs, at, Patch::Addr)?;
+                    }
+                }
+            }
+            "+=" => {
+                let x = self.register()?;
+                self.emit(&op, AddI(x))?;
+            }
+            _ => return Err(error(&op, format!("Unrecognized operator '{}'.", op.text))),
+        }
+        Ok(())
+    }
+
+    fn expand_macro(&mut self, tok: &Token) -> Result<(), CompileError> {
+        // CALLS counts the earlier expansions
+        let mac = self.macros.get_mut(&tok.text).unwrap();
+        let calls = mac.calls;
+        mac.calls += 1;
+        let (arg_names, body) = (mac.args.clone(), mac.body.clone());
+        let mut args = HashMap::new();
+        for name in arg_names {
+            args.insert(name, self.next()?);
+        }
+        let expanded: Vec<Token> = body
+            .into_iter()
+            .map(|body_tok| match args.get(&body_tok.text) {
+                Some(arg) if !body_tok.quoted => arg.clone(),
+                _ if !body_tok.quoted && body_tok.text == "CALLS" => Token { text: calls.to_string(), ..body_tok },
+                _ => body_tok,
+            })
+            .collect();
+        for expanded_tok in expanded.into_iter().rev() {
+            self.tokens.push_front(expanded_tok);
+        }
+        Ok(())
+    }
+
+    fn expand_stringmode(&mut self, tok: &Token) -> Result<(), CompileError> {
+        let string = self.next()?;
+        if !string.quoted {
+            return Err(error(&string, format!("{} needs a string.", tok.text)));
+        }
+        let mut expanded = vec![];
+        for (index, c) in string.text.chars().enumerate() {
+            let (value, body) = self.stringmodes[&tok.text]
+                .get(&c)
+                .ok_or_else(|| error(&string, format!("String mode '{}' can't handle '{}'.", tok.text, c)))?;
+            for body_tok in body {
+                let text = match body_tok.text.as_str() {
+                    _ if body_tok.quoted => None,
+                    "CHAR" => Some(c as u32 as usize),
+                    "INDEX" => Some(index),
+                    "VALUE" => Some(*value),
+                    _ => None,
+                };
+                expanded.push(match text {
+                    Some(n) => Token { text: n.to_string(), ..body_tok.clone() },
+                    None => body_tok.clone(),
+                });
+            }
+        }
+        for expanded_tok in expanded.into_iter().rev() {
+            self.tokens.push_front(expanded_tok);
+        }
+        Ok(())
+    }
+
+    // tokens up to the matching }, with the { already taken
+    fn block_body(&mut self) -> Result<Vec<Token>, CompileError> {
+        let mut depth = 1;
+        let mut body = vec![];
+        loop {
+            let tok = self.next()?;
+            if !tok.quoted && tok.text == "{" {
+                depth += 1;
+            } else if !tok.quoted && tok.text == "}" {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(body);
+                }
+            }
+            body.push(tok);
+        }
+    }
+
+    fn directive(&mut self, tok: &Token) -> Result<(), CompileError> {
+        use Instruction::*;
+        match tok.text.as_str() {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here)?;
+            }
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(&name, self.here + 1)?;
+            }
+            ":const" => {
+                let name = self.name()?;
+                self.check_undefined(&name)?;
+                let value_tok = self.next()?;
+                let value = self
+                    .lookup(&value_tok)?
+                    .ok_or_else(|| error(&value_tok, format!("Undefined name '{}'.", value_tok.text)))?;
+                self.consts.insert(name.text, value);
+            }
+            ":calc" => {
+                // unlike :const, :calc may redefine a constant
+                let name = self.name()?;
+                if self.labels.contains_key(&name.text) {
+                    return Err(error(&name, format!("The name '{}' has already been defined.", name.text)));
+                }
+                self.expect("{")?;
+                let value = self.calc_block()?;
+                self.consts.insert(name.text, value);
+            }
+            ":alias" => {
+                let name = self.name()?;
+                let reg_tok = self.next()?;
+                let reg = match self.reg_of(&reg_tok) {
+                    Some(reg) => reg,
+                    None => self.immediate(&reg_tok, 0, 15, 4)? as u8,
+                };
+                self.aliases.insert(name.text, reg);
+            }
+            ":byte" => {
+                let byte = self.byte()?;
+                self.emit_byte(tok, byte)?;
+            }
+            ":pointer" => {
+                let target = self.next()?;
+                let at = self.here;
+                self.emit_byte(tok, 0)?;
+                self.emit_byte(tok, 0)?;
+                self.reference(&target, at, Patch::Word)?;
+            }
+            ":org" => {
+                let addr_tok = self.next()?;
+                self.here = self.immediate(&addr_tok, ROM_START as i64, MEM_SIZE as i64 - 1, 16)? as usize;
+            }
+            ":call" => {
+                let target = self.next()?;
+                let at = self.emit(tok, Call(0))?;
+                self.reference(&target, at, Patch::Addr)?;
+            }
+            ":unpack" => {
+                let first = self.next()?;
+                let nibble = if first.text == "long" { None } else { Some(self.immediate(&first, 0, 15, 4)? as u8) };
+                let target = self.next()?;
+                let high = self.emit(tok, SetImm(0, 0))?;
+                self.reference(&target, high + 1, Patch::UnpackHigh(nibble))?;
+                let low = self.emit(tok, SetImm(1, 0))?;
+                self.reference(&target, low + 1, Patch::UnpackLow)?;
+            }
+            ":breakpoint" => {
+                let name = self.next()?;
+                self.breakpoints.push((name.text, self.here as u16));
+            }
+            ":assert" => {
+                let mut message = None;
+                if self.tokens.front().is_some_and(|next| next.quoted) {
+                    message = Some(self.next()?.text);
+                }
+                self.expect("{")?;
+                if self.calc_block()? == 0.0 {
+                    let msg = match message {
+                        Some(message) => format!("Assertion failed: {}", message),
+                        None => "Assertion failed.".to_string(),
+                    };
+                    return Err(error(tok, msg));
+                }
+            }
+            ":monitor" => {
+                // only meaningful to Octo's own debugger
+                for _ in 0..2 {
+                    if self.next()?.text == "{" {
+                        self.calc_block()?;
+                    }
+                }
+            }
+            ":proto" => {
+                self.next()?;
+            }
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = vec![];
+                loop {
+                    let arg = self.next()?;
+                    if arg.text == "{" {
+                        break;
+                    }
+                    args.push(arg.text);
+                }
+                let body = self.block_body()?;
+                self.macros.insert(name.text, Macro { args, body, calls: 0 });
+            }
+            ":stringmode" => {
+                let name = self.name()?;
+                let alphabet = self.next()?;
+                if !alphabet.quoted {
+                    return Err(error(&alphabet, ":stringmode needs a string of characters."));
+                }
+                self.expect("{")?;
+                let body = self.block_body()?;
+                let mode = self.stringmodes.entry(name.text).or_default();
+                for (value, c) in alphabet.text.chars().enumerate() {
+                    mode.insert(c, (value, body.clone()));
+                }
+            }
+            _ => return Err(error(tok, format!("Unknown directive '{}'.", tok.text))),
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, tok: Token) -> Result<(), CompileError> {
+        use Instruction::*;
+        if tok.quoted {
+            return Err(error(&tok, format!("Unexpected string \"{}\".", tok.text)));
+        }
+        if let Some(x) = self.reg_of(&tok) {
+            return self.register_statement(x);
+        }
+        let no_operands = match tok.text.as_str() {
+            "clear" => Some(ClearScreen),
+            "return" | ";" => Some(Return),
+            "exit" => Some(Exit),
+            "hires" => Some(Hires),
+            "lores" => Some(Lores),
+            "scroll-right" => Some(ScrollRight),
+            "scroll-left" => Some(ScrollLeft),
+            "audio" => Some(LoadAudio),
+            _ => None,
+        };
+        if let Some(instr) = no_operands {
+            self.emit(&tok, instr)?;
+            return Ok(());
+        }
+        match tok.text.as_str() {
+            text if text.starts_with(':') => self.directive(&tok)?,
+            "scroll-down" => {
+                let n = self.nibble()?;
+                self.emit(&tok, ScrollDown(n))?;
+            }
+            "scroll-up" => {
+                let n = self.nibble()?;
+                self.emit(&tok, ScrollUp(n))?;
+            }
+            "plane" => {
+                let n_tok = self.next()?;
+                let n = self.immediate(&n_tok, 0, 3, 2)? as u8;
+                self.emit(&tok, SelectPlanes(n))?;
+            }
+            "bcd" | "saveflags" | "loadflags" => {
+                let x = self.register()?;
+                let instr = match tok.text.as_str() {
+                    "bcd" => Bcd(x),
+                    "saveflags" => SaveFlags(x),
+                    _ => LoadFlags(x),
+                };
+                self.emit(&tok, instr)?;
+            }
+            "save" | "load" => {
+                let x = self.register()?;
+                let instr = if self.peek_is("-") {
+                    self.next()?;
+                    let y = self.register()?;
+                    if tok.text == "save" { SaveRange(x, y) } else { LoadRange(x, y) }
+                } else if tok.text == "save" {
+                    Store(x)
+                } else {
+                    Load(x)
+                };
+                self.emit(&tok, instr)?;
+            }
+            "sprite" => {
+                let x = self.register()?;
+                let y = self.register()?;
+                let n = self.nibble()?;
+                self.emit(&tok, Draw(x, y, n))?;
+            }
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let x = self.register()?;
+                let instr = match tok.text.as_str() {
+                    "delay" => SetDelay(x),
+                    "buzzer" => SetSound(x),
+                    _ => SetPitch(x),
+                };
+                self.emit(&tok, instr)?;
+            }
+            "i" => self.i_statement()?,
+            "jump" | "jump0" | "native" => {
+                let target = self.next()?;
+                let instr = match tok.text.as_str() {
+                    "jump" => Jump(0),
+                    "jump0" => JumpOffset(0),
+                    _ => Sys(0),
+                };
+                let at = self.emit(&tok, instr)?;
+                self.reference(&target, at, Patch::Addr)?;
+            }
+            "if" => {
+                let cond = self.condition()?;
+                let then = self.next()?;
+                match then.text.as_str() {
+                    "then" => self.emit_condition(&cond, false)?,
+                    "begin" => {
+                        self.emit_condition(&cond, true)?;
+                        let at = self.forward_jump(&tok)?;
+                        self.frames.push(Frame::If(at, tok));
+                    }
+                    _ => return Err(error(&then, format!("Expected 'then' or 'begin', got '{}'.", then.text))),
+                }
+            }
+            "else" => match self.frames.pop() {
+                Some(Frame::If(at, _)) => {
+                    let end = self.forward_jump(&tok)?;
+                    self.patch(&tok, at, Patch::Addr, self.here as i64)?;
+                    self.frames.push(Frame::Else(end, tok));
+                }
+                _ => return Err(error(&tok, "This 'else' does not have a matching 'begin'.")),
+            },
+            "end" => match self.frames.pop() {
+                Some(Frame::If(at, _)) | Some(Frame::Else(at, _)) => {
+                    self.patch(&tok, at, Patch::Addr, self.here as i64)?
+                }
+                _ => return Err(error(&tok, "This 'end' does not have a matching 'begin'.")),
+            },
+            "loop" => self.frames.push(Frame::Loop(self.here, vec![], tok)),
+            "while" => {
+                let cond = self.condition()?;
+                self.emit_condition(&cond, true)?;
+                let at = self.forward_jump(&tok)?;
+                match self.frames.iter_mut().rev().find(|frame| matches!(frame, Frame::Loop(..))) {
+                    Some(Frame::Loop(_, whiles, _)) => whiles.push(at),
+                    _ => return Err(error(&tok, "This 'while' is not within a loop.")),
+                }
+            }
+            "again" => match self.frames.pop() {
+                Some(Frame::Loop(start, whiles, _)) => {
+                    let at = self.emit(&tok, Jump(0))?;
+                    self.patch(&tok, at, Patch::Addr, start as i64)?;
+                    for at in whiles {
+                        self.patch(&tok, at, Patch::Addr, self.here as i64)?;
+                    }
+                }
+                _ => return Err(error(&tok, "This 'again' does not have a matching 'loop'.")),
+            },
+            _ if self.macros.contains_key(&tok.text) => self.expand_macro(&tok)?,
+            _ if self.stringmodes.contains_key(&tok.text) => self.expand_stringmode(&tok)?,
+            // a bare label is a call, a bare value is a byte of data (sprites and such)
+            _ if self.labels.contains_key(&tok.text) => {
+                let at = self.emit(&tok, Call(0))?;
+                self.reference(&tok, at, Patch::Addr)?;
+            }
+            _ if self.is_name(&tok) && !self.consts.contains_key(&tok.text) => {
+                let at = self.emit(&tok, Call(0))?;
+                self.reference(&tok, at, Patch::Addr)?;
+            }
+            text if KEYWORDS.contains(&text) => return Err(error(&tok, format!("Unexpected '{}'.", text))),
+            _ => {
+                let byte = self.immediate(&tok, -128, 255, 8)? as u8;
+                self.emit_byte(&tok, byte)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<Program, CompileError> {
+        if let Some(frame) = self.frames.pop() {
+            let (tok, msg) = match frame {
+                Frame::If(_, tok) | Frame::Else(_, tok) => (tok, "This 'begin' does not have a matching 'end'."),
+                Frame::Loop(_, _, tok) => (tok, "This 'loop' does not have a matching 'again'."),
+            };
+            return Err(error(&tok, msg));
+        }
+        // report the earliest reference to something that never got defined
+        let undefined = self.protos.values().flatten().min_by_key(|(_, _, tok)| (tok.line, tok.col));
+        if let Some((_, _, tok)) = undefined {
+            return Err(error(tok, format!("Undefined name '{}'.", tok.text)));
+        }
+        let main = *self
+            .labels
+            .get("main")
+            .ok_or_else(|| error(&self.last, "This program does not define a subroutine called 'main'."))?;
+        if main > 0xFFF {
+            return Err(error(&self.last, "The subroutine 'main' must be in the first 4 KiB of memory."));
+        }
+        self.rom[ROM_START..ROM_START + 2].copy_from_slice(&Instruction::Jump(main).encode());
+        Ok(Program { rom: self.rom[ROM_START..self.end].to_vec(), breakpoints: self.breakpoints })
+    }
+}
+
+pub fn compile(source: &str) -> Result<Program, CompileError> {
+    let mut compiler = Compiler::new(tokenize(source)?);
+    while !compiler.tokens.is_empty() {
+        let tok = compiler.next()?;
+        compiler.statement(tok)?;
+    }
+    compiler.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rom(source: &str) -> Vec<u8> { compile(source).unwrap().rom }
+
+    // rom[0..2] is always the jump to main
+    #[test]
+    fn labels_and_forward_references() {
+        assert_eq!(rom(": main jump done : done clear"), [0x12, 0x02, 0x12, 0x04, 0x00, 0xE0]);
+        assert_eq!(
+            rom(": main\n  sub\n  jump main\n: sub\n  v0 := 1\n;"),
+            [0x12, 0x02, 0x22, 0x06, 0x12, 0x02, 0x60, 0x01, 0x00, 0xEE]
+        );
+    }
+
+    #[test]
+    fn const_alias_and_macro() {
+        let source = ":const speed 3\n:alias x v4\n:macro bump reg { reg += speed }\n: main x := speed bump x";
+        assert_eq!(rom(source), [0x12, 0x02, 0x64, 0x03, 0x74, 0x03]);
+    }
+
+    #[test]
+    fn loop_while_again() {
+        let source = ": main loop v0 += 1 while v0 != 5 again";
+        assert_eq!(rom(source), [0x12, 0x02, 0x70, 0x01, 0x40, 0x05, 0x12, 0x0A, 0x12, 0x02]);
+    }
+
+    // then skips its statement when the condition is false, begin skips the jump to else
+    // when it is true
+    #[test]
+    fn if_begin_else_end() {
+        assert_eq!(rom(": main if v1 == 2 then v2 := 1"), [0x12, 0x02, 0x41, 0x02, 0x62, 0x01]);
+        let source = ": main if v1 == 2 begin v2 := 1 else v2 := 3 end";
+        assert_eq!(rom(source), [0x12, 0x02, 0x31, 0x02, 0x12, 0x0A, 0x62, 0x01, 0x12, 0x0C, 0x62, 0x03]);
+    }
+
+    // right to left without precedence, like Octo
+    #[test]
+    fn calc() {
+        assert_eq!(rom(":calc x { 2 + 3 * 4 } : main v0 := x"), [0x12, 0x02, 0x60, 14]);
+        assert_eq!(rom(":calc x { 10 - 2 - 3 } : main v0 := x"), [0x12, 0x02, 0x60, 11]);
+    }
+
+    #[test]
+    fn breakpoints() {
+        let program = compile(": main clear :breakpoint here v0 := 1").unwrap();
+        assert_eq!(program.breakpoints, [("here".to_string(), 0x204)]);
+    }
+
+    #[test]
+    fn error_positions() {
+        let err = compile(": main\n  jump nowhere").unwrap_err();
+        assert_eq!((err.line, err.col, err.msg.as_str()), (2, 8, "Undefined name 'nowhere'."));
+        let err = compile(": main\n  v0 := 1\n  v1 += v99").unwrap_err();
+        assert_eq!((err.line, err.col), (3, 9));
+        let err = compile(": start clear").unwrap_err();
+        assert!(err.msg.contains("'main'"), "{}", err);
+    }
+}
@@ -0,0 +1,57 @@
+// Screen colors. A palette has one color per combination of lit XO-CHIP planes:
+// background, plane 1 (the only one plain chip-8 and SCHIP draw to), plane 2 and both.
+
+use crate::cpu;
+
+pub type Rgb = [u8; 3];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub name: String,
+    pub colors: [Rgb; 1 << cpu::NUM_PLANES], // indexed by the bitmask of lit planes
+}
+
+impl Palette {
+    fn new(name: &str, colors: [u32; 1 << cpu::NUM_PLANES]) -> Palette {
+        Palette { name: name.to_string(), colors: colors.map(rgb_from_u32) }
+    }
+
+    pub fn background(&self) -> Rgb { self.colors[0] }
+}
+
+impl Default for Palette {
+    fn default() -> Palette { Palette::new("gray", [0x000000, 0x808080, 0x404040, 0xFFFFFF]) }
+}
+
+fn rgb_from_u32(rgb: u32) -> Rgb { [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8] }
+
+// the ones that are always available, the first is the default
+pub fn builtin() -> Vec<Palette> {
+    vec![
+        Palette::default(),
+        Palette::new("green", [0x001A00, 0x33FF33, 0x118811, 0xAAFFAA]), // green phosphor
+        Palette::new("amber", [0x1A0F00, 0xFFB000, 0x995500, 0xFFE099]), // amber phosphor
+        Palette::new("lcd", [0x9BBC0F, 0x0F380F, 0x8BAC0F, 0x306230]),   // greenish handheld LCD
+        Palette::new("octo", [0x996600, 0xFFCC00, 0xFF6600, 0x662200]),  // Octo's defaults
+    ]
+}
+
+// "#RRGGBB" or "RRGGBB"
+pub fn parse_color(text: &str) -> Option<Rgb> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+    if hex.len() != 6 {
+        return None;
+    }
+    u32::from_str_radix(hex, 16).ok().map(rgb_from_u32)
+}
+
+// from the config file's colors: background and foreground, or all four. Two color
+// palettes draw every plane in the foreground color.
+pub fn from_colors(name: &str, colors: &[Rgb]) -> Option<Palette> {
+    let colors = match *colors {
+        [bg, fg] => [bg, fg, fg, fg],
+        [bg, plane1, plane2, both] => [bg, plane1, plane2, both],
+        _ => return None,
+    };
+    Some(Palette { name: name.to_string(), colors })
+}
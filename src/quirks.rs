@@ -0,0 +1,70 @@
+// Behavioral differences between the CHIP-8 family of interpreters.
+//
+// Most ROMs were written against one specific interpreter and rely on its
+// (often accidental) behavior, so the CPU is parameterized by these flags
+// rather than picking one interpretation for everybody.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub shift_uses_vy: bool,           // 8XY6/8XYE: VX = VY >> 1 instead of VX >>= 1
+    pub load_store_increments_i: bool, // FX55/FX65: I is left at I + X + 1
+    pub jump_uses_vx: bool,            // BNNN behaves as BXNN: jump to XNN + VX
+    pub clip_sprites: bool,            // sprites are clipped at the edges instead of wrapped
+    pub vf_reset: bool,                // 8XY1/8XY2/8XY3 set VF to 0
+    pub display_wait: bool,            // DXYN waits for the next vertical blank (60 Hz tick)
+    pub collision_rows: bool,          // hires DXYN sets VF to the colliding and bottom-clipped rows
+}
+
+impl Quirks {
+    // original interpreter on the RCA COSMAC VIP
+    pub const fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: true,
+            vf_reset: true,
+            display_wait: true,
+            collision_rows: false,
+        }
+    }
+
+    // SUPER-CHIP 1.1 on the HP 48
+    pub const fn schip() -> Quirks {
+        Quirks {
+            shift_uses_vy: false,
+            load_store_increments_i: false,
+            jump_uses_vx: true,
+            clip_sprites: true,
+            vf_reset: false,
+            display_wait: false,
+            collision_rows: true,
+        }
+    }
+
+    // XO-CHIP as implemented by Octo
+    pub const fn xochip() -> Quirks {
+        Quirks {
+            shift_uses_vy: true,
+            load_store_increments_i: true,
+            jump_uses_vx: false,
+            clip_sprites: false,
+            vf_reset: false,
+            display_wait: false,
+            collision_rows: false,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_ascii_lowercase().as_str() {
+            "vip" | "cosmac" | "chip8" | "chip-8" => Some(Quirks::cosmac_vip()),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::schip()),
+            "xochip" | "xo-chip" | "octo" => Some(Quirks::xochip()),
+            _ => None,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks { Quirks::cosmac_vip() }
+}
@@ -0,0 +1,196 @@
+// History of save states for rewinding.
+//
+// Only the newest snapshot is kept whole. Every older one is stored as a delta that
+// turns the snapshot after it back into it: the XOR of the two, run-length encoded.
+// Between two frames almost nothing in memory changes, so most deltas are a few
+// dozen bytes instead of the ~74 KiB of a full state. Stepping back undoes the newest
+// delta; the oldest ones are dropped once there are too many or they take up too much
+// memory.
+
+use std::collections::VecDeque;
+
+pub const DEFAULT_MAX_BYTES: usize = 32 * 1024 * 1024;
+
+// delta kinds, the first byte of every delta
+const XOR_DELTA: u8 = 0;
+const FULL_COPY: u8 = 1; // the states differ in length (hires switch), just store the old one
+
+pub struct Rewind {
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>, // deltas[n] turns snapshot n + 1 into snapshot n
+    max_snapshots: usize,
+    max_bytes: usize,
+    bytes: usize, // total size of the deltas
+}
+
+fn push_varint(out: &mut Vec<u8>, mut val: usize) {
+    while val >= 0x80 {
+        out.push(val as u8 | 0x80);
+        val >>= 7;
+    }
+    out.push(val as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut val = 0;
+    let mut shift = 0;
+    while let Some(&byte) = data.get(*pos) {
+        *pos += 1;
+        val |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    val
+}
+
+// XOR of the two as (zero run, literal length, literal bytes) triples
+fn encode(newer: &[u8], older: &[u8]) -> Vec<u8> {
+    if newer.len() != older.len() {
+        let mut out = vec![FULL_COPY];
+        out.extend_from_slice(older);
+        return out;
+    }
+    let mut out = vec![XOR_DELTA];
+    let mut pos = 0;
+    while pos < newer.len() {
+        let run_start = pos;
+        while pos < newer.len() && newer[pos] == older[pos] {
+            pos += 1;
+        }
+        let literal_start = pos;
+        while pos < newer.len() && newer[pos] != older[pos] {
+            pos += 1;
+        }
+        push_varint(&mut out, literal_start - run_start);
+        push_varint(&mut out, pos - literal_start);
+        out.extend(newer[literal_start..pos].iter().zip(&older[literal_start..pos]).map(|(a, b)| a ^ b));
+    }
+    out
+}
+
+fn decode(newer: &[u8], delta: &[u8]) -> Vec<u8> {
+    if delta[0] == FULL_COPY {
+        return delta[1..].to_vec();
+    }
+    let mut older = newer.to_vec();
+    let mut pos = 1;
+    let mut offset = 0;
+    while pos < delta.len() {
+        offset += read_varint(delta, &mut pos);
+        let len = read_varint(delta, &mut pos);
+        for (byte, xor) in older[offset..offset + len].iter_mut().zip(&delta[pos..pos + len]) {
+            *byte ^= xor;
+        }
+        offset += len;
+        pos += len;
+    }
+    older
+}
+
+impl Rewind {
+    // max_snapshots is how many steps back are possible, memory use is capped at about max_bytes
+    pub fn new(max_snapshots: usize, max_bytes: usize) -> Rewind {
+        Rewind { latest: None, deltas: VecDeque::new(), max_snapshots, max_bytes, bytes: 0 }
+    }
+
+    pub fn push(&mut self, state: Vec<u8>) {
+        if let Some(older) = self.latest.take() {
+            let delta = encode(&state, &older);
+            self.bytes += delta.len();
+            self.deltas.push_back(delta);
+        }
+        self.latest = Some(state);
+        let latest_len = self.latest.as_ref().map_or(0, |latest| latest.len());
+        while self.deltas.len() > self.max_snapshots || self.bytes + latest_len > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.bytes -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    // the snapshot before the newest one, which becomes the newest. None when out of history.
+    pub fn step_back(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        self.bytes -= delta.len();
+        let older = decode(self.latest.as_ref()?, &delta);
+        self.latest = Some(older);
+        self.latest.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // len bytes that differ from the next seed's in most places
+    fn state(seed: u8, len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(seed) ^ (i / 200) as u8).collect()
+    }
+
+    #[test]
+    fn deltas_round_trip() {
+        let older = state(3, 1000);
+        let mut newer = older.clone();
+        newer[0] ^= 1;
+        newer[500..700].iter_mut().for_each(|byte| *byte = byte.wrapping_add(1));
+        newer[999] = 0;
+        let delta = encode(&newer, &older);
+        assert_eq!(delta[0], XOR_DELTA);
+        assert!(delta.len() < 220);
+        assert_eq!(decode(&newer, &delta), older);
+        // identical states only need the run
+        assert_eq!(encode(&older, &older), [XOR_DELTA, 0xE8, 0x07, 0]);
+        assert_eq!(decode(&older, &encode(&older, &older)), older);
+        // different lengths store the older state whole
+        let shorter = state(5, 600);
+        assert_eq!(encode(&newer, &shorter)[0], FULL_COPY);
+        assert_eq!(decode(&newer, &encode(&newer, &shorter)), shorter);
+        assert_eq!(decode(&shorter, &encode(&shorter, &newer)), newer);
+    }
+
+    #[test]
+    fn steps_back_in_order() {
+        let states: Vec<Vec<u8>> = (1..=5).map(|seed| state(seed, 300 + seed as usize % 2)).collect();
+        let mut rewind = Rewind::new(10, DEFAULT_MAX_BYTES);
+        for state in &states {
+            rewind.push(state.clone());
+        }
+        for older in states[..4].iter().rev() {
+            assert_eq!(rewind.step_back(), Some(&older[..]));
+        }
+        assert_eq!(rewind.step_back(), None);
+        // history continues from where it stopped
+        rewind.push(states[2].clone());
+        assert_eq!(rewind.step_back(), Some(&states[0][..]));
+    }
+
+    #[test]
+    fn drops_the_oldest_first() {
+        let states: Vec<Vec<u8>> = (1..=6).map(|seed| state(seed, 300)).collect();
+        let mut rewind = Rewind::new(3, DEFAULT_MAX_BYTES);
+        for state in &states {
+            rewind.push(state.clone());
+        }
+        assert_eq!(rewind.deltas.len(), 3);
+        for older in states[2..5].iter().rev() {
+            assert_eq!(rewind.step_back(), Some(&older[..]));
+        }
+        assert_eq!(rewind.step_back(), None);
+
+        // and when over the byte limit
+        let mut rewind = Rewind::new(100, 1000);
+        for state in &states {
+            rewind.push(state.clone());
+        }
+        assert!(rewind.bytes + 300 <= 1000);
+        let kept = rewind.deltas.len();
+        assert!(kept > 0 && kept < 5);
+        for older in states[5 - kept..5].iter().rev() {
+            assert_eq!(rewind.step_back(), Some(&older[..]));
+        }
+        assert_eq!(rewind.bytes, 0);
+    }
+}
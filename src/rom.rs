@@ -0,0 +1,403 @@
+// Reading roms. Besides raw binaries this understands two text formats, told apart
+// from binaries by their content:
+//
+//   Intel HEX     ":LLAAAATT...CC" records, as written by EPROM tools and some
+//                 assemblers. Addresses at or above the load address are memory
+//                 addresses, lower ones are offsets into the rom.
+//   hex dumps     "00E0 A22A 6000 ...", whitespace or comma separated with optional 0x
+//                 prefixes and # comments, as found in listings and forum posts
+//
+// Either may be gzipped (.gz) or in a zip archive, which is searched for the first
+// file with a rom extension. "-" reads from stdin.
+
+use crate::cpu;
+use crate::octo;
+
+use std::error::Error;
+use std::io::{self, Read};
+use std::path::Path;
+use std::{fmt, fs};
+
+// always raw, even if the bytes happen to look like text
+const BINARY_EXTENSIONS: [&str; 4] = ["ch8", "c8", "sc8", "xo8"];
+const TEXT_EXTENSIONS: [&str; 2] = ["hex", "txt"];
+
+const GZIP_MAGIC: &[u8] = &[0x1F, 0x8B];
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+
+#[derive(Debug)]
+pub enum RomError {
+    NotFound,
+    Io(io::Error),
+    Empty,
+    TooBig { size: usize, load_address: usize },
+    BadLoadAddress(usize),
+    Parse(usize, String), // line of a text rom and what's wrong with it
+    Gzip(io::Error),
+    Zip(String),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::NotFound => write!(f, "no such file"),
+            RomError::Io(err) => write!(f, "{}", err),
+            RomError::Empty => write!(f, "rom is empty"),
+            RomError::TooBig { size, load_address } => write!(
+                f,
+                "rom is {} bytes, at most {} fit in memory when loaded at {:#05X}",
+                size,
+                cpu::MEM_SIZE - load_address,
+                load_address
+            ),
+            RomError::BadLoadAddress(addr) => {
+                write!(f, "load address {:#X} is outside {:#05X}-{:#X}", addr, cpu::ROM_START, cpu::MEM_SIZE - 1)
+            }
+            RomError::Parse(line, msg) => write!(f, "line {}: {}", line, msg),
+            RomError::Gzip(err) => write!(f, "bad gzip data: {}", err),
+            RomError::Zip(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl Error for RomError {}
+
+impl From<io::Error> for RomError {
+    fn from(err: io::Error) -> RomError {
+        match err.kind() {
+            io::ErrorKind::NotFound => RomError::NotFound,
+            _ => RomError::Io(err),
+        }
+    }
+}
+
+// reads a rom to be loaded at load_address (cpu::ROM_START for nearly everything,
+// 0x600 for ETI-660 programs), refusing anything that would not fit in memory
+pub fn read_rom(path: &str, load_address: usize) -> Result<Vec<u8>, RomError> {
+    if !(cpu::ROM_START..cpu::MEM_SIZE).contains(&load_address) {
+        return Err(RomError::BadLoadAddress(load_address));
+    }
+    decode_rom(path, read_file(path)?, load_address)
+}
+
+// the file's contents, stdin for "-"
+pub fn read_file(path: &str) -> Result<Vec<u8>, RomError> {
+    if path == "-" {
+        let mut data = vec![];
+        io::stdin().lock().read_to_end(&mut data)?;
+        return Ok(data);
+    }
+    Ok(fs::read(path)?)
+}
+
+// the rom in data as read from path, for load_address. Text roms place their bytes by
+// address, so the same data can decode differently for another load address.
+pub fn decode_rom(path: &str, data: Vec<u8>, load_address: usize) -> Result<Vec<u8>, RomError> {
+    let name = if path == "-" { "" } else { path };
+    let rom = decode(name, data, load_address)?;
+    if rom.is_empty() {
+        return Err(RomError::Empty);
+    }
+    check_fits(&rom, load_address)?;
+    Ok(rom)
+}
+
+// refuses a rom that would not fit in memory at load_address
+pub fn check_fits(rom: &[u8], load_address: usize) -> Result<(), RomError> {
+    if !(cpu::ROM_START..cpu::MEM_SIZE).contains(&load_address) {
+        return Err(RomError::BadLoadAddress(load_address));
+    }
+    if rom.len() > cpu::MEM_SIZE - load_address {
+        return Err(RomError::TooBig { size: rom.len(), load_address });
+    }
+    Ok(())
+}
+
+// a rom, or Octo source if the path ends in .8o. Errors start with the path.
+pub fn load_program(path: &str, load_address: usize) -> Result<octo::Program, String> {
+    let data = read_file(path).map_err(|e| format!("{}: {}", path, e))?;
+    decode_program(path, data, load_address)
+}
+
+// load_program for a file that has already been read
+pub fn decode_program(path: &str, data: Vec<u8>, load_address: usize) -> Result<octo::Program, String> {
+    if !path.ends_with(".8o") {
+        let rom = decode_rom(path, data, load_address).map_err(|e| format!("{}: {}", path, e))?;
+        return Ok(octo::Program { rom, breakpoints: vec![] });
+    }
+    if load_address != cpu::ROM_START {
+        return Err(format!("{}: Octo programs always start at {:#05X}", path, cpu::ROM_START));
+    }
+    let source = String::from_utf8(data).map_err(|e| format!("{}: {}", path, e))?;
+    octo::compile(&source).map_err(|e| format!("{}:{}", path, e))
+}
+
+fn extension(name: &str) -> String {
+    let ext = Path::new(name).extension().map(|ext| ext.to_string_lossy().to_ascii_lowercase());
+    ext.unwrap_or_default()
+}
+
+// unpacks and parses until only the raw rom is left, name picks the format if the
+// extension says so
+fn decode(name: &str, data: Vec<u8>, load_address: usize) -> Result<Vec<u8>, RomError> {
+    let ext = extension(name);
+    if data.starts_with(GZIP_MAGIC) {
+        let mut unpacked = vec![];
+        flate2::read::GzDecoder::new(&data[..]).read_to_end(&mut unpacked).map_err(RomError::Gzip)?;
+        // pong.ch8.gz holds pong.ch8
+        let inner = if ext == "gz" { &name[..name.len() - 3] } else { "" };
+        return decode(inner, unpacked, load_address);
+    }
+    if data.starts_with(ZIP_MAGIC) {
+        let (inner, unpacked) = unzip(&data)?;
+        return decode(&inner, unpacked, load_address);
+    }
+    if BINARY_EXTENSIONS.contains(&ext.as_str()) {
+        return Ok(data);
+    }
+    // raw roms practically always have bytes outside printable ascii, 00E0 for one
+    let is_text = data.iter().all(|&b| b.is_ascii_graphic() || b.is_ascii_whitespace());
+    if !is_text || data.is_empty() {
+        return Ok(data);
+    }
+    let text = String::from_utf8(data).expect("ascii is valid utf-8");
+    if text.trim_start().starts_with(':') {
+        parse_intel_hex(&text, load_address)
+    } else {
+        parse_hex_dump(&text)
+    }
+}
+
+// the first file with a rom extension, or the only file
+fn unzip(data: &[u8]) -> Result<(String, Vec<u8>), RomError> {
+    let zip_err = |e: zip::result::ZipError| RomError::Zip(format!("bad zip archive: {}", e));
+    let mut archive = zip::ZipArchive::new(io::Cursor::new(data)).map_err(zip_err)?;
+    let files: Vec<String> = archive
+        .file_names()
+        .filter(|name| !name.ends_with('/') && !name.starts_with("__MACOSX/"))
+        .map(String::from)
+        .collect();
+    let is_rom = |name: &&String| {
+        let ext = extension(name);
+        BINARY_EXTENSIONS.contains(&ext.as_str()) || TEXT_EXTENSIONS.contains(&ext.as_str())
+    };
+    let name = match files.iter().find(is_rom) {
+        Some(name) => name.clone(),
+        None if files.len() == 1 => files[0].clone(),
+        None => {
+            let expected = [&BINARY_EXTENSIONS[..], &TEXT_EXTENSIONS[..]].concat().join(", .");
+            return Err(RomError::Zip(format!("no rom in zip archive (looked for .{})", expected)));
+        }
+    };
+    let mut rom = vec![];
+    archive.by_name(&name).map_err(zip_err)?.read_to_end(&mut rom)?;
+    Ok((name, rom))
+}
+
+fn parse_intel_hex(text: &str, load_address: usize) -> Result<Vec<u8>, RomError> {
+    let mut records: Vec<(usize, Vec<u8>)> = vec![];
+    let mut base = 0; // from extended address records
+    for (line_num, line) in text.lines().enumerate() {
+        let err = |msg: &str| RomError::Parse(line_num + 1, msg.to_string());
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let hex = line.strip_prefix(':').ok_or_else(|| err("expected a record starting with ':'"))?;
+        if hex.len() & 1 != 0 {
+            return Err(err("odd number of hex digits"));
+        }
+        if hex.len() < 10 {
+            return Err(err("record is too short"));
+        }
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16))
+            .collect::<Result<Vec<u8>, _>>()
+            .map_err(|_| err("not a hex digit"))?;
+        let len = bytes[0] as usize;
+        if bytes.len() != len + 5 {
+            return Err(err("record length doesn't match its data"));
+        }
+        if bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) != 0 {
+            return Err(err("bad checksum"));
+        }
+        let addr = (bytes[1] as usize) << 8 | bytes[2] as usize;
+        let data = &bytes[4..4 + len];
+        match bytes[3] {
+            0x00 => records.push((base + addr, data.to_vec())),
+            0x01 => break, // end of file
+            0x02 if len == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 4,
+            0x04 if len == 2 => base = ((data[0] as usize) << 8 | data[1] as usize) << 16,
+            0x03 | 0x05 => {} // start address, the cpu always starts at the load address
+            _ => return Err(err("unsupported record type")),
+        }
+    }
+    let start = records.iter().map(|&(addr, _)| addr).min().unwrap_or(0);
+    let start = if start >= load_address { load_address } else { 0 };
+    let end = records.iter().map(|(addr, data)| addr + data.len()).max().unwrap_or(start);
+    if end - start > cpu::MEM_SIZE {
+        return Err(RomError::TooBig { size: end - start, load_address });
+    }
+    let mut rom = vec![0; end - start];
+    for (addr, data) in records {
+        rom[addr - start..addr - start + data.len()].copy_from_slice(&data);
+    }
+    Ok(rom)
+}
+
+// "00E0 A22A", "0x00, 0xE0", "00E0A22A", ...
+fn parse_hex_dump(text: &str) -> Result<Vec<u8>, RomError> {
+    let mut rom = vec![];
+    for (line_num, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap();
+        for word in line.split(|c: char| c.is_ascii_whitespace() || c == ',') {
+            let digits = word.strip_prefix("0x").or_else(|| word.strip_prefix("0X")).unwrap_or(word);
+            if digits.len() & 1 != 0 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+                let msg = format!("expected hex bytes, got \"{}\"", word);
+                return Err(RomError::Parse(line_num + 1, msg));
+            }
+            rom.extend((0..digits.len()).step_by(2).map(|i| u8::from_str_radix(&digits[i..i + 2], 16).unwrap()));
+        }
+    }
+    Ok(rom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    // one Intel HEX record with its checksum
+    fn record(addr: u16, kind: u8, data: &[u8]) -> String {
+        let bytes = [&[data.len() as u8], &addr.to_be_bytes()[..], &[kind], data].concat();
+        let checksum = bytes.iter().fold(0u8, |sum, &b| sum.wrapping_sub(b));
+        let hex: String = bytes.iter().chain([checksum].iter()).map(|b| format!("{:02X}", b)).collect();
+        format!(":{}\n", hex)
+    }
+
+    #[test]
+    fn intel_hex_addresses() {
+        // at or above the load address they are memory addresses, gaps are zero
+        let text = record(0x200, 0, &[0x00, 0xE0]) + &record(0x204, 0, &[0x12, 0x00]) + &record(0, 1, &[]);
+        assert_eq!(parse_intel_hex(&text, 0x200).unwrap(), [0x00, 0xE0, 0, 0, 0x12, 0x00]);
+        // below it they are offsets into the rom
+        let text = record(0, 0, &[0x00, 0xE0]) + &record(2, 0, &[0x12, 0x00]);
+        assert_eq!(parse_intel_hex(&text, 0x200).unwrap(), [0x00, 0xE0, 0x12, 0x00]);
+        // the same records are offsets for a higher load address
+        let text = record(0x200, 0, &[0xAB]);
+        assert_eq!(parse_intel_hex(&text, 0x600).unwrap().len(), 0x201);
+        // nothing after the end of file record counts
+        let text = record(0x200, 0, &[0xAB]) + &record(0, 1, &[]) + "garbage";
+        assert_eq!(parse_intel_hex(&text, 0x200).unwrap(), [0xAB]);
+    }
+
+    #[test]
+    fn intel_hex_extended_records() {
+        // segment base 0x20 << 4 moves offset 0 up to the load address
+        let text = record(0, 2, &[0x00, 0x20]) + &record(0, 0, &[0x00, 0xE0]) + &record(0, 3, &[0, 0, 0, 0]);
+        assert_eq!(parse_intel_hex(&text, 0x200).unwrap(), [0x00, 0xE0]);
+        // linear base 1 << 16 is past the end of memory
+        let text = record(0, 4, &[0x00, 0x01]) + &record(0, 0, &[0x00]);
+        assert!(matches!(decode_rom("rom.hex", text.into_bytes(), 0x200), Err(RomError::TooBig { .. })));
+    }
+
+    #[test]
+    fn intel_hex_errors() {
+        let parse = |text: &str| match parse_intel_hex(text, 0x200) {
+            Err(RomError::Parse(line, msg)) => (line, msg),
+            other => panic!("{:?}", other),
+        };
+        let mut bad_checksum = record(0x200, 0, &[0x00, 0xE0]);
+        bad_checksum.replace_range(bad_checksum.len() - 3.., "00\n");
+        assert_eq!(parse(&format!("\n{}", bad_checksum)), (2, "bad checksum".to_string()));
+        assert_eq!(parse(":0102"), (1, "record is too short".to_string()));
+        assert_eq!(parse(":020200000E"), (1, "record length doesn't match its data".to_string()));
+        assert_eq!(parse(":0"), (1, "odd number of hex digits".to_string()));
+        assert_eq!(parse(":00000006FA"), (1, "unsupported record type".to_string()));
+        assert_eq!(parse("0200"), (1, "expected a record starting with ':'".to_string()));
+    }
+
+    #[test]
+    fn hex_dumps() {
+        let text = "00E0 A22A # clear, point I at the sprite\n0x60, 0X05,0xD0\n15\n";
+        assert_eq!(parse_hex_dump(text).unwrap(), [0x00, 0xE0, 0xA2, 0x2A, 0x60, 0x05, 0xD0, 0x15]);
+        assert_eq!(parse_hex_dump("00E0A22A").unwrap(), [0x00, 0xE0, 0xA2, 0x2A]);
+        match parse_hex_dump("00E0\n00E 0") {
+            Err(RomError::Parse(line, msg)) => {
+                assert_eq!((line, msg.as_str()), (2, "expected hex bytes, got \"00E\""))
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(parse_hex_dump("00G0"), Err(RomError::Parse(1, _))));
+    }
+
+    #[test]
+    fn text_or_binary() {
+        // hex dumps are recognized by content, unless the extension says binary
+        let text = b"00E0 1200".to_vec();
+        assert_eq!(decode("rom", text.clone(), 0x200).unwrap(), [0x00, 0xE0, 0x12, 0x00]);
+        assert_eq!(decode("rom.ch8", text.clone(), 0x200).unwrap(), text);
+        let binary = vec![0x00, 0xE0, 0x12, 0x00];
+        assert_eq!(decode("rom.txt", binary.clone(), 0x200).unwrap(), binary);
+    }
+
+    fn gzip(data: &[u8]) -> Vec<u8> {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(io::Cursor::new(vec![]));
+        for (name, data) in files {
+            writer.start_file(*name, zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(data).unwrap();
+        }
+        writer.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn gzip_unpacking() {
+        let rom = [0x00, 0xE0, 0x12, 0x00];
+        assert_eq!(decode("pong.ch8.gz", gzip(&rom), 0x200).unwrap(), rom);
+        // the name inside says binary, so this text isn't parsed
+        assert_eq!(decode("pong.ch8.gz", gzip(b"00E0"), 0x200).unwrap(), b"00E0");
+        assert_eq!(decode("pong.gz", gzip(b"00E0 1200"), 0x200).unwrap(), rom);
+        let mut truncated = gzip(&rom);
+        truncated.truncate(12);
+        assert!(matches!(decode("pong.gz", truncated, 0x200), Err(RomError::Gzip(_))));
+    }
+
+    #[test]
+    fn zip_unpacking() {
+        let rom: &[u8] = &[0x00, 0xE0, 0x12, 0x00];
+        let archive = zip(&[
+            ("README.md", b"# pong"),
+            ("__MACOSX/roms/._pong.ch8", b"resource fork"),
+            ("roms/", b""),
+            ("roms/pong.ch8", rom),
+        ]);
+        assert_eq!(unzip(&archive).unwrap(), ("roms/pong.ch8".to_string(), rom.to_vec()));
+        assert_eq!(decode("pong.zip", archive, 0x200).unwrap(), rom);
+        // a text rom is parsed after unpacking
+        assert_eq!(decode("pong.zip", zip(&[("pong.hex", b"00E0 1200")]), 0x200).unwrap(), rom);
+        // a single file is taken whatever its name
+        assert_eq!(decode("pong.zip", zip(&[("PONG", rom)]), 0x200).unwrap(), rom);
+        let no_rom = zip(&[("README.md", b"# pong"), ("pong.bin", rom)]);
+        assert!(matches!(unzip(&no_rom), Err(RomError::Zip(msg)) if msg.starts_with("no rom in zip archive")));
+        assert!(matches!(decode("pong.zip", b"PK\x03\x04garbage".to_vec(), 0x200), Err(RomError::Zip(_))));
+    }
+
+    #[test]
+    fn roms_have_to_fit() {
+        let rom = vec![0; cpu::MEM_SIZE - 0x600];
+        assert!(check_fits(&rom, 0x600).is_ok());
+        let too_big = check_fits(&rom, 0x601);
+        assert!(matches!(too_big, Err(RomError::TooBig { size, load_address: 0x601 }) if size == rom.len()));
+        assert!(matches!(check_fits(&[0], 0x1FF), Err(RomError::BadLoadAddress(0x1FF))));
+        assert!(matches!(check_fits(&[0], cpu::MEM_SIZE), Err(RomError::BadLoadAddress(_))));
+        assert!(matches!(decode_rom("rom.ch8", vec![], 0x200), Err(RomError::Empty)));
+        assert!(matches!(decode_rom("rom.ch8", rom, 0x700), Err(RomError::TooBig { .. })));
+    }
+}
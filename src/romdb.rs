@@ -0,0 +1,329 @@
+// What platform a rom was written for, and how it likes to be run.
+//
+// Roms are looked up by SHA-1 in a database in the format of the community
+// chip-8-database (https://github.com/chip-8/chip-8-database): its programs.json lists
+// every program with its roms keyed by SHA-1, each with the platforms it runs on (best
+// first), its speed, start address, key layout and colors. The bundled copy is
+// data/programs.json, which is compiled in, and `database = "path/to/programs.json"` in
+// the config file reads another one instead.
+//
+// Roms that aren't in it get their platform guessed from the instructions they use.
+
+use crate::cpu;
+use crate::instruction::Instruction;
+use crate::palette::{self, Rgb};
+use crate::quirks::Quirks;
+use crate::state;
+
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+
+const BUNDLED: &str = include_str!("../data/programs.json");
+
+#[derive(Debug)]
+pub struct Platform {
+    pub id: &'static str, // as in the database's platforms.json
+    pub name: &'static str,
+    pub quirks: Quirks,
+    pub ipf: u64, // the database's defaultTickrate
+}
+
+// the database's platforms that can be emulated, SCHIP's and XO-CHIP's extensions
+// are always there
+pub const PLATFORMS: [Platform; 7] = [
+    Platform { id: "originalChip8", name: "COSMAC VIP CHIP-8", quirks: Quirks::cosmac_vip(), ipf: 15 },
+    Platform { id: "hybridVIP", name: "COSMAC VIP CHIP-8 with machine code", quirks: Quirks::cosmac_vip(), ipf: 15 },
+    Platform {
+        id: "modernChip8",
+        name: "modern CHIP-8",
+        quirks: Quirks { vf_reset: false, display_wait: false, ..Quirks::cosmac_vip() },
+        ipf: 12,
+    },
+    // CHIP-48's FX55/FX65 leave I at I + X, there is no quirk for that
+    Platform { id: "chip48", name: "CHIP-48", quirks: Quirks::schip(), ipf: 30 },
+    Platform { id: "superchip1", name: "SUPER-CHIP 1.0", quirks: Quirks::schip(), ipf: 30 },
+    Platform { id: "superchip", name: "SUPER-CHIP 1.1", quirks: Quirks::schip(), ipf: 30 },
+    Platform { id: "xochip", name: "XO-CHIP", quirks: Quirks::xochip(), ipf: 100 },
+];
+
+pub fn find_platform(id: &str) -> Option<&'static Platform> {
+    PLATFORMS.iter().find(|platform| platform.id == id)
+}
+
+#[derive(Clone, Debug)]
+pub struct RomInfo {
+    pub title: Option<String>,
+    pub authors: Vec<String>,
+    pub platform: &'static Platform,
+    pub quirks: Quirks, // the platform's, with the rom's own exceptions
+    pub ipf: u64,
+    pub load_address: Option<usize>,
+    pub keys: Vec<(String, usize)>, // database action ("up", "a", ...) and its hex key
+    pub colors: Vec<Rgb>,           // background and foreground, or one per plane combination
+    pub known: bool,                // false if the platform is a guess
+}
+
+impl RomInfo {
+    fn guess(platform: &'static Platform) -> RomInfo {
+        RomInfo {
+            title: None,
+            authors: vec![],
+            platform,
+            quirks: platform.quirks,
+            ipf: platform.ipf,
+            load_address: None,
+            keys: vec![],
+            colors: vec![],
+            known: false,
+        }
+    }
+}
+
+impl fmt::Display for RomInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.known {
+            return write!(f, "unknown rom, looks like {}", self.platform.name);
+        }
+        write!(f, "{}", self.title.as_deref().unwrap_or("untitled"))?;
+        if !self.authors.is_empty() {
+            write!(f, " by {}", self.authors.join(", "))?;
+        }
+        write!(f, " ({}, {} ipf)", self.platform.name, self.ipf)
+    }
+}
+
+#[derive(Default)]
+pub struct Database {
+    roms: HashMap<String, RomInfo>, // by hex SHA-1
+}
+
+impl Database {
+    pub fn bundled() -> Database { Database::parse(BUNDLED).expect("bundled rom database is broken") }
+
+    pub fn load(path: &Path) -> Result<Database, String> {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        Database::parse(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    // entries that are malformed or only run on unsupported platforms are left out
+    pub fn parse(text: &str) -> Result<Database, String> {
+        let programs: Value = serde_json::from_str(text).map_err(|e| e.to_string())?;
+        let programs = programs.as_array().ok_or("expected a list of programs")?;
+        let mut roms = HashMap::new();
+        for program in programs {
+            let title = program["title"].as_str().map(String::from);
+            let authors = strings(&program["authors"]);
+            for (hash, rom) in program["roms"].as_object().into_iter().flatten() {
+                if let Some(info) = parse_rom(rom, &title, &authors) {
+                    roms.insert(hash.to_ascii_lowercase(), info);
+                }
+            }
+        }
+        Ok(Database { roms })
+    }
+
+    pub fn get(&self, rom: &[u8]) -> Option<&RomInfo> {
+        let hash: String = state::rom_hash(rom).iter().map(|byte| format!("{:02x}", byte)).collect();
+        self.roms.get(&hash)
+    }
+}
+
+fn strings(value: &Value) -> Vec<String> {
+    let strings = value.as_array().into_iter().flatten().filter_map(|s| s.as_str());
+    strings.map(String::from).collect()
+}
+
+fn parse_rom(rom: &Value, title: &Option<String>, authors: &[String]) -> Option<RomInfo> {
+    let platforms = rom["platforms"].as_array()?.iter().filter_map(|id| id.as_str());
+    let platform = platforms.filter_map(find_platform).next()?;
+    let mut quirks = platform.quirks;
+    for (quirk, on) in rom["quirkyPlatforms"][platform.id].as_object().into_iter().flatten() {
+        if let Some(on) = on.as_bool() {
+            apply_quirk(&mut quirks, quirk, on);
+        }
+    }
+    let keys = rom["keys"].as_object().into_iter().flatten().filter_map(|(action, key)| {
+        let key = key.as_u64().filter(|&key| key < 16)?;
+        Some((action.clone(), key as usize))
+    });
+    let colors = rom["colors"]["pixels"].as_array().into_iter().flatten();
+    let rom_authors = strings(&rom["authors"]);
+    Some(RomInfo {
+        title: title.clone(),
+        authors: if rom_authors.is_empty() { authors.to_vec() } else { rom_authors },
+        platform,
+        quirks,
+        ipf: rom["tickrate"].as_u64().filter(|&ipf| ipf > 0).unwrap_or(platform.ipf),
+        load_address: rom["startAddress"]
+            .as_u64()
+            .map(|addr| addr as usize)
+            .filter(|addr| (cpu::ROM_START..cpu::MEM_SIZE).contains(addr)),
+        keys: keys.collect(),
+        colors: colors.filter_map(|color| color.as_str().and_then(palette::parse_color)).collect(),
+        known: true,
+    })
+}
+
+// the database's quirk names, all of them true where the COSMAC VIP behaves differently
+fn apply_quirk(quirks: &mut Quirks, quirk: &str, on: bool) {
+    match quirk {
+        "shift" => quirks.shift_uses_vy = !on,
+        "memoryLeaveIUnchanged" => quirks.load_store_increments_i = !on,
+        "memoryIncrementByX" if on => quirks.load_store_increments_i = false, // closest there is
+        "wrap" => quirks.clip_sprites = !on,
+        "jump" => quirks.jump_uses_vx = on,
+        "vblank" => quirks.display_wait = on,
+        "logic" => quirks.vf_reset = on,
+        _ => {}
+    }
+}
+
+// the database's entry for the rom, or a guess from the instructions it uses
+pub fn identify(db: Option<&Database>, rom: &[u8], load_address: usize) -> RomInfo {
+    match db.and_then(|db| db.get(rom)) {
+        Some(info) => info.clone(),
+        None => RomInfo::guess(guess_platform(rom, load_address)),
+    }
+}
+
+fn decode_at(mem: &[u8], addr: usize) -> Option<Instruction> {
+    let word = |addr: usize| mem.get(addr..addr + 2).map_or(0, |bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+    Instruction::decode(word(addr), word(addr + 2))
+}
+
+// follows the code from the load address and looks for SCHIP and XO-CHIP instructions.
+// Unlike scanning the whole rom this doesn't mistake sprites for code, as long as
+// nothing jumps into them.
+fn guess_platform(rom: &[u8], load_address: usize) -> &'static Platform {
+    use Instruction::*;
+    let mut mem = vec![0u8; cpu::MEM_SIZE];
+    let len = rom.len().min(cpu::MEM_SIZE - load_address);
+    mem[load_address..load_address + len].copy_from_slice(&rom[..len]);
+    let end = load_address + len;
+    let mut schip = false;
+    let mut xochip = end > 0x1000; // everything before it had 4 KiB
+    let mut seen = vec![false; cpu::MEM_SIZE];
+    let mut todo = vec![load_address];
+    while let Some(mut addr) = todo.pop() {
+        while addr >= load_address && addr + 1 < end && !seen[addr] {
+            seen[addr] = true;
+            let instr = match decode_at(&mem, addr) {
+                Some(instr) => instr,
+                None => break,
+            };
+            match instr {
+                ScrollDown(_) | ScrollRight | ScrollLeft | Exit | Lores | Hires | BigFont(_) => schip = true,
+                Draw(_, _, 0) => schip = true, // 16x16 sprite
+                SaveFlags(x) | LoadFlags(x) => {
+                    schip = true;
+                    xochip |= x > 7; // SCHIP has 8 flag registers, XO-CHIP 16
+                }
+                ScrollUp(_) | SaveRange(..) | LoadRange(..) | SetILong(_) | SelectPlanes(_) | LoadAudio
+                | SetPitch(_) => xochip = true,
+                _ => {}
+            }
+            let next = addr + instr.size();
+            match instr {
+                Jump(target) => {
+                    todo.push(target as usize);
+                    break;
+                }
+                Call(target) => todo.push(target as usize),
+                // BNNN's target depends on V0, machine code can't be followed
+                Return | Exit | JumpOffset(_) | Sys(_) => break,
+                SkipEqImm(..) | SkipNeImm(..) | SkipEq(..) | SkipNe(..) | SkipKey(_) | SkipNotKey(_) => {
+                    if let Some(skipped) = decode_at(&mem, next) {
+                        todo.push(next + skipped.size());
+                    }
+                }
+                _ => {}
+            }
+            addr = next;
+        }
+    }
+    let id = if xochip {
+        "xochip"
+    } else if schip {
+        "superchip"
+    } else {
+        "originalChip8"
+    };
+    find_platform(id).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex_hash(rom: &[u8]) -> String { state::rom_hash(rom).iter().map(|byte| format!("{:02x}", byte)).collect() }
+
+    #[test]
+    fn known_rom_gets_its_entry() {
+        let rom = [0x60, 0x05, 0xF0, 0x29, 0xD0, 0x05, 0x12, 0x06];
+        let db = Database::parse(&format!(
+            r##"[{{
+                "title": "Test",
+                "authors": ["Someone"],
+                "roms": {{
+                    "{}": {{
+                        "file": "test.ch8",
+                        "platforms": ["megachip8", "superchip"],
+                        "quirkyPlatforms": {{ "superchip": {{ "shift": false, "vblank": true }} }},
+                        "tickrate": 20,
+                        "startAddress": 1536,
+                        "keys": {{ "up": 1, "a": 16 }},
+                        "colors": {{ "pixels": ["#000000", "#FF8800"] }}
+                    }}
+                }}
+            }}]"##,
+            hex_hash(&rom).to_ascii_uppercase()
+        ))
+        .unwrap();
+        let info = identify(Some(&db), &rom, cpu::ROM_START);
+        assert!(info.known);
+        assert_eq!(info.title.as_deref(), Some("Test"));
+        assert_eq!(info.platform.id, "superchip"); // megachip8 can't be emulated
+        assert_eq!(info.quirks, Quirks { shift_uses_vy: true, display_wait: true, ..Quirks::schip() });
+        assert_eq!(info.ipf, 20);
+        assert_eq!(info.load_address, Some(0x600));
+        assert_eq!(info.keys, vec![("up".to_string(), 1)]); // 16 isn't a hex key
+        assert_eq!(info.colors.len(), 2);
+    }
+
+    #[test]
+    fn unknown_rom_gets_a_guess() {
+        let db = Database::parse("[]").unwrap();
+        let lores = [0x00, 0xE0, 0x12, 0x00];
+        let info = identify(Some(&db), &lores, cpu::ROM_START);
+        assert!(!info.known);
+        assert_eq!((info.platform.id, info.ipf), ("originalChip8", 15));
+        let hires = [0x00, 0xFF, 0x12, 0x02];
+        assert_eq!(identify(None, &hires, cpu::ROM_START).platform.id, "superchip");
+        let planes = [0xF2, 0x01, 0x12, 0x02];
+        assert_eq!(identify(None, &planes, cpu::ROM_START).quirks, Quirks::xochip());
+    }
+
+    // every rom of the bundled copy that runs on a platform we have is found by its hash,
+    // with its program's title and its best platform
+    #[test]
+    fn bundled_database_finds_its_roms() {
+        let db = Database::bundled();
+        let programs: Value = serde_json::from_str(BUNDLED).unwrap();
+        let mut expected = HashMap::new(); // like parse, a hash listed twice keeps the last entry
+        for program in programs.as_array().unwrap() {
+            for (hash, rom) in program["roms"].as_object().into_iter().flatten() {
+                let platforms = rom["platforms"].as_array().into_iter().flatten().filter_map(|id| id.as_str());
+                if let Some(platform) = platforms.filter_map(find_platform).next() {
+                    expected.insert(hash.to_ascii_lowercase(), (program["title"].as_str(), platform.id));
+                }
+            }
+        }
+        assert_eq!(db.roms.len(), expected.len());
+        for (hash, (title, platform)) in expected {
+            let info = &db.roms[&hash];
+            assert_eq!((info.title.as_deref(), info.platform.id), (title, platform), "{}", hash);
+        }
+    }
+}
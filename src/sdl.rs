@@ -0,0 +1,590 @@
+// SDL2 implementations of the frontend traits
+
+use crate::cpu;
+use crate::frontend::{Audio, Display, Input, InputEvent};
+use crate::keymap::{Binding, Keymap};
+use crate::palette::Palette;
+
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::event::{Event, WindowEvent};
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::{Keycode, Scancode};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Texture, WindowCanvas};
+use sdl2::video::FullscreenType;
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+
+const SPEC_FREQ: i32 = 44100;
+
+// based on: https://docs.rs/sdl2/0.32.1/sdl2/audio/index.html
+// plays the XO-CHIP 1-bit pattern buffer in a loop. The CPU loop hands over new
+// patterns/pitches through AudioDevice::lock, which keeps the audio thread out of
+// the callback while they are swapped.
+pub struct PatternWave {
+    pattern: [u8; cpu::AUDIO_PATTERN_SIZE],
+    bits_per_sample: f32, // how far through the pattern one output sample advances
+    position: f32,        // in bits, 0..128
+    spec_freq: f32,
+    volume: f32,
+}
+impl PatternWave {
+    fn set_pattern(&mut self, pattern: [u8; cpu::AUDIO_PATTERN_SIZE], pitch: u8) {
+        self.pattern = pattern;
+        let playback_rate = 4000.0 * 2f32.powf((pitch as f32 - 64.0) / 48.0);
+        self.bits_per_sample = playback_rate / self.spec_freq;
+    }
+}
+impl AudioCallback for PatternWave {
+    type Channel = f32;
+
+    fn callback(&mut self, out: &mut [f32]) {
+        let num_bits = (cpu::AUDIO_PATTERN_SIZE * 8) as f32;
+        for x in out.iter_mut() {
+            let bit = self.position as usize;
+            let high = self.pattern[bit / 8] & (0x80 >> (bit % 8)) != 0;
+            *x = if high { self.volume } else { -self.volume };
+            self.position = (self.position + self.bits_per_sample) % num_bits;
+        }
+    }
+}
+
+pub struct SdlDisplay {
+    canvas: WindowCanvas,
+    // one gfx pixel per texel, by size. Only lores and hires, kept until the canvas goes away
+    // (which is what frees them with sdl2's unsafe_textures).
+    textures: HashMap<(usize, usize), Texture>,
+    palette: Palette,
+}
+
+pub struct SdlAudio {
+    device: AudioDevice<PatternWave>,
+}
+
+pub struct SdlInput {
+    event_pump: sdl2::EventPump,
+    keys: KeyBindings,
+    // None if SDL couldn't start its game controller support
+    controller_subsystem: Option<sdl2::GameControllerSubsystem>,
+    controllers: HashMap<u32, GameController>, // open ones, by instance id
+}
+
+// a Keymap resolved to SDL's codes
+#[derive(Default)]
+struct KeyBindings {
+    keycodes: HashMap<Keycode, usize>,
+    scancodes: HashMap<Scancode, usize>,
+    buttons: HashMap<Button, usize>,
+    axes: HashMap<(Axis, bool), usize>, // axis and direction
+    axis_threshold: i16,
+    held: [u8; 16], // how many of the keys and buttons bound to each hex key are down
+    buttons_down: HashSet<(u32, Button)>,    // by controller instance id
+    axes_pushed: HashMap<(u32, Axis), bool>, // past the threshold, in which direction
+}
+
+impl KeyBindings {
+    fn new(keymap: &Keymap) -> Result<KeyBindings, String> {
+        let mut bindings = KeyBindings {
+            axis_threshold: (keymap.axis_threshold * i16::MAX as f32).max(1.0) as i16,
+            ..KeyBindings::default()
+        };
+        for (hex_key, keys) in keymap.keys.iter().enumerate() {
+            for key in keys {
+                match key {
+                    Binding::Keycode(name) => {
+                        let code = Keycode::from_name(name).ok_or(format!("Unknown key: {}", name))?;
+                        bindings.keycodes.insert(code, hex_key);
+                    }
+                    Binding::Scancode(name) => {
+                        let code = Scancode::from_name(name)
+                            .ok_or(format!("Unknown scancode: {}", name))?;
+                        bindings.scancodes.insert(code, hex_key);
+                    }
+                    Binding::Button(name) => {
+                        let button = Button::from_string(name)
+                            .ok_or(format!("Unknown controller button: {}", name))?;
+                        bindings.buttons.insert(button, hex_key);
+                    }
+                    Binding::Axis(name, positive) => {
+                        let axis = Axis::from_string(name)
+                            .ok_or(format!("Unknown controller axis: {}", name))?;
+                        bindings.axes.insert((axis, *positive), hex_key);
+                    }
+                }
+            }
+        }
+        Ok(bindings)
+    }
+
+    // scancodes win when a key matches both ways
+    fn hex_key(&self, keycode: Option<Keycode>, scancode: Option<Scancode>) -> Option<usize> {
+        let by_scancode = scancode.and_then(|code| self.scancodes.get(&code));
+        by_scancode.or_else(|| keycode.and_then(|code| self.keycodes.get(&code))).copied()
+    }
+
+    fn press(&mut self, hex_key: usize, events: &mut Vec<InputEvent>) {
+        self.held[hex_key] += 1;
+        events.push(InputEvent::KeyDown(hex_key));
+    }
+
+    // the hex key goes up once nothing bound to it is held
+    fn release(&mut self, hex_key: usize, events: &mut Vec<InputEvent>) {
+        if self.held[hex_key] > 0 {
+            self.held[hex_key] -= 1;
+            if self.held[hex_key] == 0 {
+                events.push(InputEvent::KeyUp(hex_key));
+            }
+        }
+    }
+
+    fn axis_key(&self, axis: Axis, direction: Option<bool>) -> Option<usize> {
+        direction.and_then(|positive| self.axes.get(&(axis, positive)).copied())
+    }
+
+    // true for events of bound keys and controller input, which take priority over the hotkeys
+    fn translate(&mut self, event: &Event, events: &mut Vec<InputEvent>) -> bool {
+        match *event {
+            Event::KeyDown { keycode, scancode, repeat, .. } => match self.hex_key(keycode, scancode) {
+                Some(hex_key) if !repeat => self.press(hex_key, events),
+                Some(_) => {}
+                None => return false,
+            },
+            Event::KeyUp { keycode, scancode, .. } => match self.hex_key(keycode, scancode) {
+                Some(hex_key) => self.release(hex_key, events),
+                None => return false,
+            },
+            Event::ControllerButtonDown { which, button, .. } => {
+                if let Some(&hex_key) = self.buttons.get(&button) {
+                    if self.buttons_down.insert((which, button)) {
+                        self.press(hex_key, events);
+                    }
+                }
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                if let Some(&hex_key) = self.buttons.get(&button) {
+                    if self.buttons_down.remove(&(which, button)) {
+                        self.release(hex_key, events);
+                    }
+                }
+            }
+            Event::ControllerAxisMotion { which, axis, value, .. } => {
+                let direction = if value >= self.axis_threshold {
+                    Some(true)
+                } else if value <= -self.axis_threshold {
+                    Some(false)
+                } else {
+                    None
+                };
+                let old = match direction {
+                    Some(positive) => self.axes_pushed.insert((which, axis), positive),
+                    None => self.axes_pushed.remove(&(which, axis)),
+                };
+                if old != direction {
+                    if let Some(hex_key) = self.axis_key(axis, old) {
+                        self.release(hex_key, events);
+                    }
+                    if let Some(hex_key) = self.axis_key(axis, direction) {
+                        self.press(hex_key, events);
+                    }
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    // lets go of everything a controller that was unplugged was holding
+    fn disconnect(&mut self, which: u32, events: &mut Vec<InputEvent>) {
+        let buttons: Vec<Button> =
+            self.buttons_down.iter().filter(|(id, _)| *id == which).map(|&(_, button)| button).collect();
+        for button in buttons {
+            self.buttons_down.remove(&(which, button));
+            if let Some(&hex_key) = self.buttons.get(&button) {
+                self.release(hex_key, events);
+            }
+        }
+        let axes: Vec<(Axis, bool)> = self
+            .axes_pushed
+            .iter()
+            .filter(|((id, _), _)| *id == which)
+            .map(|(&(_, axis), &positive)| (axis, positive))
+            .collect();
+        for (axis, positive) in axes {
+            self.axes_pushed.remove(&(which, axis));
+            if let Some(hex_key) = self.axis_key(axis, Some(positive)) {
+                self.release(hex_key, events);
+            }
+        }
+    }
+}
+
+// opens the window, scale times the lores resolution, and the audio device
+pub fn init(scale: u32, fullscreen: bool) -> Result<(SdlDisplay, SdlAudio, SdlInput), String> {
+    let sdl_context = sdl2::init()?;
+    let video_subsystem = sdl_context.video()?;
+    let scale = scale.max(1);
+    let mut window = video_subsystem.window(
+        "chip8",
+        cpu::GFX_COLS as u32 * scale,
+        cpu::GFX_ROWS as u32 * scale,
+    );
+    window.position_centered().resizable();
+    if fullscreen {
+        window.fullscreen_desktop();
+    }
+    let window = window.build().map_err(|e| e.to_string())?;
+    let canvas: WindowCanvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+    let event_pump = sdl_context.event_pump()?;
+    let spec = AudioSpecDesired {
+        freq: Some(SPEC_FREQ),
+        channels: Some(1),
+        samples: None,
+    };
+    let device = sdl_context.audio()?.open_playback(None, &spec, |spec| PatternWave {
+        pattern: [0; cpu::AUDIO_PATTERN_SIZE], // set by the emulator before playing
+        bits_per_sample: 0.0,
+        position: 0.0,
+        spec_freq: spec.freq as f32,
+        volume: 0.25,
+    })?;
+    let display = SdlDisplay { canvas, textures: HashMap::new(), palette: Palette::default() };
+    // controllers that are already plugged in get an added event too
+    let controller_subsystem = sdl_context
+        .game_controller()
+        .map_err(|e| eprintln!("no game controller support: {}", e))
+        .ok();
+    let input = SdlInput {
+        event_pump,
+        keys: KeyBindings::new(&Keymap::default())?,
+        controller_subsystem,
+        controllers: HashMap::new(),
+    };
+    Ok((display, SdlAudio { device }, input))
+}
+
+// the largest rect with the screen's aspect ratio that fits in the window, centered
+fn letterbox((width, height): (u32, u32), cols: usize, rows: usize) -> Rect {
+    let (cols, rows) = (cols as u32, rows as u32);
+    let (w, h) = if width * rows > height * cols {
+        (height * cols / rows, height)
+    } else {
+        (width, width * rows / cols)
+    };
+    Rect::new(((width - w) / 2) as i32, ((height - h) / 2) as i32, w.max(1), h.max(1))
+}
+
+impl Display for SdlDisplay {
+    fn draw(&mut self, gfx: &[u8], cols: usize, rows: usize) -> Result<(), String> {
+        // hires switches need a texture of the new size
+        let texture = match self.textures.entry((cols, rows)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                self.canvas
+                    .create_texture_streaming(PixelFormatEnum::RGB24, cols as u32, rows as u32)
+                    .map_err(|e| e.to_string())?,
+            ),
+        };
+        let colors = &self.palette.colors;
+        texture.with_lock(None, |pixels, pitch| {
+            for (y, row) in gfx.chunks(cols).enumerate() {
+                for (x, &planes) in row.iter().enumerate() {
+                    let offset = y * pitch + x * 3;
+                    pixels[offset..offset + 3].copy_from_slice(&colors[planes as usize]);
+                }
+            }
+        })?;
+
+        let [r, g, b] = self.palette.background();
+        self.canvas.set_draw_color(Color::RGB(r, g, b)); // the letterbox bars
+        self.canvas.clear();
+        let dest = letterbox(self.canvas.output_size()?, cols, rows);
+        self.canvas.copy(texture, None, dest)?;
+        self.canvas.present();
+        Ok(())
+    }
+
+    fn set_palette(&mut self, palette: &Palette) { self.palette = palette.clone(); }
+
+    fn toggle_fullscreen(&mut self) {
+        let window = self.canvas.window_mut();
+        let state = match window.fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        if let Err(e) = window.set_fullscreen(state) {
+            eprintln!("fullscreen: {}", e);
+        }
+    }
+
+    fn set_status(&mut self, status: &str) {
+        let title = if status.is_empty() {
+            "chip8".to_string()
+        } else {
+            format!("chip8 - {}", status)
+        };
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+}
+
+impl Audio for SdlAudio {
+    fn set_playing(&mut self, playing: bool) {
+        if playing {
+            self.device.resume();
+        } else {
+            self.device.pause();
+        }
+    }
+
+    fn set_pattern(&mut self, pattern: [u8; cpu::AUDIO_PATTERN_SIZE], pitch: u8) {
+        self.device.lock().set_pattern(pattern, pitch);
+    }
+}
+
+impl SdlInput {
+    // replaces the default keymap, failing on key names SDL doesn't know
+    pub fn set_keymap(&mut self, keymap: &Keymap) -> Result<(), String> {
+        self.keys = KeyBindings::new(keymap)?;
+        Ok(())
+    }
+
+    fn connect(&mut self, index: u32) {
+        let subsystem = match &self.controller_subsystem {
+            Some(subsystem) if subsystem.is_game_controller(index) => subsystem,
+            _ => return,
+        };
+        match subsystem.open(index) {
+            Ok(controller) => {
+                println!("controller connected: {}", controller.name());
+                self.controllers.insert(controller.instance_id(), controller);
+            }
+            Err(e) => eprintln!("controller {}: {}", index, e),
+        }
+    }
+
+    fn disconnect(&mut self, which: u32, events: &mut Vec<InputEvent>) {
+        if let Some(controller) = self.controllers.remove(&which) {
+            println!("controller disconnected: {}", controller.name());
+        }
+        self.keys.disconnect(which, events);
+    }
+}
+
+impl Input for SdlInput {
+    fn poll(&mut self) -> Vec<InputEvent> {
+        let mut events = vec![];
+        let polled: Vec<Event> = self.event_pump.poll_iter().collect();
+        for event in polled {
+            if self.keys.translate(&event, &mut events) {
+                continue;
+            }
+            match event {
+                Event::Quit { .. } => events.push(InputEvent::Quit),
+                Event::ControllerDeviceAdded { which, .. } => self.connect(which),
+                Event::ControllerDeviceRemoved { which, .. } => self.disconnect(which, &mut events),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Escape),
+                    ..
+                } => events.push(InputEvent::Quit),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Pause),
+                    repeat: false,
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::Break),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::SaveState),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::NextSlot),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F7),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::LoadState),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Backspace),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::Rewind(true)),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Backspace),
+                    ..
+                } => events.push(InputEvent::Rewind(false)),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::NextPalette),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F11),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::ToggleFullscreen),
+                Event::Window {
+                    win_event: WindowEvent::SizeChanged(..) | WindowEvent::Exposed,
+                    ..
+                } => events.push(InputEvent::Refresh),
+                Event::KeyDown {
+                    keycode: Some(Keycode::P),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::Pause),
+                Event::KeyDown {
+                    keycode: Some(Keycode::N),
+                    ..
+                } => events.push(InputEvent::FrameAdvance),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Equals),
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::KpPlus),
+                    ..
+                } => events.push(InputEvent::SpeedUp),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Minus),
+                    ..
+                }
+                | Event::KeyDown {
+                    keycode: Some(Keycode::KpMinus),
+                    ..
+                } => events.push(InputEvent::SpeedDown),
+                Event::KeyDown {
+                    keycode: Some(Keycode::Tab),
+                    repeat: false,
+                    ..
+                } => events.push(InputEvent::FastForward(true)),
+                Event::KeyUp {
+                    keycode: Some(Keycode::Tab),
+                    ..
+                } => events.push(InputEvent::FastForward(false)),
+                _ => {}
+            }
+        }
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdl2::keyboard::Mod;
+
+    // A on the keyboard, the A button and the left stick to the right all press hex key 5,
+    // the stick to the left presses 4
+    fn bindings() -> KeyBindings {
+        let mut bindings = KeyBindings { axis_threshold: 16000, ..KeyBindings::default() };
+        bindings.scancodes.insert(Scancode::A, 5);
+        bindings.buttons.insert(Button::A, 5);
+        bindings.axes.insert((Axis::LeftX, true), 5);
+        bindings.axes.insert((Axis::LeftX, false), 4);
+        bindings
+    }
+
+    fn key(down: bool, scancode: Scancode, repeat: bool) -> Event {
+        let (timestamp, window_id, keycode, scancode, keymod) = (0, 0, None, Some(scancode), Mod::NOMOD);
+        if down {
+            Event::KeyDown { timestamp, window_id, keycode, scancode, keymod, repeat }
+        } else {
+            Event::KeyUp { timestamp, window_id, keycode, scancode, keymod, repeat }
+        }
+    }
+
+    fn button(down: bool, which: u32, button: Button) -> Event {
+        if down {
+            Event::ControllerButtonDown { timestamp: 0, which, button }
+        } else {
+            Event::ControllerButtonUp { timestamp: 0, which, button }
+        }
+    }
+
+    fn axis(which: u32, axis: Axis, value: i16) -> Event {
+        Event::ControllerAxisMotion { timestamp: 0, which, axis, value }
+    }
+
+    // the events one sdl event turns into, or None if it wasn't for the keypad
+    fn translate(bindings: &mut KeyBindings, event: Event) -> Option<Vec<InputEvent>> {
+        let mut events = vec![];
+        if bindings.translate(&event, &mut events) {
+            Some(events)
+        } else {
+            assert!(events.is_empty());
+            None
+        }
+    }
+
+    #[test]
+    fn buttons() {
+        let mut bindings = bindings();
+        assert_eq!(translate(&mut bindings, button(true, 0, Button::A)), Some(vec![InputEvent::KeyDown(5)]));
+        // the same button again without a release is ignored
+        assert_eq!(translate(&mut bindings, button(true, 0, Button::A)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, button(false, 0, Button::A)), Some(vec![InputEvent::KeyUp(5)]));
+        assert_eq!(translate(&mut bindings, button(false, 0, Button::A)), Some(vec![]));
+        // unbound buttons are still controller input, and never reach the hotkeys
+        assert_eq!(translate(&mut bindings, button(true, 0, Button::B)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, key(true, Scancode::B, false)), None);
+    }
+
+    #[test]
+    fn keys_and_buttons_share_a_hex_key() {
+        let mut bindings = bindings();
+        assert_eq!(translate(&mut bindings, key(true, Scancode::A, false)), Some(vec![InputEvent::KeyDown(5)]));
+        assert_eq!(translate(&mut bindings, key(true, Scancode::A, true)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, button(true, 1, Button::A)), Some(vec![InputEvent::KeyDown(5)]));
+        // still held by the button
+        assert_eq!(translate(&mut bindings, key(false, Scancode::A, false)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, button(false, 1, Button::A)), Some(vec![InputEvent::KeyUp(5)]));
+    }
+
+    #[test]
+    fn axes() {
+        let mut bindings = bindings();
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, 15999)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, 16000)), Some(vec![InputEvent::KeyDown(5)]));
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, i16::MAX)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, 100)), Some(vec![InputEvent::KeyUp(5)]));
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, -100)), Some(vec![]));
+        // straight from one side to the other
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, 20000)), Some(vec![InputEvent::KeyDown(5)]));
+        assert_eq!(
+            translate(&mut bindings, axis(0, Axis::LeftX, -20000)),
+            Some(vec![InputEvent::KeyUp(5), InputEvent::KeyDown(4)])
+        );
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, -16000)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftX, 0)), Some(vec![InputEvent::KeyUp(4)]));
+        assert_eq!(translate(&mut bindings, axis(0, Axis::LeftY, 30000)), Some(vec![]));
+    }
+
+    #[test]
+    fn unplugging_releases_held_keys() {
+        let mut bindings = bindings();
+        translate(&mut bindings, button(true, 0, Button::A));
+        translate(&mut bindings, axis(0, Axis::LeftX, -30000));
+        translate(&mut bindings, button(true, 1, Button::A));
+        let mut events = vec![];
+        bindings.disconnect(0, &mut events);
+        // 5 is still held on the other controller
+        assert_eq!(events, [InputEvent::KeyUp(4)]);
+        events.clear();
+        bindings.disconnect(1, &mut events);
+        assert_eq!(events, [InputEvent::KeyUp(5)]);
+        events.clear();
+        bindings.disconnect(1, &mut events);
+        assert!(events.is_empty());
+        // a release arriving after the unplug doesn't release anything twice
+        assert_eq!(translate(&mut bindings, button(false, 0, Button::A)), Some(vec![]));
+        assert_eq!(translate(&mut bindings, button(true, 0, Button::A)), Some(vec![InputEvent::KeyDown(5)]));
+    }
+}
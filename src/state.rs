@@ -0,0 +1,121 @@
+// Save state format, see CPU::save_state and CPU::load_state.
+//
+// A state is a fixed header (magic, format version, SHA-1 of the rom it was made
+// with) followed by the machine state, all integers big-endian. Bump VERSION
+// whenever the layout changes; old states are rejected rather than misread.
+
+use crate::quirks::Quirks;
+
+use std::error::Error;
+use std::fmt;
+
+pub const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u16 = 1;
+
+pub type RomHash = [u8; 20];
+
+pub fn rom_hash(rom: &[u8]) -> RomHash { sha1_smol::Sha1::from(rom).digest().bytes() }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StateError {
+    NotAState,
+    UnsupportedVersion(u16),
+    WrongRom, // made with a different rom than the one loaded
+    Truncated,
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "save state version {} is not supported (expected {})", version, VERSION)
+            }
+            StateError::WrongRom => write!(f, "save state belongs to a different rom"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt => write!(f, "save state is corrupt"),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+// appends big-endian fields to a buffer
+#[derive(Default)]
+pub struct Writer {
+    pub buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn u8(&mut self, val: u8) { self.buf.push(val); }
+    pub fn u16(&mut self, val: u16) { self.buf.extend_from_slice(&val.to_be_bytes()); }
+    pub fn u32(&mut self, val: u32) { self.buf.extend_from_slice(&val.to_be_bytes()); }
+    pub fn u64(&mut self, val: u64) { self.buf.extend_from_slice(&val.to_be_bytes()); }
+    pub fn bool(&mut self, val: bool) { self.buf.push(val as u8); }
+    pub fn bytes(&mut self, val: &[u8]) { self.buf.extend_from_slice(val); }
+    pub fn quirks(&mut self, quirks: &Quirks) {
+        self.bool(quirks.shift_uses_vy);
+        self.bool(quirks.load_store_increments_i);
+        self.bool(quirks.jump_uses_vx);
+        self.bool(quirks.clip_sprites);
+        self.bool(quirks.vf_reset);
+        self.bool(quirks.display_wait);
+        self.bool(quirks.collision_rows);
+    }
+}
+
+// reads fields back in the same order they were written
+pub struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(data: &'a [u8]) -> Reader<'a> { Reader { data } }
+
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (head, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(head)
+    }
+    pub fn u8(&mut self) -> Result<u8, StateError> { Ok(self.bytes(1)?[0]) }
+    pub fn u16(&mut self) -> Result<u16, StateError> { Ok(u16::from_be_bytes(self.array()?)) }
+    pub fn u32(&mut self) -> Result<u32, StateError> { Ok(u32::from_be_bytes(self.array()?)) }
+    pub fn u64(&mut self) -> Result<u64, StateError> { Ok(u64::from_be_bytes(self.array()?)) }
+    pub fn bool(&mut self) -> Result<bool, StateError> { Ok(self.u8()? != 0) }
+    pub fn array<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.bytes(N)?);
+        Ok(array)
+    }
+
+    pub fn quirks(&mut self) -> Result<Quirks, StateError> {
+        Ok(Quirks {
+            shift_uses_vy: self.bool()?,
+            load_store_increments_i: self.bool()?,
+            jump_uses_vx: self.bool()?,
+            clip_sprites: self.bool()?,
+            vf_reset: self.bool()?,
+            display_wait: self.bool()?,
+            collision_rows: self.bool()?,
+        })
+    }
+
+    // checks magic, version and rom hash
+    pub fn header(&mut self, rom_hash: &RomHash) -> Result<(), StateError> {
+        if self.bytes(MAGIC.len()).map_err(|_| StateError::NotAState)? != MAGIC {
+            return Err(StateError::NotAState);
+        }
+        let version = self.u16()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if &self.array::<20>()? != rom_hash {
+            return Err(StateError::WrongRom);
+        }
+        Ok(())
+    }
+}
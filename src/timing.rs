@@ -0,0 +1,110 @@
+// Instruction timing of the original CHIP-8 interpreter on the COSMAC VIP.
+//
+// The VIP's 1802 runs at 1.76 MHz with eight clocks to a machine cycle, which makes
+// 3668 machine cycles per 60 Hz frame. About half of those go to the display (the
+// 1861's DMA and the interrupt routine that also counts down the timers), the
+// interpreter gets the rest. Its routines differ wildly in cost: loading a register
+// takes a few dozen cycles, drawing a sprite hundreds and clearing the screen most of a
+// frame, so a fixed number of instructions per frame runs some games far too fast.
+//
+// Costs are in machine cycles and include fetching and decoding the instruction. They
+// are estimates of the interpreter's routines, not counts taken from its code: the
+// relative costs (sprites by rows and alignment, the screen clear, the timer
+// interrupt's share of the frame) follow the VIP, the exact numbers don't.
+
+use crate::instruction::Instruction;
+
+pub const CYCLES_PER_FRAME: u32 = 3668;
+const DISPLAY_CYCLES: u32 = 1832; // DMA and the interrupt routine
+pub const INTERPRETER_CYCLES: u32 = CYCLES_PER_FRAME - DISPLAY_CYCLES; // left to run chip-8 each frame
+
+const FETCH: u32 = 22; // fetch, decode and jump to the instruction's routine
+const SKIP: u32 = 4; // extra when a skip is taken
+
+// vx is VX before the instruction ran, skipped whether a skip instruction skipped and
+// blocked whether DXYN or FX0A is still waiting (for the vertical blank or a key)
+pub fn vip_cycles(instr: Instruction, vx: u8, skipped: bool, blocked: bool) -> u32 {
+    use Instruction::*;
+    let skip = if skipped { SKIP } else { 0 };
+    FETCH
+        + match instr {
+            ClearScreen => 1536, // 256 bytes of display memory
+            Return => 10,
+            Jump(_) => 12,
+            Call(_) => 26,
+            SkipEqImm(..) | SkipNeImm(..) => 10 + skip,
+            SkipEq(..) | SkipNe(..) => 14 + skip,
+            SetImm(..) => 6,
+            AddImm(..) => 10,
+            // the VIP assembles and runs an 1802 instruction for these
+            Set(..) | Or(..) | And(..) | Xor(..) | Add(..) | Sub(..) | ShiftRight(..)
+            | SubReverse(..) | ShiftLeft(..) => 22,
+            SetI(_) => 8,
+            JumpOffset(_) => 22,
+            Random(..) => 36,
+            Draw(..) if blocked => 8,
+            // unaligned rows straddle two bytes of display memory
+            Draw(_, _, rows) => 26 + rows as u32 * if vx & 7 == 0 { 20 } else { 34 },
+            SkipKey(_) | SkipNotKey(_) => 14 + skip,
+            GetDelay(_) | SetDelay(_) | SetSound(_) => 8,
+            WaitKey(_) => 10,
+            AddI(_) => 12,
+            Font(_) => 16,
+            // digits are found by repeated subtraction
+            Bcd(_) => 30 + 16 * (vx / 100 + vx / 10 % 10 + vx % 10) as u32,
+            Store(x) | Load(x) => 14 + 14 * (x as u32 + 1),
+            // machine code routines, and everything the VIP never had
+            _ => 10,
+        }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::instruction::Instruction::*;
+
+    #[test]
+    fn interpreter_gets_what_the_display_leaves() {
+        assert_eq!(CYCLES_PER_FRAME, 1_760_640 / 8 / 60);
+        assert_eq!(INTERPRETER_CYCLES + DISPLAY_CYCLES, CYCLES_PER_FRAME);
+        // a screen clear takes most of a frame, but fits
+        let clear = vip_cycles(ClearScreen, 0, false, false);
+        assert!(clear > INTERPRETER_CYCLES / 2 && clear < INTERPRETER_CYCLES);
+    }
+
+    #[test]
+    fn sprites_cost_by_rows_and_alignment() {
+        let aligned: Vec<u32> = (0..=15).map(|rows| vip_cycles(Draw(0, 1, rows), 8, false, false)).collect();
+        let unaligned: Vec<u32> = (0..=15).map(|rows| vip_cycles(Draw(0, 1, rows), 9, false, false)).collect();
+        assert!(aligned.windows(2).all(|pair| pair[1] - pair[0] == 20));
+        assert!(unaligned.windows(2).all(|pair| pair[1] - pair[0] == 34));
+        assert_eq!(aligned[0], unaligned[0]);
+        // only the alignment of vx matters
+        assert_eq!(vip_cycles(Draw(0, 1, 5), 0x38, false, false), aligned[5]);
+        assert_eq!(vip_cycles(Draw(0, 1, 5), 0x3F, false, false), unaligned[5]);
+        // waiting for the vertical blank only pays for the fetch and the check
+        assert_eq!(vip_cycles(Draw(0, 1, 15), 9, false, true), FETCH + 8);
+    }
+
+    #[test]
+    fn taken_skips_cost_more() {
+        for instr in [SkipEqImm(0, 1), SkipNeImm(0, 1), SkipEq(0, 1), SkipNe(0, 1), SkipKey(0), SkipNotKey(0)] {
+            assert_eq!(vip_cycles(instr, 0, true, false), vip_cycles(instr, 0, false, false) + SKIP);
+        }
+        assert_eq!(vip_cycles(Jump(0x200), 0, true, false), vip_cycles(Jump(0x200), 0, false, false));
+    }
+
+    #[test]
+    fn costs_that_depend_on_operands() {
+        // one subtraction per unit of each digit
+        assert_eq!(vip_cycles(Bcd(0), 0, false, false), FETCH + 30);
+        assert_eq!(vip_cycles(Bcd(0), 255, false, false), FETCH + 30 + 16 * 12);
+        assert_eq!(vip_cycles(Bcd(0), 100, false, false), vip_cycles(Bcd(0), 1, false, false));
+        // registers are copied one at a time
+        let store: Vec<u32> = (0..16).map(|x| vip_cycles(Store(x), 0, false, false)).collect();
+        assert!(store.windows(2).all(|pair| pair[1] - pair[0] == 14));
+        assert_eq!(vip_cycles(Load(7), 0, false, false), store[7]);
+        // instructions the VIP didn't have still take some time
+        assert_eq!(vip_cycles(ScrollRight, 0, false, false), FETCH + 10);
+    }
+}